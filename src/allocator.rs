@@ -44,6 +44,11 @@ pub fn init_heap(
     Ok(())
 }
 
+/// bytes of the kernel heap currently allocated
+pub fn heap_used() -> usize {
+    ALLOCATOR.lock().used()
+}
+
 pub struct Locked<A> {
     inner: spin::Mutex<A>,
 }
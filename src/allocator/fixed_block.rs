@@ -12,6 +12,8 @@ struct ListNode {
 pub(crate) struct FixedBlockAlloc {
     list_heads: [Option<&'static mut ListNode>; BLOCK_SIZES.len()],
     fallback: linked_list_allocator::Heap,
+    /// bytes handed out, counting whole blocks for small allocations
+    used: usize,
 }
 
 impl FixedBlockAlloc {
@@ -21,6 +23,7 @@ impl FixedBlockAlloc {
         FixedBlockAlloc {
             list_heads: [EMPTY; BLOCK_SIZES.len()],
             fallback: linked_list_allocator::Heap::empty(),
+            used: 0,
         }
     }
 
@@ -31,6 +34,11 @@ impl FixedBlockAlloc {
         self.fallback.init(heap_start as *mut u8, heap_size);
     }
 
+    /// bytes currently allocated
+    pub fn used(&self) -> usize {
+        self.used
+    }
+
     /// allocated using fallback allocator
     fn fallback_alloc(&mut self, layout: Layout) -> *mut u8 {
         match self.fallback.allocate_first_fit(layout) {
@@ -44,7 +52,7 @@ impl FixedBlockAlloc {
 unsafe impl GlobalAlloc for Locked<FixedBlockAlloc> {
     unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
         let mut allocator = self.lock();
-        match block_index(&layout) {
+        let ptr = match block_index(&layout) {
             Some(index) => {
                 match allocator.list_heads[index].take() {
                     Some(node) => {
@@ -61,11 +69,16 @@ unsafe impl GlobalAlloc for Locked<FixedBlockAlloc> {
                 }
             }
             None => allocator.fallback_alloc(layout),
+        };
+        if !ptr.is_null() {
+            allocator.used += allocated_size(&layout);
         }
+        ptr
     }
 
     unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
         let mut allocator = self.lock();
+        allocator.used -= allocated_size(&layout);
         match block_index(&layout) {
             Some(index) => {
                 let new_node = ListNode {
@@ -89,4 +102,8 @@ unsafe impl GlobalAlloc for Locked<FixedBlockAlloc> {
 fn block_index(layout: &Layout) -> Option<usize> {
     let required_block_size = layout.size().max(layout.align());
     BLOCK_SIZES.iter().position(|&block_size| block_size >= required_block_size)
-}
\ No newline at end of file
+}
+/// bytes an allocation takes up, which is a whole block if it fits one
+fn allocated_size(layout: &Layout) -> usize {
+    block_index(layout).map_or(layout.size(), |index| BLOCK_SIZES[index])
+}
@@ -0,0 +1,114 @@
+use core::sync::atomic::{AtomicU64, Ordering};
+use crossbeam_queue::ArrayQueue;
+use lazy_static::lazy_static;
+use pc_keyboard::{layouts, DecodedKey, HandleControl, Keyboard, ScancodeSet1};
+use spin::Mutex;
+use x86_64::instructions::interrupts;
+use crate::println;
+use crate::process::signal::SIGINT;
+use crate::threading::scheduler::{self, PID, SCHEDULER};
+use crate::threading::wait_queue::{Interrupted, WaitQueue};
+
+const INPUT_QUEUE_SIZE: usize = 256;
+/// produced by Ctrl-C since control keys are mapped to ascii control codes
+const INTERRUPT_CHAR: char = '\u{3}';
+
+lazy_static! {
+    static ref KEYBOARD: Mutex<Keyboard<layouts::Us104Key, ScancodeSet1>> = Mutex::new(
+        Keyboard::new(ScancodeSet1::new(), layouts::Us104Key, HandleControl::MapLettersToUnicode)
+    );
+    /// decoded keyboard input waiting to be read from stdin
+    static ref INPUT: ArrayQueue<u8> = ArrayQueue::new(INPUT_QUEUE_SIZE);
+}
+
+/// tasks waiting for keyboard input, only locked with interrupts disabled
+static READERS: Mutex<WaitQueue> = Mutex::new(WaitQueue::new());
+
+/// pid of the process interrupted by Ctrl-C, 0 if there is none
+static FOREGROUND: AtomicU64 = AtomicU64::new(0);
+
+/// pid of the process owning the console, the only one that can hand it to any other process
+static SESSION_LEADER: AtomicU64 = AtomicU64::new(0);
+
+/// decodes a scancode from the keyboard interrupt and queues the resulting bytes
+pub(crate) fn add_scancode(scancode: u8) {
+    let mut keyboard = KEYBOARD.lock();
+    let key = match keyboard.add_byte(scancode) {
+        Ok(Some(event)) => keyboard.process_keyevent(event),
+        _ => None,
+    };
+
+    if let Some(DecodedKey::Unicode(INTERRUPT_CHAR)) = key {
+        match FOREGROUND.load(Ordering::Relaxed) {
+            0 => {},
+            pid => scheduler::queue_signal(PID::new(pid), SIGINT),
+        }
+    } else if let Some(DecodedKey::Unicode(char)) = key {
+        let mut bytes = [0; 4];
+        for byte in char.encode_utf8(&mut bytes).bytes() {
+            if INPUT.push(byte).is_err() {
+                println!("WARNING: console input queue is full; input dropped");
+                break;
+            }
+        }
+        READERS.lock().wake_all();
+    }
+}
+
+/// reads at least one byte of keyboard input, blocking until a key is pressed
+///
+/// fails if a signal arrives before any input
+pub fn read(buffer: &mut [u8]) -> Result<usize, Interrupted> {
+    if buffer.is_empty() {
+        return Ok(0);
+    }
+    loop {
+        interrupts::disable();
+        if !INPUT.is_empty() {
+            let mut count = 0;
+            while let Some(byte) = INPUT.pop() {
+                buffer[count] = byte;
+                count += 1;
+                if count == buffer.len() {
+                    break;
+                }
+            }
+            interrupts::enable();
+            return Ok(count);
+        }
+        if scheduler::signal_pending() {
+            interrupts::enable();
+            return Err(Interrupted);
+        }
+        if let Some(pid) = SCHEDULER.lock().current_pid() {
+            READERS.lock().register(pid);
+        }
+        // interrupts are re-enabled once this task is scheduled again
+        SCHEDULER.lock().block_current();
+    }
+}
+
+/// true if a read would not block
+pub fn has_input() -> bool {
+    !INPUT.is_empty()
+}
+
+/// wakes pid the next time keyboard input arrives
+pub fn register_reader(pid: PID) {
+    interrupts::without_interrupts(|| READERS.lock().register(pid));
+}
+
+/// sends future Ctrl-C presses to pid
+pub fn set_foreground(pid: PID) {
+    FOREGROUND.store(pid.as_u64(), Ordering::Relaxed);
+}
+
+/// makes pid the owner of the console and its foreground process
+pub fn set_session_leader(pid: PID) {
+    SESSION_LEADER.store(pid.as_u64(), Ordering::Relaxed);
+    set_foreground(pid);
+}
+
+pub fn session_leader() -> PID {
+    PID::new(SESSION_LEADER.load(Ordering::Relaxed))
+}
@@ -12,11 +12,16 @@ use x86_64::structures::paging::{OffsetPageTable, Translate};
 use x86_64::VirtAddr;
 use bitfield_struct::bitfield;
 use crate::{pci, println, serial_println};
-use crate::disk::DiskAccessError::{NoCommandSlots, TaskFileError};
+use crate::disk::DiskAccessError::{CorruptFileSystem, NoCommandSlots, TaskFileError};
 use crate::pci::{DeviceConfigurationSpace, DeviceIdentification};
 
 static PORTS: Mutex<Vec<AHCIPort>> = Mutex::new(Vec::new());
 
+pub const SECTOR_SIZE: u64 = 512;
+const ATA_READ_DMA_EXT: u8 = 0x25;
+const ATA_WRITE_DMA_EXT: u8 = 0x35;
+const ATA_IDENTIFY_DEVICE: u8 = 0xEC;
+
 #[repr(u8)]
 enum FISType {
     RegH2D = 0x27,
@@ -264,6 +269,8 @@ struct AHCIPort {
 pub enum DiskAccessError {
     NoCommandSlots,
     TaskFileError,
+    /// on disk structures such as cluster chains are inconsistent
+    CorruptFileSystem,
 }
 
 impl Display for DiskAccessError {
@@ -271,6 +278,7 @@ impl Display for DiskAccessError {
         let err_string = match self {
             NoCommandSlots => "no command slots",
             TaskFileError => "task file error",
+            CorruptFileSystem => "corrupt file system",
         };
         write!(f, "{}", err_string)
     }
@@ -417,6 +425,24 @@ fn init_device(
 
 pub fn read_sectors(
     mapper: &OffsetPageTable, drive_num: usize, lba: u64, sector_count: u16
+) -> Result<Vec<u8>, DiskAccessError> {
+    read_command(mapper, drive_num, ATA_READ_DMA_EXT, lba, sector_count)
+}
+
+/// the number of sectors on the drive, as reported by IDENTIFY DEVICE
+pub fn get_sector_count(
+    mapper: &OffsetPageTable, drive_num: usize
+) -> Result<u64, DiskAccessError> {
+    let identify = read_command(mapper, drive_num, ATA_IDENTIFY_DEVICE, 0, 1)?;
+    // words 100 to 103 hold the 48 bit count, words 60 and 61 the 28 bit one for older drives
+    let lba48 = u64::from_le_bytes(identify[200..208].try_into().unwrap());
+    let lba28 = u32::from_le_bytes(identify[120..124].try_into().unwrap());
+    Ok(if lba48 != 0 { lba48 } else { lba28 as u64 })
+}
+
+/// issues a command that reads sector_count sectors from the drive into a new buffer
+fn read_command(
+    mapper: &OffsetPageTable, drive_num: usize, command: u8, lba: u64, sector_count: u16
 ) -> Result<Vec<u8>, DiskAccessError> {
     let ahci_port = &mut PORTS.lock()[drive_num];
 
@@ -457,7 +483,7 @@ pub fn read_sectors(
     let lbas = lba.to_le_bytes();
     cmd_fis.fis_type = FISType::RegH2D;
     cmd_fis.bit_field.set_c(1);
-    cmd_fis.command = 0x25;
+    cmd_fis.command = command;
     cmd_fis.lba0 = lbas[0];
     cmd_fis.lba1 = lbas[1];
     cmd_fis.lba2 = lbas[2];
@@ -542,7 +568,7 @@ pub fn write_sectors(
     let lbas = lba.to_le_bytes();
     cmd_fis.fis_type = FISType::RegH2D;
     cmd_fis.bit_field.set_c(1);
-    cmd_fis.command = 0x35;
+    cmd_fis.command = ATA_WRITE_DMA_EXT;
     cmd_fis.lba0 = lbas[0];
     cmd_fis.lba1 = lbas[1];
     cmd_fis.lba2 = lbas[2];
@@ -130,7 +130,12 @@ impl Writer {
 
     /// Write a string to the vga buffer
     pub fn write_string(&mut self, string: &str) {
-        for byte in string.bytes() {
+        self.write_bytes(string.as_bytes());
+    }
+
+    /// Write raw bytes to the vga buffer, showing anything but printable ascii as '?'
+    pub fn write_bytes(&mut self, bytes: &[u8]) {
+        for &byte in bytes {
             match byte {
                 0x20..=0x7e | b'\n' => self.write_byte(byte),
                 // invalid ascii characters
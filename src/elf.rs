@@ -1,274 +1,159 @@
-use alloc::{format, vec};
-use alloc::alloc::alloc_zeroed;
-use alloc::collections::{BTreeMap, BTreeSet};
-use alloc::string::ToString;
+pub mod core_file;
+pub mod parser;
+
+use alloc::string::{String, ToString};
 use alloc::vec::Vec;
-use core::alloc::Layout;
 use core::cmp::min;
-use core::error::Error;
-use core::fmt::{Display, Formatter};
-use core::mem::{size_of, transmute};
-use core::pin::Pin;
-use core::ptr::{addr_of, read_unaligned, slice_from_raw_parts, slice_from_raw_parts_mut};
-use x86_64::structures::paging::{FrameAllocator, Mapper, OffsetPageTable, Page, PageTableFlags, PhysFrame, Size4KiB, Translate};
-use x86_64::structures::paging::frame::PhysFrameRange;
-use x86_64::structures::paging::mapper::MappedFrame;
-use x86_64::{align_down, align_up, VirtAddr};
-use crate::elf::ElfVerifyError::{BadMagicNum, Elf32Bit, BadSliceSize, BadEndianness, BadArch};
-use crate::elf::ProgramHeaderType::Load;
-use crate::{MAPPER, println, serial_println};
-use crate::memory::BuddyAllocator;
-
-const MAGIC_NUM: &[u8; 4] = b"\x7FELF";
-
-/// 64-bit elf header
-#[repr(C, packed)]
-#[derive(Debug, Copy, Clone)]
-pub struct ElfHeader {
-    magic_num: [u8; 4],
-    bits: u8,
-    endianness: u8,
-    elf_header_version: u8,
-    os_abi: u8,
-    _reserved: [u8; 8],
-    elf_type: u16,
-    instruction_set: u16,
-    elf_version: u32,
-    program_entry: u64,
-    program_header_table: u64,
-    section_header_table: u64,
-    flags: u32,
-    header_size: u16,
-    program_entry_size: u16,
-    program_entry_count: u16,
-    section_entry_size: u16,
-    section_entry_count: u16,
-    section_names_idx: u16,
-}
+use crate::process::address_space::{USER_MEMORY_END, USER_MEMORY_START};
+use crate::process::ProcessSpawnError;
+use crate::process::vma::{Vma, VmaBacking, VmaKind, PROT_EXEC, PROT_READ, PROT_WRITE};
+use crate::vfs::InodeRef;
+use parser::{Segment, PF_R, PF_W, PF_X};
 
-#[repr(C, packed)]
-#[derive(Debug, Copy, Clone)]
-pub struct ProgramHeader {
-    header_type: ProgramHeaderType,
-    flags: u32,
-    offset: u64,
-    virt_addr: u64,
-    _reserved: [u8; 8],
-    file_size: u64,
-    mem_size: u64,
-    align: u64,
-}
+pub use parser::{ElfLoadError, Relocation, TlsTemplate};
 
-#[repr(u32)]
+/// what an image is loaded as, which decides who relocates it
 #[derive(Debug, Copy, Clone, PartialEq)]
-pub enum ProgramHeaderType {
-    Null = 0x0,
-    Load = 0x1,
-    Dynamic = 0x2,
-    Interp = 0x3,
-    Note = 0x4,
-    SHLib = 0x5,
-    PHDR = 0x6,
-    LoProc = 0x70000000,
-    HiProc = 0x7FFFFFFF,
+pub enum ImageRole {
+    /// the executable being run, relocated by the kernel unless it names an interpreter
+    Program,
+    /// the dynamic loader named by PT_INTERP, which relocates itself and the program
+    Interpreter,
 }
 
-#[derive(Copy, Clone, Debug)]
-enum ElfVerifyError {
-    BadSliceSize(usize),
-    BadMagicNum,
-    Elf32Bit,
-    BadEndianness,
-    BadArch,
+/// an executable checked and laid out for a new process
+pub struct ElfImage {
+    pub vmas: Vec<Vma>,
+    pub entry: u64,
+    /// added to every address in the file, 0 for fixed position executables
+    pub load_base: u64,
+    /// where the program headers are in memory, passed to the dynamic loader in AT_PHDR
+    pub program_headers_addr: Option<u64>,
+    pub program_header_count: u16,
+    /// path of the dynamic loader that should run before the program
+    pub interpreter: Option<String>,
+    /// words patched as the pages holding them are first loaded, sorted by address
+    pub relocations: Vec<Relocation>,
+    pub tls: Option<TlsTemplate>,
 }
 
-impl Display for ElfVerifyError {
-    fn fmt(&self, f: &mut Formatter<'_>) -> core::fmt::Result {
-        write!(f, "failed to verify elf header: {}", match self {
-            BadSliceSize(size) => format!("incorrect slice size of {} provided", size),
-            BadMagicNum => "incorrect ELF magic num".to_string(),
-            Elf32Bit => "only 64-bit elf is supported".to_string(),
-            BadEndianness => "ELF is not little endian".to_string(),
-            BadArch => "ELF is not x64".to_string(),
-        })
+/// checks an executable and describes its segments as memory areas filled on first access
+///
+/// position independent executables are placed by choose_base, which gets the size of the
+/// image and returns the address of its lowest page
+pub fn load_image(
+    executable: &InodeRef, role: ImageRole, choose_base: impl FnOnce(u64) -> Option<u64>
+) -> Result<ElfImage, ProcessSpawnError> {
+    let file_len = executable.metadata()
+        .map_err(|_| ProcessSpawnError::ReadFail)?
+        .size;
+    // only the headers are read now, segments are read as their pages are first touched
+    let header_bytes = read_file(executable, 0, min(file_len, parser::ELF_HEADER_SIZE as u64))?;
+    let header = parser::parse_header(&header_bytes)?;
+    if header.program_headers_end()? > file_len {
+        return Err(ElfLoadError::Truncated.into());
     }
+    let headers = read_file(executable, header.program_header_offset, header.program_headers_len())?;
+    let program_headers = parser::parse_program_headers(&header, &headers)?;
+
+    let load_base = if header.is_dynamic() {
+        let (start, end) = parser::image_span(&program_headers)?;
+        choose_base(end - start)
+            .ok_or(ProcessSpawnError::MapFail)?
+            .wrapping_sub(start)
+    } else {
+        0
+    };
+    let segments = parser::loadable_segments(
+        &header, &program_headers, file_len, load_base, USER_MEMORY_START, USER_MEMORY_END
+    )?;
+
+    let interpreter = match parser::interpreter_section(&program_headers, file_len)? {
+        Some(_) if role == ImageRole::Interpreter => return Err(ElfLoadError::BadInterpreter.into()),
+        Some((offset, size)) => {
+            let bytes = read_file(executable, offset, size)?;
+            Some(parser::parse_interpreter(&bytes)?.to_string())
+        },
+        None => None,
+    };
+
+    let relocate = header.is_dynamic() && role == ImageRole::Program && interpreter.is_none();
+    let relocations = match parser::dynamic_section(&program_headers, file_len)? {
+        Some((offset, size)) if relocate => {
+            read_relocations(executable, offset, size, load_base, &segments)?
+        },
+        _ => Vec::new(),
+    };
+
+    let vmas = segments.iter()
+        .map(|segment| {
+            let backing = VmaBacking::File {
+                file: executable.clone(),
+                offset: segment.offset,
+                virt_addr: segment.virt_addr,
+                file_size: segment.file_size,
+            };
+            Vma::new(segment.start, segment.end, segment_protection(segment.flags), VmaKind::Image)
+                .with_backing(backing)
+        })
+        .collect();
+
+    Ok(ElfImage {
+        vmas,
+        entry: header.entry.wrapping_add(load_base),
+        load_base,
+        program_headers_addr: parser::program_headers_addr(&header, &program_headers, &segments, load_base),
+        program_header_count: header.program_header_count,
+        interpreter,
+        relocations,
+        tls: parser::tls_template(&program_headers, file_len)?,
+    })
 }
 
-impl Error for ElfVerifyError {}
-
-impl ElfHeader {
-    /// creates an elf header reference from a byte slice
-    /// requires that bytes is a valid elf header struct
-    unsafe fn from_slice(bytes: &[u8]) -> Result<&Self, ElfVerifyError> {
-        if bytes.len() != size_of::<Self>() {
-            return Err(BadSliceSize(bytes.len()));
-        }
-
-        let header = &*(bytes.as_ptr() as *const Self);
-
-        if header.magic_num != *MAGIC_NUM {
-            return Err(BadMagicNum);
-        }
-        if header.bits != 2 {
-            return Err(Elf32Bit);
-        }
-        if header.endianness != 1 {
-            return Err(BadEndianness);
-        }
-        if header.instruction_set != 0x3E {
-            return Err(BadArch);
-        }
+/// reads the relocations named by the dynamic section at offset
+fn read_relocations(
+    executable: &InodeRef, offset: u64, size: u64, load_base: u64, segments: &[Segment]
+) -> Result<Vec<Relocation>, ProcessSpawnError> {
+    let dynamic = read_file(executable, offset, size)?;
+    let table = match parser::parse_dynamic(&dynamic)? {
+        Some(table) => table,
+        None => return Ok(Vec::new()),
+    };
+    let table_offset = parser::file_offset(segments, table.addr.wrapping_add(load_base), table.size)?;
+    let table_bytes = read_file(executable, table_offset, table.size)?;
+    Ok(parser::parse_relocations(&table_bytes, load_base, segments)?)
+}
 
-        Ok(header)
-    }
+/// reads len bytes of executable at offset, which must already be known to lie in the file
+pub fn read_file(
+    executable: &InodeRef, offset: u64, len: u64
+) -> Result<Vec<u8>, ProcessSpawnError> {
+    let mut bytes = alloc::vec![0; len as usize];
+    executable.read_at(offset, &mut bytes)
+        .map_err(|_| ProcessSpawnError::ReadFail)?;
+    Ok(bytes)
 }
 
-/// Map elf file in new process memory space
+/// converts program header flags to PROT_* bits
 ///
-/// Requires that the currently active page table is the new process space and has the kernel mapped
-pub unsafe fn map_elf_file_to_process(
-    file: &Vec<u8>, mapper: &mut OffsetPageTable, frame_allocator: &mut impl FrameAllocator<Size4KiB>
-) -> (Vec<PhysFrame>, u64) {
-    let elf_header = ElfHeader::from_slice(&file[..size_of::<ElfHeader>()])
-        .expect("invalid elf header");
-
-    // make array of program headers
-    let program_headers = &*slice_from_raw_parts(
-        addr_of!(file[elf_header.program_header_table as usize]) as *const ProgramHeader,
-        elf_header.program_entry_count as usize
-    );
-
-    let mut regions: BTreeSet<u64> = BTreeSet::new();
-    let mut frames: Vec<PhysFrame> = Vec::new();
-    for program_header in program_headers {
-        if read_unaligned(addr_of!(program_header.header_type)) != Load {
-            continue;
-        }
-
-        let mem_size = program_header.mem_size;
-        let offset = program_header.offset;
-        let file_size = program_header.file_size;
-        let virt_addr = program_header.virt_addr;
-
-        let page_count = mem_size / 0x1000 + 1;
-        // allocate pages
-        for i in 0..page_count {
-            let page_addr = i*0x1000 + align_down(virt_addr, 0x1000);
-            if !regions.contains(&page_addr) {
-                let frame = frame_allocator.allocate_frame()
-                    .expect("failed to allocate frame");
-                mapper
-                    .map_to(
-                        Page::<Size4KiB>::containing_address(VirtAddr::new(virt_addr)),
-                        frame,
-                        PageTableFlags::PRESENT
-                            | PageTableFlags::WRITABLE
-                            | PageTableFlags::USER_ACCESSIBLE,
-                        frame_allocator
-                    )
-                    .unwrap()
-                    .flush();
-
-                frames.push(frame);
-                regions.insert(page_addr);
-            }
-        }
-
-        // copy over data
-        let mut working_offset = 0;
-        let file_data_page_count = file_size / 0x1000 + 1;
-        for _ in 0..file_data_page_count {
-            let lower_bound = offset + working_offset;
-            let page_addr = align_down(virt_addr + working_offset, 0x1000);
-            let write_size = min(page_addr+0x1000, file_size - working_offset + 1);
-            let lower_bound_offset = lower_bound % 0x1000;
-
-            // get memory page from address
-            let page = &mut *slice_from_raw_parts_mut(
-                page_addr as *mut u8,
-                0x1000
-            );
-
-            page[(lower_bound_offset as usize)..((lower_bound_offset+write_size) as usize)]
-                .copy_from_slice(
-                    &file[(lower_bound as usize)..((lower_bound+write_size) as usize)]
-                );
-            working_offset += write_size;
-        }
+/// writable segments are never executable so that text cannot be modified and data cannot run
+fn segment_protection(flags: u32) -> u64 {
+    let mut protection = 0;
+    if flags & PF_R != 0 {
+        protection |= PROT_READ;
     }
-
-    (frames, elf_header.program_entry)
+    if flags & PF_W != 0 {
+        protection |= PROT_WRITE;
+    } else if flags & PF_X != 0 {
+        protection |= PROT_EXEC;
+    }
+    protection
 }
 
-// pub unsafe fn map_elf_file_to_process(
-//     file: &Vec<u8>, mapper: &mut OffsetPageTable, frame_allocator: &mut impl FrameAllocator<Size4KiB>
-// ) -> (Vec<PhysFrame>, u64) {
-//     let elf_header = ElfHeader::from_slice(&file[..size_of::<ElfHeader>()])
-//         .expect("invalid elf header");
-//
-//     // make array of program headers
-//     let program_headers = &*slice_from_raw_parts(
-//         addr_of!(file[elf_header.program_header_table as usize]) as *const ProgramHeader,
-//         elf_header.program_entry_count as usize
-//     );
-//
-//     let mut regions: BTreeSet<u64> = BTreeSet::new();
-//     for program_header in program_headers {
-//         if read_unaligned(addr_of!(program_header.header_type)) != Load {
-//             continue;
-//         }
-//
-//         let mem_size = program_header.mem_size;
-//         let offset = program_header.offset;
-//         let file_size = program_header.file_size;
-//         let virt_addr = program_header.virt_addr;
-//
-//         let page_count = mem_size / 0x1000 + 1;
-//         // allocate pages
-//         for i in 0..page_count {
-//             let page_addr = i*0x1000 + align_down(virt_addr, 0x1000);
-//             if !regions.contains_key(&page_addr) {
-//                 let layout = Layout::from_size_align_unchecked(0x1000, 0x1000);
-//                 let mut page: [u8; 0x1000] = *(alloc_zeroed(layout) as *mut [u8; 0x1000]);
-//                 regions.insert(page_addr, page);
-//             }
-//         }
-//
-//         // copy over data
-//         let mut working_offset = 0;
-//         let file_data_page_count = file_size / 0x1000 + 1;
-//         for i in 0..file_data_page_count {
-//             let lower_bound = offset + working_offset;
-//             let page_addr = align_down(virt_addr + working_offset, 0x1000);
-//             let write_size = min(page_addr+0x1000, file_size - working_offset + 1);
-//             let lower_bound_offset = lower_bound % 0x1000;
-//             let mut page = regions.get_mut(&page_addr).unwrap();
-//
-//             page[(lower_bound_offset as usize)..((lower_bound_offset+write_size) as usize)]
-//                 .copy_from_slice(
-//                     &file[(lower_bound as usize)..((lower_bound+write_size) as usize)]
-//                 );
-//             working_offset += write_size;
-//         }
-//     }
-//
-//     // map pages
-//     for (virt_addr, page) in regions.iter() {
-//         let phys_addr = mapper.translate_addr(VirtAddr::from_ptr(page.as_ptr()))
-//             .unwrap();
-//         println!("{:2x}", phys_addr.as_u64());
-//         mapper
-//             .map_to(
-//                 Page::<Size4KiB>::from_start_address(VirtAddr::new(*virt_addr)).unwrap(),
-//                 PhysFrame::containing_address(phys_addr),
-//                 PageTableFlags::PRESENT
-//                     | PageTableFlags::WRITABLE
-//                     | PageTableFlags::USER_ACCESSIBLE,
-//                 frame_allocator
-//             )
-//             .unwrap()
-//             .flush();
-//     }
-//
-//     (regions, elf_header.program_entry)
-// }
\ No newline at end of file
+#[test_case]
+fn test_segment_protection() {
+    assert_eq!(segment_protection(PF_R | PF_X), PROT_READ | PROT_EXEC);
+    assert_eq!(segment_protection(PF_R), PROT_READ);
+    assert_eq!(segment_protection(PF_R | PF_W), PROT_READ | PROT_WRITE);
+    assert_eq!(segment_protection(PF_R | PF_W | PF_X), PROT_READ | PROT_WRITE);
+}
@@ -0,0 +1,170 @@
+//! Writer for elf core files that debuggers such as gdb can load
+//!
+//! Like the parser it only depends on core and alloc.
+
+use alloc::vec::Vec;
+use core::mem::size_of;
+use super::parser::{ProgramHeader, ELF_HEADER_SIZE, PROGRAM_HEADER_SIZE, PT_LOAD};
+
+const ET_CORE: u16 = 4;
+const PT_NOTE: u32 = 4;
+const NT_PRSTATUS: u32 = 1;
+/// the note name padded to 4 bytes, NOTE_NAME_SIZE counts up to its nul
+const NOTE_NAME: &[u8; 8] = b"CORE\0\0\0\0";
+const NOTE_NAME_SIZE: u32 = 5;
+/// size of struct elf_prstatus on x86_64
+const PRSTATUS_SIZE: usize = 336;
+const PRSTATUS_REGISTERS_OFFSET: usize = 112;
+const PAGE_SIZE: usize = 0x1000;
+
+/// registers in the order of struct user_regs_struct
+pub const REGISTER_COUNT: usize = 27;
+
+/// a range of memory copied into the core
+pub struct CoreSegment {
+    pub start: u64,
+    /// PF_* flags
+    pub flags: u32,
+    /// a multiple of the page size
+    pub len: u64,
+}
+
+/// the state of the crashed thread, written as an NT_PRSTATUS note
+pub struct PrStatus {
+    pub signal: u8,
+    pub pid: u32,
+    pub pending: u64,
+    pub blocked: u64,
+    pub registers: [u64; REGISTER_COUNT],
+}
+
+/// builds the start of a core file holding status and describing the given memory
+///
+/// the result is padded to a page, the memory of the segments follows it in order so that it
+/// can be written a page at a time without ever holding the whole core
+pub fn core_headers(segments: &[CoreSegment], status: &PrStatus) -> Vec<u8> {
+    let program_header_count = segments.len() + 1;
+    let note_offset = ELF_HEADER_SIZE + program_header_count * PROGRAM_HEADER_SIZE;
+    let note_len = 12 + NOTE_NAME.len() + PRSTATUS_SIZE;
+    let data_offset = align_up(note_offset + note_len, PAGE_SIZE);
+
+    let mut core = Vec::with_capacity(data_offset);
+    write_header(&mut core, program_header_count as u16);
+    write_program_header(&mut core, &ProgramHeader {
+        header_type: PT_NOTE,
+        flags: 0,
+        offset: note_offset as u64,
+        virt_addr: 0,
+        file_size: note_len as u64,
+        mem_size: 0,
+        align: 4,
+    });
+    let mut offset = data_offset as u64;
+    for segment in segments {
+        write_program_header(&mut core, &ProgramHeader {
+            header_type: PT_LOAD,
+            flags: segment.flags,
+            offset,
+            virt_addr: segment.start,
+            file_size: segment.len,
+            mem_size: segment.len,
+            align: PAGE_SIZE as u64,
+        });
+        offset += segment.len;
+    }
+
+    core.extend_from_slice(&NOTE_NAME_SIZE.to_le_bytes());
+    core.extend_from_slice(&(PRSTATUS_SIZE as u32).to_le_bytes());
+    core.extend_from_slice(&NT_PRSTATUS.to_le_bytes());
+    core.extend_from_slice(NOTE_NAME);
+    core.extend_from_slice(&prstatus(status));
+    core.resize(data_offset, 0);
+    core
+}
+
+fn write_header(core: &mut Vec<u8>, program_header_count: u16) {
+    let mut header = [0; ELF_HEADER_SIZE];
+    header[..4].copy_from_slice(b"\x7FELF");
+    header[4] = 2; // 64 bit
+    header[5] = 1; // little endian
+    header[6] = 1; // current version
+    header[16..18].copy_from_slice(&ET_CORE.to_le_bytes());
+    header[18..20].copy_from_slice(&0x3Eu16.to_le_bytes());
+    header[20..24].copy_from_slice(&1u32.to_le_bytes());
+    header[32..40].copy_from_slice(&(ELF_HEADER_SIZE as u64).to_le_bytes());
+    header[52..54].copy_from_slice(&(ELF_HEADER_SIZE as u16).to_le_bytes());
+    header[54..56].copy_from_slice(&(PROGRAM_HEADER_SIZE as u16).to_le_bytes());
+    header[56..58].copy_from_slice(&program_header_count.to_le_bytes());
+    core.extend_from_slice(&header);
+}
+
+fn write_program_header(core: &mut Vec<u8>, header: &ProgramHeader) {
+    for field in [header.header_type, header.flags] {
+        core.extend_from_slice(&field.to_le_bytes());
+    }
+    // the physical address is unused
+    for field in [header.offset, header.virt_addr, 0, header.file_size, header.mem_size, header.align] {
+        core.extend_from_slice(&field.to_le_bytes());
+    }
+}
+
+fn prstatus(status: &PrStatus) -> [u8; PRSTATUS_SIZE] {
+    let mut bytes = [0; PRSTATUS_SIZE];
+    bytes[..4].copy_from_slice(&(status.signal as u32).to_le_bytes());
+    bytes[12..14].copy_from_slice(&(status.signal as u16).to_le_bytes());
+    bytes[16..24].copy_from_slice(&status.pending.to_le_bytes());
+    bytes[24..32].copy_from_slice(&status.blocked.to_le_bytes());
+    bytes[32..36].copy_from_slice(&status.pid.to_le_bytes());
+    for (index, register) in status.registers.iter().enumerate() {
+        let offset = PRSTATUS_REGISTERS_OFFSET + index * size_of::<u64>();
+        bytes[offset..offset + 8].copy_from_slice(&register.to_le_bytes());
+    }
+    bytes
+}
+
+fn align_up(value: usize, align: usize) -> usize {
+    (value + align - 1) & !(align - 1)
+}
+
+#[test_case]
+fn test_prstatus_layout() {
+    let mut registers = [0; REGISTER_COUNT];
+    registers[16] = 0x401000;
+    let status = PrStatus {
+        signal: 11,
+        pid: 3,
+        pending: 0,
+        blocked: 0,
+        registers,
+    };
+    let bytes = prstatus(&status);
+    assert_eq!(bytes[12], 11);
+    assert_eq!(bytes[32], 3);
+    // rip is the 17th register
+    assert_eq!(bytes[PRSTATUS_REGISTERS_OFFSET + 16 * 8..][..8], 0x401000u64.to_le_bytes());
+    assert_eq!(PRSTATUS_REGISTERS_OFFSET + REGISTER_COUNT * 8 + 8, PRSTATUS_SIZE);
+}
+
+#[test_case]
+fn test_core_headers() {
+    let status = PrStatus {
+        signal: 11,
+        pid: 3,
+        pending: 0,
+        blocked: 0,
+        registers: [0; REGISTER_COUNT],
+    };
+    let segments = [
+        CoreSegment { start: 0x400000, flags: 0, len: 0x2000 },
+        CoreSegment { start: 0x800000, flags: 0, len: 0x1000 },
+    ];
+    let headers = core_headers(&segments, &status);
+    assert_eq!(headers.len() % PAGE_SIZE, 0);
+    // the note comes first, then the segments follow the headers back to back
+    let load_offset = |index: usize| {
+        let offset = ELF_HEADER_SIZE + (index + 1) * PROGRAM_HEADER_SIZE + 8;
+        u64::from_le_bytes(headers[offset..offset + 8].try_into().unwrap())
+    };
+    assert_eq!(load_offset(0), headers.len() as u64);
+    assert_eq!(load_offset(1), headers.len() as u64 + 0x2000);
+}
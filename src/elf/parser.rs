@@ -0,0 +1,730 @@
+//! Validating parser for 64-bit x86 elf executables
+//!
+//! Only depends on core and alloc and never trusts an offset or size from the file, so it can
+//! be built on the host (for example included with #[path] into a fuzz target) and fed
+//! arbitrary bytes.
+
+use alloc::vec::Vec;
+use core::fmt::{Display, Formatter};
+
+pub const ELF_HEADER_SIZE: usize = 64;
+pub const PROGRAM_HEADER_SIZE: usize = 56;
+/// more program headers than this are rejected rather than read
+pub const MAX_PROGRAM_HEADERS: u16 = 64;
+
+const MAGIC_NUM: &[u8; 4] = b"\x7FELF";
+const CLASS_64: u8 = 2;
+const DATA_LITTLE_ENDIAN: u8 = 1;
+const VERSION_CURRENT: u8 = 1;
+const MACHINE_X86_64: u16 = 0x3E;
+const PAGE_SIZE: u64 = 0x1000;
+
+pub const ET_EXEC: u16 = 2;
+/// position independent executable, loaded at any page aligned base
+pub const ET_DYN: u16 = 3;
+
+pub const PT_LOAD: u32 = 1;
+pub const PT_DYNAMIC: u32 = 2;
+pub const PT_INTERP: u32 = 3;
+pub const PT_PHDR: u32 = 6;
+pub const PT_TLS: u32 = 7;
+
+/// larger thread local storage templates are rejected
+pub const MAX_TLS_SIZE: u64 = 0x10_0000;
+
+/// longer interpreter paths are rejected rather than read
+pub const MAX_INTERPRETER_LEN: u64 = 256;
+
+const DYNAMIC_ENTRY_SIZE: usize = 16;
+const RELA_SIZE: u64 = 24;
+/// more relocations than this are rejected rather than read
+pub const MAX_RELOCATIONS: u64 = 0x10000;
+
+const DT_NULL: u64 = 0;
+const DT_RELA: u64 = 7;
+const DT_RELASZ: u64 = 8;
+const DT_RELAENT: u64 = 9;
+const DT_REL: u64 = 17;
+const DT_RELR: u64 = 36;
+
+const R_X86_64_NONE: u32 = 0;
+const R_X86_64_RELATIVE: u32 = 8;
+
+/// program header permission flags
+pub const PF_X: u32 = 0x1;
+pub const PF_W: u32 = 0x2;
+pub const PF_R: u32 = 0x4;
+
+/// the fields of the elf header the loader uses
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub struct ElfHeader {
+    pub elf_type: u16,
+    pub entry: u64,
+    pub program_header_offset: u64,
+    pub program_header_count: u16,
+}
+
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub struct ProgramHeader {
+    pub header_type: u32,
+    pub flags: u32,
+    pub offset: u64,
+    pub virt_addr: u64,
+    pub file_size: u64,
+    pub mem_size: u64,
+    pub align: u64,
+}
+
+/// a loadable segment that passed validation
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub struct Segment {
+    /// page aligned range the segment occupies in memory
+    pub start: u64,
+    pub end: u64,
+    pub virt_addr: u64,
+    pub offset: u64,
+    pub file_size: u64,
+    pub flags: u32,
+}
+
+/// the initial contents of the thread local storage of every thread
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub struct TlsTemplate {
+    /// file_size bytes at offset initialize the block, the rest of mem_size is zeroed
+    pub offset: u64,
+    pub file_size: u64,
+    pub mem_size: u64,
+    /// a power of two no larger than a page
+    pub align: u64,
+}
+
+/// location of the relocation table named by the dynamic section
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub struct RelaTable {
+    /// address of the table before the load base is added
+    pub addr: u64,
+    pub size: u64,
+}
+
+/// a relocated word, value is stored at addr once the page holding it is loaded
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub struct Relocation {
+    pub addr: u64,
+    pub value: u64,
+}
+
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum ElfLoadError {
+    /// the file ends before a structure it claims to contain
+    Truncated,
+    BadMagicNum,
+    Not64Bit,
+    NotLittleEndian,
+    BadVersion,
+    NotX86_64,
+    UnsupportedType(u16),
+    BadProgramHeaderSize(u16),
+    TooManyProgramHeaders(u16),
+    /// the file data of the segment at this index lies outside the file
+    SegmentOutOfFile(usize),
+    /// the segment at this index has more file data than memory
+    SegmentFileLargerThanMemory(usize),
+    BadAlignment(usize),
+    /// the segment at this index wraps around or leaves user memory
+    BadSegmentAddress(usize),
+    OverlappingSegments(usize),
+    NoLoadableSegments,
+    EntryNotExecutable(u64),
+    /// the dynamic section or the relocation table it names is malformed
+    BadDynamicSection,
+    UnsupportedDynamicTag(u64),
+    UnsupportedRelocation(u32),
+    /// the relocation at this address does not lie within a segment
+    BadRelocation(u64),
+    TooManyRelocations(u64),
+    /// the interpreter path is malformed, or the interpreter itself needs one
+    BadInterpreter,
+    /// the thread local storage template is malformed or too large
+    BadTls,
+}
+
+impl Display for ElfLoadError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> core::fmt::Result {
+        match self {
+            ElfLoadError::Truncated => write!(f, "file is truncated"),
+            ElfLoadError::BadMagicNum => write!(f, "incorrect ELF magic num"),
+            ElfLoadError::Not64Bit => write!(f, "only 64-bit elf is supported"),
+            ElfLoadError::NotLittleEndian => write!(f, "ELF is not little endian"),
+            ElfLoadError::BadVersion => write!(f, "unknown ELF version"),
+            ElfLoadError::NotX86_64 => write!(f, "ELF is not x64"),
+            ElfLoadError::UnsupportedType(elf_type) => write!(f, "unsupported ELF type {}", elf_type),
+            ElfLoadError::BadProgramHeaderSize(size) => {
+                write!(f, "program header size {} is not {}", size, PROGRAM_HEADER_SIZE)
+            },
+            ElfLoadError::TooManyProgramHeaders(count) => {
+                write!(f, "{} program headers exceed the limit of {}", count, MAX_PROGRAM_HEADERS)
+            },
+            ElfLoadError::SegmentOutOfFile(index) => {
+                write!(f, "segment {} extends past the end of the file", index)
+            },
+            ElfLoadError::SegmentFileLargerThanMemory(index) => {
+                write!(f, "segment {} has a file size larger than its memory size", index)
+            },
+            ElfLoadError::BadAlignment(index) => write!(f, "segment {} is misaligned", index),
+            ElfLoadError::BadSegmentAddress(index) => {
+                write!(f, "segment {} lies outside of user memory", index)
+            },
+            ElfLoadError::OverlappingSegments(index) => {
+                write!(f, "segment {} overlaps another segment", index)
+            },
+            ElfLoadError::NoLoadableSegments => write!(f, "no loadable segments"),
+            ElfLoadError::EntryNotExecutable(entry) => {
+                write!(f, "entry point {:#x} is not in an executable segment", entry)
+            },
+            ElfLoadError::BadDynamicSection => write!(f, "malformed dynamic section"),
+            ElfLoadError::UnsupportedDynamicTag(tag) => {
+                write!(f, "unsupported dynamic tag {:#x}", tag)
+            },
+            ElfLoadError::UnsupportedRelocation(relocation_type) => {
+                write!(f, "unsupported relocation type {}", relocation_type)
+            },
+            ElfLoadError::BadRelocation(addr) => {
+                write!(f, "relocation at {:#x} is outside of the image", addr)
+            },
+            ElfLoadError::TooManyRelocations(count) => {
+                write!(f, "{} relocations exceed the limit of {}", count, MAX_RELOCATIONS)
+            },
+            ElfLoadError::BadInterpreter => write!(f, "invalid interpreter"),
+            ElfLoadError::BadTls => write!(f, "invalid thread local storage segment"),
+        }
+    }
+}
+
+impl core::error::Error for ElfLoadError {}
+
+impl ElfHeader {
+    /// size of the program header table in bytes
+    pub fn program_headers_len(&self) -> u64 {
+        self.program_header_count as u64 * PROGRAM_HEADER_SIZE as u64
+    }
+
+    /// file offset just past the program header table
+    pub fn program_headers_end(&self) -> Result<u64, ElfLoadError> {
+        self.program_header_offset.checked_add(self.program_headers_len())
+            .ok_or(ElfLoadError::Truncated)
+    }
+
+    /// true for position independent executables
+    pub fn is_dynamic(&self) -> bool {
+        self.elf_type == ET_DYN
+    }
+}
+
+/// parses and validates the elf header at the start of bytes
+pub fn parse_header(bytes: &[u8]) -> Result<ElfHeader, ElfLoadError> {
+    if bytes.len() < ELF_HEADER_SIZE {
+        return Err(ElfLoadError::Truncated);
+    }
+    if bytes[..4] != *MAGIC_NUM {
+        return Err(ElfLoadError::BadMagicNum);
+    }
+    if bytes[4] != CLASS_64 {
+        return Err(ElfLoadError::Not64Bit);
+    }
+    if bytes[5] != DATA_LITTLE_ENDIAN {
+        return Err(ElfLoadError::NotLittleEndian);
+    }
+    if bytes[6] != VERSION_CURRENT {
+        return Err(ElfLoadError::BadVersion);
+    }
+    if read_u16(bytes, 18)? != MACHINE_X86_64 {
+        return Err(ElfLoadError::NotX86_64);
+    }
+
+    let elf_type = read_u16(bytes, 16)?;
+    if elf_type != ET_EXEC && elf_type != ET_DYN {
+        return Err(ElfLoadError::UnsupportedType(elf_type));
+    }
+    let program_header_size = read_u16(bytes, 54)?;
+    let program_header_count = read_u16(bytes, 56)?;
+    if program_header_count > MAX_PROGRAM_HEADERS {
+        return Err(ElfLoadError::TooManyProgramHeaders(program_header_count));
+    }
+    if program_header_count > 0 && program_header_size as usize != PROGRAM_HEADER_SIZE {
+        return Err(ElfLoadError::BadProgramHeaderSize(program_header_size));
+    }
+
+    Ok(ElfHeader {
+        elf_type,
+        entry: read_u64(bytes, 24)?,
+        program_header_offset: read_u64(bytes, 32)?,
+        program_header_count,
+    })
+}
+
+/// parses every program header, bytes must hold the table read from program_header_offset
+pub fn parse_program_headers(
+    header: &ElfHeader, bytes: &[u8]
+) -> Result<Vec<ProgramHeader>, ElfLoadError> {
+    if (bytes.len() as u64) < header.program_headers_len() {
+        return Err(ElfLoadError::Truncated);
+    }
+    (0..header.program_header_count as usize)
+        .map(|index| {
+            let base = index * PROGRAM_HEADER_SIZE;
+            Ok(ProgramHeader {
+                header_type: read_u32(bytes, base)?,
+                flags: read_u32(bytes, base + 4)?,
+                offset: read_u64(bytes, base + 8)?,
+                virt_addr: read_u64(bytes, base + 16)?,
+                file_size: read_u64(bytes, base + 32)?,
+                mem_size: read_u64(bytes, base + 40)?,
+                align: read_u64(bytes, base + 48)?,
+            })
+        })
+        .collect()
+}
+
+/// returns the page aligned range the loadable segments span before relocation
+pub fn image_span(program_headers: &[ProgramHeader]) -> Result<(u64, u64), ElfLoadError> {
+    let mut span: Option<(u64, u64)> = None;
+    for (index, program_header) in program_headers.iter().enumerate() {
+        if program_header.header_type != PT_LOAD || program_header.mem_size == 0 {
+            continue;
+        }
+        let start = program_header.virt_addr & !(PAGE_SIZE - 1);
+        let end = program_header.virt_addr.checked_add(program_header.mem_size)
+            .and_then(|end| end.checked_add(PAGE_SIZE - 1))
+            .map(|end| end & !(PAGE_SIZE - 1))
+            .ok_or(ElfLoadError::BadSegmentAddress(index))?;
+        span = Some(match span {
+            Some((low, high)) => (low.min(start), high.max(end)),
+            None => (start, end),
+        });
+    }
+    span.ok_or(ElfLoadError::NoLoadableSegments)
+}
+
+/// validates the loadable segments of a file of file_len bytes and moves them up by load_base
+///
+/// every segment must lie within [user_start, user_end), must not share pages with another
+/// segment and must take its data from inside the file. the entry point must be executable.
+/// load_base must be page aligned and 0 for fixed position executables
+pub fn loadable_segments(
+    header: &ElfHeader,
+    program_headers: &[ProgramHeader],
+    file_len: u64,
+    load_base: u64,
+    user_start: u64,
+    user_end: u64,
+) -> Result<Vec<Segment>, ElfLoadError> {
+    let entry = header.entry.wrapping_add(load_base);
+    let mut segments: Vec<Segment> = Vec::new();
+    for (index, program_header) in program_headers.iter().enumerate() {
+        if program_header.header_type != PT_LOAD || program_header.mem_size == 0 {
+            continue;
+        }
+        if program_header.file_size > program_header.mem_size {
+            return Err(ElfLoadError::SegmentFileLargerThanMemory(index));
+        }
+        let file_end = program_header.offset.checked_add(program_header.file_size)
+            .ok_or(ElfLoadError::SegmentOutOfFile(index))?;
+        if file_end > file_len {
+            return Err(ElfLoadError::SegmentOutOfFile(index));
+        }
+        let align = program_header.align;
+        if align > 1 && (!align.is_power_of_two()
+            || program_header.virt_addr % align != program_header.offset % align)
+        {
+            return Err(ElfLoadError::BadAlignment(index));
+        }
+
+        let virt_addr = program_header.virt_addr.checked_add(load_base)
+            .ok_or(ElfLoadError::BadSegmentAddress(index))?;
+        let end = virt_addr.checked_add(program_header.mem_size)
+            .and_then(|end| end.checked_add(PAGE_SIZE - 1))
+            .map(|end| end & !(PAGE_SIZE - 1))
+            .ok_or(ElfLoadError::BadSegmentAddress(index))?;
+        let start = virt_addr & !(PAGE_SIZE - 1);
+        if start < user_start || end > user_end {
+            return Err(ElfLoadError::BadSegmentAddress(index));
+        }
+        if segments.iter().any(|segment| start < segment.end && segment.start < end) {
+            return Err(ElfLoadError::OverlappingSegments(index));
+        }
+
+        segments.push(Segment {
+            start,
+            end,
+            virt_addr,
+            offset: program_header.offset,
+            file_size: program_header.file_size,
+            flags: program_header.flags,
+        });
+    }
+
+    if segments.is_empty() {
+        return Err(ElfLoadError::NoLoadableSegments);
+    }
+    let entry_is_executable = segments.iter().any(|segment| {
+        segment.flags & PF_X != 0
+            && segment.virt_addr <= entry
+            && entry < segment.end
+    });
+    if !entry_is_executable {
+        return Err(ElfLoadError::EntryNotExecutable(entry));
+    }
+    Ok(segments)
+}
+
+/// returns the file offset and size of the dynamic section, if there is one
+pub fn dynamic_section(
+    program_headers: &[ProgramHeader], file_len: u64
+) -> Result<Option<(u64, u64)>, ElfLoadError> {
+    let dynamic = match program_headers.iter().find(|header| header.header_type == PT_DYNAMIC) {
+        Some(dynamic) => dynamic,
+        None => return Ok(None),
+    };
+    let in_file = dynamic.offset.checked_add(dynamic.file_size)
+        .map_or(false, |end| end <= file_len);
+    if !in_file || dynamic.file_size % DYNAMIC_ENTRY_SIZE as u64 != 0 {
+        return Err(ElfLoadError::BadDynamicSection);
+    }
+    Ok(Some((dynamic.offset, dynamic.file_size)))
+}
+
+/// returns the file offset and size of the interpreter path, if there is one
+pub fn interpreter_section(
+    program_headers: &[ProgramHeader], file_len: u64
+) -> Result<Option<(u64, u64)>, ElfLoadError> {
+    let interpreter = match program_headers.iter().find(|header| header.header_type == PT_INTERP) {
+        Some(interpreter) => interpreter,
+        None => return Ok(None),
+    };
+    let in_file = interpreter.offset.checked_add(interpreter.file_size)
+        .map_or(false, |end| end <= file_len);
+    if !in_file || interpreter.file_size < 2 || interpreter.file_size > MAX_INTERPRETER_LEN {
+        return Err(ElfLoadError::BadInterpreter);
+    }
+    Ok(Some((interpreter.offset, interpreter.file_size)))
+}
+
+/// parses the nul terminated interpreter path
+pub fn parse_interpreter(bytes: &[u8]) -> Result<&str, ElfLoadError> {
+    let path = match bytes.split_last() {
+        Some((0, path)) if !path.is_empty() && !path.contains(&0) => path,
+        _ => return Err(ElfLoadError::BadInterpreter),
+    };
+    core::str::from_utf8(path)
+        .map_err(|_| ElfLoadError::BadInterpreter)
+}
+
+/// returns where the program headers are in memory once the segments are loaded
+///
+/// the address is given by PT_PHDR, otherwise it is found from the segment holding them
+pub fn program_headers_addr(
+    header: &ElfHeader, program_headers: &[ProgramHeader], segments: &[Segment], load_base: u64
+) -> Option<u64> {
+    if let Some(phdr) = program_headers.iter().find(|header| header.header_type == PT_PHDR) {
+        return Some(phdr.virt_addr.wrapping_add(load_base));
+    }
+    let table_len = header.program_header_count as u64 * PROGRAM_HEADER_SIZE as u64;
+    segments.iter()
+        .find(|segment| {
+            segment.offset <= header.program_header_offset
+                && header.program_header_offset + table_len <= segment.offset + segment.file_size
+        })
+        .map(|segment| segment.virt_addr + (header.program_header_offset - segment.offset))
+}
+
+/// returns the thread local storage template, if there is one
+pub fn tls_template(
+    program_headers: &[ProgramHeader], file_len: u64
+) -> Result<Option<TlsTemplate>, ElfLoadError> {
+    let tls = match program_headers.iter().find(|header| header.header_type == PT_TLS) {
+        Some(tls) => tls,
+        None => return Ok(None),
+    };
+    let in_file = tls.offset.checked_add(tls.file_size)
+        .map_or(false, |end| end <= file_len);
+    let align = tls.align.max(1);
+    if !in_file
+        || tls.file_size > tls.mem_size
+        || tls.mem_size > MAX_TLS_SIZE
+        || !align.is_power_of_two()
+        || align > PAGE_SIZE
+    {
+        return Err(ElfLoadError::BadTls);
+    }
+    Ok(Some(TlsTemplate {
+        offset: tls.offset,
+        file_size: tls.file_size,
+        mem_size: tls.mem_size,
+        align,
+    }))
+}
+
+/// finds the relocation table in the contents of the dynamic section
+///
+/// only rela relocations are supported, which is all x86_64 linkers emit by default
+pub fn parse_dynamic(bytes: &[u8]) -> Result<Option<RelaTable>, ElfLoadError> {
+    let mut addr = None;
+    let mut size = None;
+    let mut entry_size = RELA_SIZE;
+    for entry in bytes.chunks_exact(DYNAMIC_ENTRY_SIZE) {
+        let tag = read_u64(entry, 0)?;
+        let value = read_u64(entry, 8)?;
+        match tag {
+            DT_NULL => break,
+            DT_RELA => addr = Some(value),
+            DT_RELASZ => size = Some(value),
+            DT_RELAENT => entry_size = value,
+            DT_REL | DT_RELR => return Err(ElfLoadError::UnsupportedDynamicTag(tag)),
+            _ => {},
+        }
+    }
+    if entry_size != RELA_SIZE {
+        return Err(ElfLoadError::BadDynamicSection);
+    }
+    match (addr, size) {
+        (Some(addr), Some(size)) if size % RELA_SIZE == 0 => {
+            let count = size / RELA_SIZE;
+            if count > MAX_RELOCATIONS {
+                return Err(ElfLoadError::TooManyRelocations(count));
+            }
+            Ok(Some(RelaTable { addr, size }))
+        },
+        (None, None) => Ok(None),
+        _ => Err(ElfLoadError::BadDynamicSection),
+    }
+}
+
+/// returns the file offset of size bytes at the relocated address addr
+///
+/// the bytes must come from the file part of a single segment
+pub fn file_offset(segments: &[Segment], addr: u64, size: u64) -> Result<u64, ElfLoadError> {
+    let end = addr.checked_add(size)
+        .ok_or(ElfLoadError::BadDynamicSection)?;
+    segments.iter()
+        .find(|segment| segment.virt_addr <= addr && end <= segment.virt_addr + segment.file_size)
+        .map(|segment| segment.offset + (addr - segment.virt_addr))
+        .ok_or(ElfLoadError::BadDynamicSection)
+}
+
+/// parses a rela table and resolves every relocation against load_base
+///
+/// the result is sorted by address. each relocated word must be aligned and lie in a segment
+pub fn parse_relocations(
+    bytes: &[u8], load_base: u64, segments: &[Segment]
+) -> Result<Vec<Relocation>, ElfLoadError> {
+    let mut relocations = Vec::new();
+    for rela in bytes.chunks_exact(RELA_SIZE as usize) {
+        let offset = read_u64(rela, 0)?;
+        let relocation_type = read_u64(rela, 8)? as u32;
+        let addend = read_u64(rela, 16)?;
+        match relocation_type {
+            R_X86_64_NONE => continue,
+            R_X86_64_RELATIVE => {},
+            _ => return Err(ElfLoadError::UnsupportedRelocation(relocation_type)),
+        }
+
+        let addr = offset.wrapping_add(load_base);
+        let in_segment = segments.iter()
+            .any(|segment| segment.virt_addr <= addr && addr < segment.end - 7);
+        if addr % 8 != 0 || !in_segment {
+            return Err(ElfLoadError::BadRelocation(addr));
+        }
+        relocations.push(Relocation {
+            addr,
+            value: load_base.wrapping_add(addend),
+        });
+    }
+    relocations.sort_unstable_by_key(|relocation| relocation.addr);
+    Ok(relocations)
+}
+
+fn read_bytes<const N: usize>(bytes: &[u8], offset: usize) -> Result<[u8; N], ElfLoadError> {
+    offset.checked_add(N)
+        .and_then(|end| bytes.get(offset..end))
+        .and_then(|slice| slice.try_into().ok())
+        .ok_or(ElfLoadError::Truncated)
+}
+
+fn read_u16(bytes: &[u8], offset: usize) -> Result<u16, ElfLoadError> {
+    read_bytes(bytes, offset).map(u16::from_le_bytes)
+}
+
+fn read_u32(bytes: &[u8], offset: usize) -> Result<u32, ElfLoadError> {
+    read_bytes(bytes, offset).map(u32::from_le_bytes)
+}
+
+fn read_u64(bytes: &[u8], offset: usize) -> Result<u64, ElfLoadError> {
+    read_bytes(bytes, offset).map(u64::from_le_bytes)
+}
+
+/// an executable with one read/execute segment at 0x400000 whose header fields can be patched
+#[cfg(test)]
+fn test_elf() -> [u8; ELF_HEADER_SIZE + PROGRAM_HEADER_SIZE] {
+    let mut bytes = [0; ELF_HEADER_SIZE + PROGRAM_HEADER_SIZE];
+    bytes[..4].copy_from_slice(MAGIC_NUM);
+    bytes[4] = CLASS_64;
+    bytes[5] = DATA_LITTLE_ENDIAN;
+    bytes[6] = VERSION_CURRENT;
+    bytes[16..18].copy_from_slice(&ET_EXEC.to_le_bytes());
+    bytes[18..20].copy_from_slice(&MACHINE_X86_64.to_le_bytes());
+    bytes[24..32].copy_from_slice(&0x400078u64.to_le_bytes());
+    bytes[32..40].copy_from_slice(&(ELF_HEADER_SIZE as u64).to_le_bytes());
+    bytes[54..56].copy_from_slice(&(PROGRAM_HEADER_SIZE as u16).to_le_bytes());
+    bytes[56..58].copy_from_slice(&1u16.to_le_bytes());
+
+    let program_header = &mut bytes[ELF_HEADER_SIZE..];
+    program_header[..4].copy_from_slice(&PT_LOAD.to_le_bytes());
+    program_header[4..8].copy_from_slice(&(PF_R | PF_X).to_le_bytes());
+    program_header[16..24].copy_from_slice(&0x400000u64.to_le_bytes());
+    program_header[32..40].copy_from_slice(&0x78u64.to_le_bytes());
+    program_header[40..48].copy_from_slice(&0x1000u64.to_le_bytes());
+    program_header[48..56].copy_from_slice(&0x1000u64.to_le_bytes());
+    bytes
+}
+
+/// the program header of test_elf
+#[cfg(test)]
+fn test_segment() -> ProgramHeader {
+    ProgramHeader {
+        header_type: PT_LOAD,
+        flags: PF_R | PF_X,
+        offset: 0,
+        virt_addr: 0x400000,
+        file_size: 0x78,
+        mem_size: 0x1000,
+        align: 0x1000,
+    }
+}
+
+#[cfg(test)]
+const TEST_USER_START: u64 = 0x1000;
+#[cfg(test)]
+const TEST_USER_END: u64 = 0x0000_8000_0000_0000;
+
+#[test_case]
+fn test_valid_elf() {
+    let bytes = test_elf();
+    let header = parse_header(&bytes).unwrap();
+    assert_eq!(header.entry, 0x400078);
+    assert_eq!(header.program_headers_end(), Ok(bytes.len() as u64));
+    let program_headers = parse_program_headers(&header, &bytes[ELF_HEADER_SIZE..]).unwrap();
+    assert_eq!(program_headers, [test_segment()]);
+}
+
+#[test_case]
+fn test_truncated_elf() {
+    let bytes = test_elf();
+    assert_eq!(parse_header(&bytes[..40]), Err(ElfLoadError::Truncated));
+    let header = parse_header(&bytes).unwrap();
+    assert_eq!(
+        parse_program_headers(&header, &bytes[ELF_HEADER_SIZE..100]),
+        Err(ElfLoadError::Truncated)
+    );
+}
+
+#[test_case]
+fn test_bad_elf_header() {
+    let mut bytes = test_elf();
+    bytes[0] = 0;
+    assert_eq!(parse_header(&bytes), Err(ElfLoadError::BadMagicNum));
+    let mut bytes = test_elf();
+    bytes[4] = 1;
+    assert_eq!(parse_header(&bytes), Err(ElfLoadError::Not64Bit));
+    let mut bytes = test_elf();
+    bytes[32..40].copy_from_slice(&u64::MAX.to_le_bytes());
+    let header = parse_header(&bytes).unwrap();
+    assert_eq!(header.program_headers_end(), Err(ElfLoadError::Truncated));
+}
+
+#[test_case]
+fn test_parse_dynamic() {
+    let mut bytes = [0; DYNAMIC_ENTRY_SIZE * 3];
+    bytes[..8].copy_from_slice(&DT_RELA.to_le_bytes());
+    bytes[8..16].copy_from_slice(&0x2000u64.to_le_bytes());
+    bytes[16..24].copy_from_slice(&DT_RELASZ.to_le_bytes());
+    bytes[24..32].copy_from_slice(&(RELA_SIZE * 4).to_le_bytes());
+    assert_eq!(parse_dynamic(&bytes), Ok(Some(RelaTable { addr: 0x2000, size: RELA_SIZE * 4 })));
+    bytes[24..32].copy_from_slice(&5u64.to_le_bytes());
+    assert_eq!(parse_dynamic(&bytes), Err(ElfLoadError::BadDynamicSection));
+    bytes[..8].copy_from_slice(&DT_REL.to_le_bytes());
+    assert_eq!(parse_dynamic(&bytes), Err(ElfLoadError::UnsupportedDynamicTag(DT_REL)));
+}
+
+#[test_case]
+fn test_parse_interpreter() {
+    assert_eq!(parse_interpreter(b"/LIB/LD.SO\0"), Ok("/LIB/LD.SO"));
+    assert_eq!(parse_interpreter(b"/LIB/LD.SO"), Err(ElfLoadError::BadInterpreter));
+    assert_eq!(parse_interpreter(b"/LIB\0/LD.SO\0"), Err(ElfLoadError::BadInterpreter));
+    assert_eq!(parse_interpreter(b"\0"), Err(ElfLoadError::BadInterpreter));
+}
+
+#[test_case]
+fn test_loadable_segments() {
+    let bytes = test_elf();
+    let header = parse_header(&bytes).unwrap();
+    let file_len = bytes.len() as u64;
+    let check = |program_headers: &[ProgramHeader], load_base: u64| {
+        loadable_segments(
+            &header, program_headers, file_len, load_base, TEST_USER_START, TEST_USER_END
+        )
+    };
+    let segments = check(&[test_segment()], 0).unwrap();
+    assert_eq!((segments[0].start, segments[0].end), (0x400000, 0x401000));
+
+    let data = ProgramHeader {
+        flags: PF_R | PF_W,
+        offset: 0x78,
+        virt_addr: 0x400078,
+        file_size: 0,
+        ..test_segment()
+    };
+    assert_eq!(check(&[test_segment(), data], 0), Err(ElfLoadError::OverlappingSegments(1)));
+    let past_end = ProgramHeader { file_size: file_len + 1, mem_size: 0x2000, ..test_segment() };
+    assert_eq!(check(&[past_end], 0), Err(ElfLoadError::SegmentOutOfFile(0)));
+    let wrapping = ProgramHeader { offset: u64::MAX, ..test_segment() };
+    assert_eq!(check(&[wrapping], 0), Err(ElfLoadError::SegmentOutOfFile(0)));
+    let larger_file = ProgramHeader { mem_size: 0x10, ..test_segment() };
+    assert_eq!(check(&[larger_file], 0), Err(ElfLoadError::SegmentFileLargerThanMemory(0)));
+}
+
+#[test_case]
+fn test_segment_in_kernel_half() {
+    let bytes = test_elf();
+    let header = parse_header(&bytes).unwrap();
+    let file_len = bytes.len() as u64;
+    let kernel = ProgramHeader { virt_addr: 0xffff_8000_0000_0000, ..test_segment() };
+    assert_eq!(
+        loadable_segments(&header, &[kernel], file_len, 0, TEST_USER_START, TEST_USER_END),
+        Err(ElfLoadError::BadSegmentAddress(0))
+    );
+    // a load base can move a segment past the end of user memory too
+    assert_eq!(
+        loadable_segments(
+            &header, &[test_segment()], file_len, TEST_USER_END - 0x1000,
+            TEST_USER_START, TEST_USER_END
+        ),
+        Err(ElfLoadError::BadSegmentAddress(0))
+    );
+}
+
+#[test_case]
+fn test_entry_not_executable() {
+    let bytes = test_elf();
+    let header = parse_header(&bytes).unwrap();
+    let file_len = bytes.len() as u64;
+    let read_only = ProgramHeader { flags: PF_R, ..test_segment() };
+    assert_eq!(
+        loadable_segments(&header, &[read_only], file_len, 0, TEST_USER_START, TEST_USER_END),
+        Err(ElfLoadError::EntryNotExecutable(0x400078))
+    );
+    let outside = ElfHeader { entry: 0x401000, ..header };
+    assert_eq!(
+        loadable_segments(&outside, &[test_segment()], file_len, 0, TEST_USER_START, TEST_USER_END),
+        Err(ElfLoadError::EntryNotExecutable(0x401000))
+    );
+}
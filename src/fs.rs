@@ -1,23 +1,61 @@
-use alloc::format;
+mod dentry;
+mod fat;
+mod inode;
+mod lfn;
+mod write;
+
+use alloc::{format, vec};
 use alloc::string::{String, ToString};
 use alloc::vec::Vec;
 use core::error::Error;
+use core::cmp::min;
 use core::mem::size_of;
 use core::mem::transmute;
+use core::str::from_utf8;
 use core::pin::Pin;
 use conquer_once::spin::OnceCell;
-use spin::Mutex;
 use x86_64::structures::paging::OffsetPageTable;
 use crate::disk::{DiskAccessError, read_sectors, write_sectors};
 use crate::{println, serial_println};
-use trees::{Tree, Node, TreeWalk};
-use trees::walk::Visit;
+use crate::rtc::days_from_civil;
 use bitfield_struct::bitfield;
+use dentry::DentryCache;
+use fat::Volume;
+
+pub use inode::Fat32;
+
+static VOLUME: OnceCell<Volume> = OnceCell::uninit();
+
+/// the state of the FAT volume shared by its inodes
+struct FileSystem {
+    dentries: DentryCache,
+}
 
-pub static FILE_SYSTEM: Mutex<Option<FileSystem>> = Mutex::new(None);
-static BOOT_SECTOR: OnceCell<(usize, FatBootSector)> = OnceCell::uninit();
+/// errors of operations that change the file system
+#[derive(Debug)]
+pub enum FsError {
+    NotFound,
+    NotADirectory,
+    IsADirectory,
+    AlreadyExists,
+    /// a directory to be removed or replaced still has entries
+    NotEmpty,
+    /// the root directory cannot be removed or moved
+    Busy,
+    /// every cluster is in use
+    NoSpace,
+    /// the name cannot be stored in a directory entry
+    InvalidName,
+    /// FAT stores file sizes in 32 bits
+    FileTooLarge,
+    Disk(DiskAccessError),
+}
 
-pub struct FileSystem(Tree<File>);
+impl From<DiskAccessError> for FsError {
+    fn from(err: DiskAccessError) -> Self {
+        FsError::Disk(err)
+    }
+}
 
 #[repr(C, packed)]
 #[derive(Debug, Copy, Clone)]
@@ -60,7 +98,7 @@ pub struct FatBootSector32Ext {
 #[bitfield(u8)]
 #[derive(Default)]
 pub struct FileAttributes {
-    #[bits(1)] read_only: usize,
+    #[bits(1)] pub read_only: usize,
     #[bits(1)] hidden: usize,
     #[bits(1)] system: usize,
     #[bits(1)] volume_id: usize,
@@ -85,6 +123,26 @@ pub struct FileDate {
     #[bits(7)] year: usize,
 }
 
+/// a directory entry along with its long name, if it has one
+#[derive(Debug, Clone)]
+pub struct FileNode {
+    pub file: File,
+    pub long_name: Option<String>,
+    /// where the entry is stored, the root directory has none
+    location: Option<EntryLocation>,
+}
+
+/// the slots of a directory entry, numbered in 32 byte steps from the start of its directory
+#[derive(Debug, Copy, Clone, PartialEq)]
+struct EntryLocation {
+    /// first cluster of the directory holding the entry
+    directory: u32,
+    /// first slot of the long name, the same as slot for entries without one
+    first_slot: u32,
+    /// slot of the short entry
+    slot: u32,
+}
+
 #[repr(C, packed)]
 #[derive(Debug, Default, Copy, Clone)]
 pub struct File {
@@ -102,6 +160,27 @@ pub struct File {
     file_size: u32,
 }
 
+impl FileNode {
+    /// the long name, or the short name in NAME.EXT form for files without one
+    pub fn name(&self) -> String {
+        match &self.long_name {
+            Some(long_name) => long_name.clone(),
+            None => self.file.get_display_name(),
+        }
+    }
+
+    /// true if a path component names this file by either its long or its short name
+    ///
+    /// like the rest of FAT, long names are compared ignoring case
+    fn is_named(&self, component: &str) -> bool {
+        // FAT drops trailing dots and spaces from names
+        let component = component.trim_end_matches(|c| c == '.' || c == ' ');
+        let is_long_name = self.long_name.as_ref()
+            .map_or(false, |long_name| eq_ignore_case(long_name, component));
+        is_long_name || to_short_name(component) == Some(self.file.name)
+    }
+}
+
 impl File {
     pub fn get_name(&self) -> &str {
         if self.name[0] == 0 {
@@ -113,6 +192,50 @@ impl File {
         }
     }
 
+    /// returns the name in NAME.EXT form with padding removed
+    pub fn get_display_name(&self) -> String {
+        if self.name[0] == 0 {
+            return "/".to_string();
+        }
+        let base = from_utf8(&self.name[..8]).unwrap_or("").trim_end();
+        let extension = from_utf8(&self.name[8..]).unwrap_or("").trim_end();
+        if extension.is_empty() {
+            base.to_string()
+        } else {
+            format!("{}.{}", base, extension)
+        }
+    }
+
+    pub fn get_size(&self) -> u32 {
+        self.file_size
+    }
+
+    pub fn get_attributes(&self) -> FileAttributes {
+        self.attributes
+    }
+
+    pub fn is_directory(&self) -> bool {
+        self.attributes.directory() == 1
+    }
+
+    /// creation time in seconds since the unix epoch
+    pub fn get_created(&self) -> i64 {
+        to_unix_time({ self.creation_date }, { self.creation_time })
+            + self.creation_duration as i64 / 100
+    }
+
+    /// last modification time in seconds since the unix epoch
+    pub fn get_modified(&self) -> i64 {
+        to_unix_time({ self.modified_date }, { self.modified_time })
+    }
+
+    /// last access time in seconds since the unix epoch
+    ///
+    /// FAT only stores the date of last access so this is always midnight
+    pub fn get_accessed(&self) -> i64 {
+        to_unix_time({ self.last_accessed }, FileTime::new())
+    }
+
     pub fn get_data_addr(&self) -> u32 {
         self.cluster_l as u32 | ((self.cluster_h as u32) << 16)
     }
@@ -120,32 +243,59 @@ impl File {
     pub fn get_data(
         &self, mapper: &OffsetPageTable
     ) -> Result<Vec<u8>, DiskAccessError> {
-        let (drive_num, boot_sector) = BOOT_SECTOR.get()
-            .expect("no boot sector initialized");
-        let fat_size = if boot_sector.table_size_16 == 0 {
-            boot_sector.extended_section.table_size_32
-        } else {
-            boot_sector.table_size_16 as u32
-        };
-        let root_dir_sectors = (boot_sector.root_entry_count*32 + boot_sector.bytes_per_sector-1)
-            / boot_sector.bytes_per_sector;
-        let first_data_sector = boot_sector.reserved_sector_count as u32 +
-            (boot_sector.table_count as u32 * fat_size) + root_dir_sectors as u32;
-        let cluster_idx = self.get_data_addr();
-        let first_sector = (cluster_idx - 2) * boot_sector.sectors_per_cluster as u32
-            + first_data_sector;
-        let sector_count = self.file_size / boot_sector.bytes_per_sector as u32 + 1;
-        let mut cluster_data = read_sectors(
-            mapper, *drive_num, first_sector as u64,
-            sector_count as u16
+        let mut data = vec![0; self.file_size as usize];
+        self.read_at(mapper, 0, &mut data)?;
+        Ok(data)
+    }
+
+    /// reads up to buffer.len() bytes starting at offset, returning the amount read
+    ///
+    /// follows the cluster chain of the file up to the end of the requested range and only
+    /// reads the sectors covering it from disk
+    pub fn read_at(
+        &self, mapper: &OffsetPageTable, offset: u64, buffer: &mut [u8]
+    ) -> Result<usize, DiskAccessError> {
+        let volume = VOLUME.get()
+            .expect("no volume initialized");
+        let file_size = self.file_size as u64;
+        if offset >= file_size || buffer.is_empty() {
+            return Ok(0);
+        }
+        let count = min(buffer.len() as u64, file_size - offset);
+
+        let cluster_size = volume.cluster_size();
+        let first_index = offset / cluster_size;
+        let last_index = (offset + count - 1) / cluster_size;
+        let clusters = fat::cluster_run(
+            mapper, volume, self.get_data_addr(), first_index as usize,
+            (last_index - first_index + 1) as usize
         )?;
-        // TODO find better way to return only necessary data
-        unsafe { cluster_data.set_len(self.file_size as usize) }
-        Ok(cluster_data)
+        let bytes_per_sector = volume.bytes_per_sector as u64;
+        let mut done = 0;
+        while done < count {
+            let position = offset + done;
+            let cluster = *clusters.get((position / cluster_size - first_index) as usize)
+                .ok_or(DiskAccessError::CorruptFileSystem)?;
+            let cluster_offset = position % cluster_size;
+            let len = min(count - done, cluster_size - cluster_offset);
+
+            let start_sector = cluster_offset / bytes_per_sector;
+            let end_sector = (cluster_offset + len + bytes_per_sector - 1) / bytes_per_sector;
+            let data = read_sectors(
+                mapper, volume.drive_num, volume.cluster_sector(cluster) + start_sector,
+                (end_sector - start_sector) as u16
+            )?;
+            let skip = (cluster_offset % bytes_per_sector) as usize;
+            buffer[done as usize..(done + len) as usize]
+                .copy_from_slice(&data[skip..skip + len as usize]);
+            done += len;
+        }
+        Ok(count as usize)
     }
 }
 
-pub fn init(drive_num: usize) {
+/// reads the volume on drive_num so it can be mounted
+pub fn init(drive_num: usize) -> Fat32 {
     const BOOT_SECTOR_SIZE: usize = size_of::<FatBootSector>();
     let mapper = crate::MAPPER.get()
         .expect("mapper not initialized");
@@ -161,83 +311,117 @@ pub fn init(drive_num: usize) {
     };
 
     let root_cluster = boot_sector.extended_section.root_cluster;
-    BOOT_SECTOR.init_once(|| (drive_num, boot_sector));
+    VOLUME.init_once(|| Volume::new(drive_num, &boot_sector));
+    let volume = VOLUME.get().unwrap();
+    if let Err(err) = fat::seed_next_free(mapper, volume) {
+        serial_println!("could not read the free cluster hint of the volume: {:?}", err);
+    }
 
-    let mut fs = Tree::new(File::default());
-    parse_directory(mapper,root_cluster, &mut fs.root_mut());
+    let mut root = File::default();
+    root.attributes.set_directory(1);
+    root.cluster_l = root_cluster as u16;
+    root.cluster_h = (root_cluster >> 16) as u16;
 
-    *FILE_SYSTEM.lock() = Some(FileSystem(fs));
+    let root = FileNode {
+        file: root,
+        long_name: None,
+        location: None,
+    };
+    // directories are only read once something looks into them
+    Fat32::new(FileSystem { dentries: DentryCache::new() }, root)
 }
 
-fn tree(node: &Node<File>, depth: usize) {
-    println!("{}{}", "  ".repeat(depth), node.data().get_name());
-    for child in node.iter() {
-        tree(child, depth+1);
+/// converts a FAT date and time to seconds since the unix epoch
+///
+/// an unset (zeroed) date maps to the epoch itself
+pub fn to_unix_time(date: FileDate, time: FileTime) -> i64 {
+    if date.day() == 0 || date.month() == 0 {
+        return 0;
     }
+    let days = days_from_civil(
+        1980 + date.year() as i64, date.month() as i64, date.day() as i64
+    );
+    days * 86400
+        + time.hours() as i64 * 3600
+        + time.minutes() as i64 * 60
+        + time.seconds() as i64 * 2
 }
 
-fn parse_directory(
-    mapper: &OffsetPageTable, mut cluster_idx: u32, parent: &mut Node<File>
-) {
-    let (drive_num, boot_sector) = BOOT_SECTOR.get()
-        .expect("boot sector not initialized");
-    let fat_size = if boot_sector.table_size_16 == 0 {
-        boot_sector.extended_section.table_size_32
-    } else {
-        boot_sector.table_size_16 as u32
+/// converts a path component such as "bash" or "readme.txt" to a padded 8.3 name
+fn to_short_name(component: &str) -> Option<[u8; 11]> {
+    let (base, extension) = match component.rsplit_once('.') {
+        Some((base, extension)) => (base, extension),
+        None => (component, ""),
     };
-    let root_dir_sectors = (boot_sector.root_entry_count*32 + boot_sector.bytes_per_sector-1)
-        / boot_sector.bytes_per_sector;
-    let first_data_sector = boot_sector.reserved_sector_count as u32 +
-        (boot_sector.table_count as u32 * fat_size) + root_dir_sectors as u32;
-
-    loop {
-        let first_sector = (cluster_idx - 2) * boot_sector.sectors_per_cluster as u32
-            + first_data_sector;
-        let cluster_data = read_sectors(
-            mapper, *drive_num, first_sector as u64,
-            boot_sector.sectors_per_cluster as u16
-        ).expect("failed to read root cluster");
-        let entries = cluster_data
-            .into_iter()
-            .array_chunks::<32>();
-        for entry in entries {
-            if entry[0] == 0 {
-                return;
-            }
-            if entry[0] == 0xE5 {
-                continue;
-            }
-            if entry[11] == 0x0F {
-                panic!("detected long filename");
-            }
-
-            let file = unsafe {
-                *(entry.as_ptr() as *const File)
-            };
-            // TODO could be error prone detection mechanism
-            if file.get_name() == ".          " || file.get_name() == "..         " {
-                continue;
-            }
-            parent.push_back(Tree::new(file));
-
-            // recursively parse directories
-            if file.attributes.directory() == 1 {
-                let cluster_addr = file.get_data_addr();
-                parse_directory(
-                    mapper,cluster_addr, &mut parent.back_mut().unwrap()
-                );
-            }
-        }
-        cluster_idx += 1;
+    if base.is_empty() || base.len() > 8 || extension.len() > 3 || !component.is_ascii() {
+        return None;
     }
+
+    let mut name = [b' '; 11];
+    for (i, byte) in base.bytes().enumerate() {
+        name[i] = byte.to_ascii_uppercase();
+    }
+    for (i, byte) in extension.bytes().enumerate() {
+        name[8 + i] = byte.to_ascii_uppercase();
+    }
+    Some(name)
+}
+
+/// compares names the way FAT does, ignoring the case of ascii letters and of other
+/// characters with a single character case mapping
+fn eq_ignore_case(a: &str, b: &str) -> bool {
+    let fold = |c: char| {
+        let mut lower = c.to_lowercase();
+        match (lower.next(), lower.next()) {
+            (Some(lower), None) => lower,
+            _ => c,
+        }
+    };
+    a.chars().map(fold).eq(b.chars().map(fold))
 }
 
 impl FileSystem {
-    pub fn as_tree(&self) -> &Tree<File> {
-        &self.0
+    /// the entries of directory, without "." and ".."
+    fn entries(&mut self, directory: &FileNode) -> Result<&[FileNode], FsError> {
+        if !directory.file.is_directory() {
+            return Err(FsError::NotADirectory);
+        }
+        let mapper = crate::MAPPER.get()
+            .expect("mapper not initialized");
+        let volume = VOLUME.get()
+            .expect("volume not initialized");
+        Ok(self.dentries.entries(mapper, volume, directory.file.get_data_addr())?)
     }
-    fn as_tree_mut(&mut self) -> &mut Tree<File> { &mut self.0 }
+
+    /// the entry called name in directory, matching long names or 8.3 names in any case
+    fn child(&mut self, directory: &FileNode, name: &str) -> Result<FileNode, FsError> {
+        self.entries(directory)?
+            .iter()
+            .find(|child| child.is_named(name))
+            .cloned()
+            .ok_or(FsError::NotFound)
+    }
+}
+
+unsafe impl Send for FileSystem {}
+
+#[test_case]
+fn test_eq_ignore_case() {
+    assert!(eq_ignore_case("Long File.txt", "long FILE.TXT"));
+    assert!(eq_ignore_case("Ärger", "äRGER"));
+    assert!(!eq_ignore_case("bash", "bas"));
 }
 
-unsafe impl Send for FileSystem {}
\ No newline at end of file
+#[test_case]
+fn test_fat_epoch_to_unix_time() {
+    let date = FileDate::new().with_day(1).with_month(1).with_year(0);
+    assert_eq!(to_unix_time(date, FileTime::new()), 315532800);
+}
+
+#[test_case]
+fn test_fat_date_time_to_unix_time() {
+    // 2023-07-14 13:45:30
+    let date = FileDate::new().with_day(14).with_month(7).with_year(43);
+    let time = FileTime::new().with_hours(13).with_minutes(45).with_seconds(15);
+    assert_eq!(to_unix_time(date, time), 1689342330);
+}
@@ -0,0 +1,168 @@
+use alloc::string::String;
+use alloc::vec::Vec;
+use x86_64::structures::paging::OffsetPageTable;
+use crate::disk::{read_sectors, DiskAccessError};
+use super::fat::{self, Volume};
+use super::lfn::{self, LongName, LFN_ATTRIBUTE};
+use super::{EntryLocation, File, FileNode};
+
+/// directories kept in memory, the least recently used one is dropped first
+const CACHED_DIRECTORIES: usize = 64;
+
+struct CachedDirectory {
+    /// first cluster of the directory
+    cluster: u32,
+    entries: Vec<FileNode>,
+    last_used: u64,
+}
+
+/// entries of recently used directories, read from disk when first looked at
+///
+/// changes are written to disk right away and applied to the cached copies, so a directory
+/// can be dropped from the cache at any time
+pub struct DentryCache {
+    directories: Vec<CachedDirectory>,
+    /// counts lookups to tell which directory was used least recently
+    clock: u64,
+}
+
+impl DentryCache {
+    pub const fn new() -> Self {
+        Self {
+            directories: Vec::new(),
+            clock: 0,
+        }
+    }
+
+    /// the entries of the directory starting at cluster, in the order they are stored
+    pub fn entries(
+        &mut self, mapper: &OffsetPageTable, volume: &Volume, cluster: u32
+    ) -> Result<&[FileNode], DiskAccessError> {
+        self.clock += 1;
+        let index = match self.directories.iter().position(|cached| cached.cluster == cluster) {
+            Some(index) => index,
+            None => {
+                let cached = CachedDirectory {
+                    cluster,
+                    entries: read_directory(mapper, volume, cluster)?,
+                    last_used: 0,
+                };
+                if self.directories.len() < CACHED_DIRECTORIES {
+                    self.directories.push(cached);
+                    self.directories.len() - 1
+                } else {
+                    let (index, _) = self.directories.iter()
+                        .enumerate()
+                        .min_by_key(|(_, cached)| cached.last_used)
+                        .unwrap();
+                    self.directories[index] = cached;
+                    index
+                }
+            },
+        };
+        self.directories[index].last_used = self.clock;
+        Ok(&self.directories[index].entries)
+    }
+
+    /// adds an entry just written to disk to the copy of its directory
+    pub fn insert(&mut self, node: FileNode) {
+        let location = node.location.expect("the root has no directory");
+        if let Some(entries) = self.cached(location.directory) {
+            let index = entries.partition_point(|entry| slot_of(entry) < location.slot);
+            entries.insert(index, node);
+        }
+    }
+
+    /// drops the entry stored at location from the copy of its directory
+    pub fn remove(&mut self, location: &EntryLocation) {
+        if let Some(entries) = self.cached(location.directory) {
+            entries.retain(|entry| slot_of(entry) != location.slot);
+        }
+    }
+
+    /// replaces the file of the entry stored at location
+    pub fn update(&mut self, location: &EntryLocation, file: File) {
+        if let Some(entries) = self.cached(location.directory) {
+            let entry = entries.iter_mut()
+                .find(|entry| slot_of(entry) == location.slot);
+            if let Some(entry) = entry {
+                entry.file = file;
+            }
+        }
+    }
+
+    /// drops a deleted directory, its clusters may hold a different one later
+    pub fn forget(&mut self, cluster: u32) {
+        self.directories.retain(|cached| cached.cluster != cluster);
+    }
+
+    fn cached(&mut self, cluster: u32) -> Option<&mut Vec<FileNode>> {
+        self.directories.iter_mut()
+            .find(|cached| cached.cluster == cluster)
+            .map(|cached| &mut cached.entries)
+    }
+}
+
+fn slot_of(node: &FileNode) -> u32 {
+    node.location.map_or(0, |location| location.slot)
+}
+
+/// reads the entries of the directory starting at cluster, without "." and ".."
+fn read_directory(
+    mapper: &OffsetPageTable, volume: &Volume, cluster: u32
+) -> Result<Vec<FileNode>, DiskAccessError> {
+    let chain = fat::cluster_chain(mapper, volume, cluster)?;
+    let mut nodes = Vec::new();
+    let mut long_name = LongName::new();
+    let mut slot = 0;
+    let mut name_start = 0;
+
+    for data_cluster in chain {
+        let cluster_data = read_sectors(
+            mapper, volume.drive_num, volume.cluster_sector(data_cluster),
+            volume.sectors_per_cluster as u16
+        )?;
+        let entries = cluster_data
+            .into_iter()
+            .array_chunks::<32>();
+        for entry in entries {
+            slot += 1;
+            if entry[0] == 0 {
+                return Ok(nodes);
+            }
+            if entry[0] == 0xE5 {
+                long_name.reset();
+                continue;
+            }
+            if entry[11] == LFN_ATTRIBUTE {
+                if entry[0] & lfn::LAST_ENTRY != 0 {
+                    name_start = slot - 1;
+                }
+                long_name.push(&entry);
+                continue;
+            }
+
+            let file = unsafe {
+                *(entry.as_ptr() as *const File)
+            };
+            let name: Option<String> = long_name.take(&file.name)
+                .map(|name| char::decode_utf16(name.iter().copied())
+                    .map(|c| c.unwrap_or(char::REPLACEMENT_CHARACTER))
+                    .collect());
+            if file.name == *b".          " || file.name == *b"..         " {
+                continue;
+            }
+            let location = EntryLocation {
+                directory: cluster,
+                first_slot: if name.is_some() { name_start } else { slot - 1 },
+                slot: slot - 1,
+            };
+            nodes.push(FileNode {
+                file,
+                long_name: name,
+                location: Some(location),
+            });
+        }
+    }
+    Ok(nodes)
+}
@@ -0,0 +1,486 @@
+use alloc::vec;
+use alloc::vec::Vec;
+use core::cmp::{max, min};
+use spin::Mutex;
+use x86_64::structures::paging::OffsetPageTable;
+use crate::disk::{read_sectors, write_sectors, DiskAccessError};
+use super::{FatBootSector, FsError};
+
+/// the top four bits of a FAT32 entry are reserved
+const ENTRY_MASK: u32 = 0x0FFF_FFFF;
+const ENTRY_SIZE: u32 = 4;
+const BAD_CLUSTER: u32 = 0x0FFF_FFF7;
+/// entries from here up mark the last cluster of a chain
+const END_OF_CHAIN: u32 = 0x0FFF_FFF8;
+/// fat sectors kept in memory, the least recently used one is dropped first
+const CACHED_SECTORS: usize = 16;
+
+/// fields of the FSInfo sector, which caches the free cluster count and a hint where to
+/// look for free clusters
+const FS_INFO_LEAD_SIGNATURE: u32 = 0x4161_5252;
+const FS_INFO_STRUCT_SIGNATURE: u32 = 0x6141_7272;
+const FS_INFO_LEAD_OFFSET: usize = 0;
+const FS_INFO_STRUCT_OFFSET: usize = 484;
+const FS_INFO_FREE_COUNT_OFFSET: usize = 488;
+const FS_INFO_NEXT_FREE_OFFSET: usize = 492;
+/// stored in the free count when it is not known
+const FS_INFO_UNKNOWN: u32 = 0xFFFF_FFFF;
+
+/// recently used sectors of the first copy of the fat
+///
+/// only taken with interrupts enabled, never from interrupt context, so a task spinning on it
+/// can be preempted in favour of the one holding it. page faults load file pages with
+/// interrupts enabled too
+pub static FAT_CACHE: Mutex<FatCache> = Mutex::new(FatCache::new());
+
+/// where the parts of a FAT32 volume lie on its drive
+#[derive(Debug, Copy, Clone)]
+pub struct Volume {
+    pub drive_num: usize,
+    pub bytes_per_sector: u32,
+    pub sectors_per_cluster: u32,
+    /// first sector of the first copy of the fat
+    pub fat_start: u32,
+    /// sectors in each copy of the fat
+    pub fat_size: u32,
+    /// copies of the fat, all of them are kept identical
+    pub table_count: u32,
+    pub first_data_sector: u32,
+    /// one past the highest cluster number, the first data cluster is 2
+    pub cluster_end: u32,
+    pub fs_info_sector: Option<u32>,
+}
+
+/// what the fat says about a cluster
+#[derive(Debug, PartialEq)]
+pub enum FatEntry {
+    Free,
+    Next(u32),
+    Bad,
+    EndOfChain,
+}
+
+struct CachedSector {
+    sector: u32,
+    data: Vec<u8>,
+    last_used: u64,
+    /// changed since it was read, written to every copy of the fat when flushed or dropped
+    dirty: bool,
+}
+
+pub struct FatCache {
+    sectors: Vec<CachedSector>,
+    /// counts lookups to tell which sector was used least recently
+    clock: u64,
+    /// where the search for a free cluster starts, just past the last one allocated
+    next_free: u32,
+}
+
+impl Volume {
+    pub fn new(drive_num: usize, boot_sector: &FatBootSector) -> Self {
+        let fat_size = if boot_sector.table_size_16 == 0 {
+            boot_sector.extended_section.table_size_32
+        } else {
+            boot_sector.table_size_16 as u32
+        };
+        let total_sectors = if boot_sector.total_sectors_16 == 0 {
+            boot_sector.total_sectors_32
+        } else {
+            boot_sector.total_sectors_16 as u32
+        };
+        let bytes_per_sector = boot_sector.bytes_per_sector as u32;
+        let sectors_per_cluster = max(boot_sector.sectors_per_cluster as u32, 1);
+        let root_dir_sectors = (boot_sector.root_entry_count as u32 * 32 + bytes_per_sector - 1)
+            / bytes_per_sector;
+        let fat_start = boot_sector.reserved_sector_count as u32;
+        let first_data_sector = fat_start
+            + boot_sector.table_count as u32 * fat_size
+            + root_dir_sectors;
+
+        let cluster_count = total_sectors.saturating_sub(first_data_sector) / sectors_per_cluster;
+        // a badly formatted fat may not have an entry for every cluster
+        let fat_entries = fat_size * (bytes_per_sector / ENTRY_SIZE);
+        let fs_info_sector = boot_sector.extended_section.fat_info;
+        Self {
+            drive_num,
+            bytes_per_sector,
+            sectors_per_cluster,
+            fat_start,
+            fat_size,
+            table_count: max(boot_sector.table_count as u32, 1),
+            first_data_sector,
+            cluster_end: min(cluster_count + 2, fat_entries),
+            fs_info_sector: (fs_info_sector != 0 && fs_info_sector != 0xFFFF)
+                .then_some(fs_info_sector as u32),
+        }
+    }
+
+    pub fn cluster_size(&self) -> u64 {
+        (self.sectors_per_cluster * self.bytes_per_sector) as u64
+    }
+
+    /// first sector of a data cluster
+    pub fn cluster_sector(&self, cluster: u32) -> u64 {
+        (cluster - 2) as u64 * self.sectors_per_cluster as u64 + self.first_data_sector as u64
+    }
+
+    pub fn is_valid_cluster(&self, cluster: u32) -> bool {
+        cluster >= 2 && cluster < self.cluster_end
+    }
+
+    /// sector of the first fat copy holding the entry of cluster and the entry's offset in it
+    fn entry_location(&self, cluster: u32) -> (u32, usize) {
+        let offset = cluster * ENTRY_SIZE;
+        (self.fat_start + offset / self.bytes_per_sector, (offset % self.bytes_per_sector) as usize)
+    }
+}
+
+impl FatCache {
+    pub const fn new() -> Self {
+        Self {
+            sectors: Vec::new(),
+            clock: 0,
+            next_free: 2,
+        }
+    }
+
+    /// looks up the fat entry of cluster
+    pub fn entry(
+        &mut self, mapper: &OffsetPageTable, volume: &Volume, cluster: u32
+    ) -> Result<FatEntry, DiskAccessError> {
+        if !volume.is_valid_cluster(cluster) {
+            return Err(DiskAccessError::CorruptFileSystem);
+        }
+        let (sector, offset) = volume.entry_location(cluster);
+        let data = self.sector(mapper, volume, sector)?;
+        let entry = u32::from_le_bytes(data[offset..offset + ENTRY_SIZE as usize].try_into().unwrap());
+        Ok(parse_entry(entry))
+    }
+
+    /// sets the fat entry of cluster, which reaches the disk once the cache is flushed
+    fn set_entry(
+        &mut self, mapper: &OffsetPageTable, volume: &Volume, cluster: u32, entry: FatEntry
+    ) -> Result<(), DiskAccessError> {
+        let value = match entry {
+            FatEntry::Free => 0,
+            FatEntry::Next(next) => next,
+            FatEntry::Bad => BAD_CLUSTER,
+            FatEntry::EndOfChain => ENTRY_MASK,
+        };
+        let (sector, offset) = volume.entry_location(cluster);
+        let index = self.load(mapper, volume, sector)?;
+        let data = &mut self.sectors[index].data;
+        let bytes = &mut data[offset..offset + ENTRY_SIZE as usize];
+        // the reserved bits keep whatever they were
+        let old = u32::from_le_bytes(bytes.try_into().unwrap());
+        bytes.copy_from_slice(&((old & !ENTRY_MASK) | value).to_le_bytes());
+        self.sectors[index].dirty = true;
+        Ok(())
+    }
+
+    /// writes every changed sector to each copy of the fat
+    ///
+    /// a chain freed entry by entry only writes each sector it touched once
+    fn flush(&mut self, mapper: &OffsetPageTable, volume: &Volume) -> Result<(), DiskAccessError> {
+        for cached in self.sectors.iter_mut().filter(|cached| cached.dirty) {
+            write_back(mapper, volume, cached)?;
+        }
+        Ok(())
+    }
+
+    /// returns a sector of the fat, reading it from disk unless it is cached
+    fn sector(
+        &mut self, mapper: &OffsetPageTable, volume: &Volume, sector: u32
+    ) -> Result<&[u8], DiskAccessError> {
+        let index = self.load(mapper, volume, sector)?;
+        Ok(&self.sectors[index].data)
+    }
+
+    /// caches a sector of the fat and returns its index in the cache
+    fn load(
+        &mut self, mapper: &OffsetPageTable, volume: &Volume, sector: u32
+    ) -> Result<usize, DiskAccessError> {
+        self.clock += 1;
+        let index = match self.sectors.iter().position(|cached| cached.sector == sector) {
+            Some(index) => index,
+            None => {
+                let data = read_sectors(mapper, volume.drive_num, sector as u64, 1)?;
+                let cached = CachedSector {
+                    sector,
+                    data,
+                    last_used: 0,
+                    dirty: false,
+                };
+                if self.sectors.len() < CACHED_SECTORS {
+                    self.sectors.push(cached);
+                    self.sectors.len() - 1
+                } else {
+                    let (index, _) = self.sectors.iter()
+                        .enumerate()
+                        .min_by_key(|(_, cached)| cached.last_used)
+                        .unwrap();
+                    if self.sectors[index].dirty {
+                        write_back(mapper, volume, &mut self.sectors[index])?;
+                    }
+                    self.sectors[index] = cached;
+                    index
+                }
+            },
+        };
+        self.sectors[index].last_used = self.clock;
+        Ok(index)
+    }
+
+    /// finds a free cluster, starting after the one allocated last
+    fn find_free(
+        &mut self, mapper: &OffsetPageTable, volume: &Volume
+    ) -> Result<Option<u32>, DiskAccessError> {
+        let start = if volume.is_valid_cluster(self.next_free) { self.next_free } else { 2 };
+        for cluster in (start..volume.cluster_end).chain(2..start) {
+            if self.entry(mapper, volume, cluster)? == FatEntry::Free {
+                return Ok(Some(cluster));
+            }
+        }
+        Ok(None)
+    }
+
+    /// frees the chain starting at first, returning the number of clusters freed
+    fn free_from(
+        &mut self, mapper: &OffsetPageTable, volume: &Volume, first: u32
+    ) -> Result<u32, DiskAccessError> {
+        let mut cluster = first;
+        let mut freed = 0;
+        loop {
+            // only a cycle makes a chain longer than the volume
+            if freed >= volume.cluster_end {
+                return Err(DiskAccessError::CorruptFileSystem);
+            }
+            let entry = self.entry(mapper, volume, cluster)?;
+            self.set_entry(mapper, volume, cluster, FatEntry::Free)?;
+            freed += 1;
+            match entry {
+                FatEntry::Next(next) => cluster = next,
+                _ => return Ok(freed),
+            }
+        }
+    }
+
+    /// adds change to the free cluster count in the FSInfo sector and stores the search hint
+    ///
+    /// volumes without a valid FSInfo sector are left alone, its contents are only a hint
+    fn update_fs_info(
+        &mut self, mapper: &OffsetPageTable, volume: &Volume, change: i64
+    ) -> Result<(), DiskAccessError> {
+        let (sector, mut data) = match read_fs_info(mapper, volume)? {
+            Some(fs_info) => fs_info,
+            None => return Ok(()),
+        };
+        let free_count = fs_info_field(&data, FS_INFO_FREE_COUNT_OFFSET);
+        if free_count != FS_INFO_UNKNOWN {
+            let free_count = (free_count as i64 + change).clamp(0, volume.cluster_end as i64) as u32;
+            data[FS_INFO_FREE_COUNT_OFFSET..FS_INFO_FREE_COUNT_OFFSET + 4]
+                .copy_from_slice(&free_count.to_le_bytes());
+        }
+        data[FS_INFO_NEXT_FREE_OFFSET..FS_INFO_NEXT_FREE_OFFSET + 4]
+            .copy_from_slice(&self.next_free.to_le_bytes());
+        write_sectors(mapper, volume.drive_num, sector, 1, data)
+    }
+}
+
+/// writes a cached sector of the first fat to every copy of the fat
+fn write_back(
+    mapper: &OffsetPageTable, volume: &Volume, cached: &mut CachedSector
+) -> Result<(), DiskAccessError> {
+    for copy in 0..volume.table_count {
+        write_sectors(
+            mapper, volume.drive_num, (cached.sector + copy * volume.fat_size) as u64, 1,
+            cached.data.clone()
+        )?;
+    }
+    cached.dirty = false;
+    Ok(())
+}
+
+/// reads the FSInfo sector and returns its number and contents, if the volume has a valid one
+fn read_fs_info(
+    mapper: &OffsetPageTable, volume: &Volume
+) -> Result<Option<(u64, Vec<u8>)>, DiskAccessError> {
+    let sector = match volume.fs_info_sector {
+        Some(sector) => sector as u64,
+        None => return Ok(None),
+    };
+    let data = read_sectors(mapper, volume.drive_num, sector, 1)?;
+    let is_valid = fs_info_field(&data, FS_INFO_LEAD_OFFSET) == FS_INFO_LEAD_SIGNATURE
+        && fs_info_field(&data, FS_INFO_STRUCT_OFFSET) == FS_INFO_STRUCT_SIGNATURE;
+    Ok(is_valid.then_some((sector, data)))
+}
+
+fn fs_info_field(data: &[u8], offset: usize) -> u32 {
+    u32::from_le_bytes(data[offset..offset + 4].try_into().unwrap())
+}
+
+/// starts the search for free clusters where the FSInfo sector says they begin
+///
+/// without it the first allocation would scan the fat from cluster 2 across every used one
+pub fn seed_next_free(mapper: &OffsetPageTable, volume: &Volume) -> Result<(), DiskAccessError> {
+    let next_free = match read_fs_info(mapper, volume)? {
+        Some((_, data)) => fs_info_field(&data, FS_INFO_NEXT_FREE_OFFSET),
+        None => return Ok(()),
+    };
+    // an unknown hint is stored as all ones, which is no valid cluster either
+    if volume.is_valid_cluster(next_free) {
+        FAT_CACHE.lock().next_free = next_free;
+    }
+    Ok(())
+}
+
+/// decodes a raw FAT32 entry
+pub fn parse_entry(entry: u32) -> FatEntry {
+    match entry & ENTRY_MASK {
+        0 => FatEntry::Free,
+        // cluster 1 does not exist, so pointing at it is as broken as a bad cluster
+        1 | BAD_CLUSTER => FatEntry::Bad,
+        entry if entry >= END_OF_CHAIN => FatEntry::EndOfChain,
+        next => FatEntry::Next(next),
+    }
+}
+
+/// returns the clusters of the chain starting at first, in order
+///
+/// cluster 0 stands for the empty chain of an empty file. a chain running into a free or bad
+/// cluster, or one longer than the volume, is corrupt
+pub fn cluster_chain(
+    mapper: &OffsetPageTable, volume: &Volume, first: u32
+) -> Result<Vec<u32>, DiskAccessError> {
+    cluster_run(mapper, volume, first, 0, usize::MAX)
+}
+
+/// returns up to len clusters of the chain starting at first, beginning with the one at skip
+///
+/// the chain is only followed up to the last cluster returned, so reading the start of a large
+/// file does not walk all of it. fewer clusters are returned if the chain ends early
+pub fn cluster_run(
+    mapper: &OffsetPageTable, volume: &Volume, first: u32, skip: usize, len: usize
+) -> Result<Vec<u32>, DiskAccessError> {
+    let mut run = Vec::new();
+    if first == 0 || len == 0 {
+        return Ok(run);
+    }
+    let mut cache = FAT_CACHE.lock();
+    let mut cluster = first;
+    let mut index = 0;
+    loop {
+        // only a cycle makes a chain longer than the volume
+        if index >= volume.cluster_end as usize {
+            return Err(DiskAccessError::CorruptFileSystem);
+        }
+        if index >= skip {
+            run.push(cluster);
+            if run.len() == len {
+                return Ok(run);
+            }
+        }
+        index += 1;
+        match cache.entry(mapper, volume, cluster)? {
+            FatEntry::Next(next) => cluster = next,
+            FatEntry::EndOfChain => return Ok(run),
+            FatEntry::Free | FatEntry::Bad => return Err(DiskAccessError::CorruptFileSystem),
+        }
+    }
+}
+
+/// allocates a zeroed cluster and links it after last, or starts a new chain if last is None
+pub fn allocate_cluster(
+    mapper: &OffsetPageTable, volume: &Volume, last: Option<u32>
+) -> Result<u32, FsError> {
+    // reserved as the end of a chain nothing points to, so nobody else takes it while it is
+    // zeroed without the cache locked
+    let cluster = {
+        let mut cache = FAT_CACHE.lock();
+        let cluster = cache.find_free(mapper, volume)?
+            .ok_or(FsError::NoSpace)?;
+        cache.set_entry(mapper, volume, cluster, FatEntry::EndOfChain)?;
+        cache.flush(mapper, volume)?;
+        cache.next_free = cluster + 1;
+        cluster
+    };
+
+    // old contents must not leak into files or show up as directory entries
+    let zeroes = vec![0; volume.cluster_size() as usize];
+    let zeroed = write_sectors(
+        mapper, volume.drive_num, volume.cluster_sector(cluster),
+        volume.sectors_per_cluster as u16, zeroes
+    );
+
+    let mut cache = FAT_CACHE.lock();
+    if let Err(err) = zeroed {
+        cache.set_entry(mapper, volume, cluster, FatEntry::Free)?;
+        cache.flush(mapper, volume)?;
+        return Err(err.into());
+    }
+    if let Some(last) = last {
+        cache.set_entry(mapper, volume, last, FatEntry::Next(cluster))?;
+        cache.flush(mapper, volume)?;
+    }
+    cache.update_fs_info(mapper, volume, -1)?;
+    Ok(cluster)
+}
+
+/// frees every cluster of the chain starting at first
+pub fn free_chain(mapper: &OffsetPageTable, volume: &Volume, first: u32) -> Result<(), FsError> {
+    if first == 0 {
+        return Ok(());
+    }
+    let mut cache = FAT_CACHE.lock();
+    let freed = cache.free_from(mapper, volume, first)?;
+    cache.flush(mapper, volume)?;
+    cache.update_fs_info(mapper, volume, freed as i64)?;
+    Ok(())
+}
+
+/// makes last the end of its chain and frees the clusters that followed it
+pub fn truncate_chain(mapper: &OffsetPageTable, volume: &Volume, last: u32) -> Result<(), FsError> {
+    let mut cache = FAT_CACHE.lock();
+    let next = match cache.entry(mapper, volume, last)? {
+        FatEntry::Next(next) => next,
+        _ => return Ok(()),
+    };
+    cache.set_entry(mapper, volume, last, FatEntry::EndOfChain)?;
+    let freed = cache.free_from(mapper, volume, next)?;
+    cache.flush(mapper, volume)?;
+    cache.update_fs_info(mapper, volume, freed as i64)?;
+    Ok(())
+}
+
+#[test_case]
+fn test_parse_fat_entry() {
+    assert_eq!(parse_entry(0), FatEntry::Free);
+    assert_eq!(parse_entry(5), FatEntry::Next(5));
+    // the reserved top bits are ignored
+    assert_eq!(parse_entry(0xF000_0000), FatEntry::Free);
+    assert_eq!(parse_entry(0x0FFF_FFF7), FatEntry::Bad);
+    assert_eq!(parse_entry(0x0FFF_FFF8), FatEntry::EndOfChain);
+    assert_eq!(parse_entry(0xFFFF_FFFF), FatEntry::EndOfChain);
+}
+
+#[test_case]
+fn test_volume_layout() {
+    let volume = Volume {
+        drive_num: 0,
+        bytes_per_sector: 512,
+        sectors_per_cluster: 8,
+        fat_start: 32,
+        fat_size: 100,
+        table_count: 2,
+        first_data_sector: 232,
+        cluster_end: 1000,
+        fs_info_sector: Some(1),
+    };
+    assert_eq!(volume.cluster_size(), 4096);
+    assert_eq!(volume.cluster_sector(2), 232);
+    assert_eq!(volume.cluster_sector(3), 240);
+    assert_eq!(volume.entry_location(2), (32, 8));
+    assert_eq!(volume.entry_location(128), (33, 0));
+    assert!(!volume.is_valid_cluster(1));
+    assert!(!volume.is_valid_cluster(1000));
+}
@@ -0,0 +1,279 @@
+use alloc::collections::BTreeMap;
+use alloc::sync::{Arc, Weak};
+use alloc::vec::Vec;
+use core::any::Any;
+use spin::Mutex;
+use x86_64::instructions::interrupts;
+use crate::vfs::{self, DirEntry, FileType, InodeRef, Metadata, VfsError};
+use super::{eq_ignore_case, File, FileNode, FileSystem, FsError};
+
+/// a FAT32 volume as mounted in the vfs
+///
+/// the volume itself is global, so there can only be one
+pub struct Fat32 {
+    root: Arc<FatInode>,
+}
+
+/// the file system along with the inodes handed out for its entries
+struct FatState {
+    fs: FileSystem,
+    /// one inode per entry so every user sees the same size and clusters, keyed by the
+    /// directory and slot of the entry
+    inodes: BTreeMap<(u32, u32), Weak<FatInode>>,
+}
+
+/// a file or directory of the FAT volume
+///
+/// operations lock the shared state first and the node second. the node is only locked with
+/// interrupts disabled, so page faults can read files while a system call changes them
+pub struct FatInode {
+    state: Arc<Mutex<FatState>>,
+    node: Mutex<NodeState>,
+}
+
+struct NodeState {
+    node: FileNode,
+    /// the entry was removed, its clusters may belong to another file by now
+    is_deleted: bool,
+}
+
+impl From<FsError> for VfsError {
+    fn from(err: FsError) -> Self {
+        match err {
+            FsError::NotFound => VfsError::NotFound,
+            FsError::NotADirectory => VfsError::NotADirectory,
+            FsError::IsADirectory => VfsError::IsADirectory,
+            FsError::AlreadyExists => VfsError::AlreadyExists,
+            FsError::NotEmpty => VfsError::NotEmpty,
+            FsError::Busy => VfsError::Busy,
+            FsError::NoSpace | FsError::FileTooLarge => VfsError::NoSpace,
+            FsError::InvalidName => VfsError::InvalidName,
+            FsError::Disk(err) => VfsError::Disk(err),
+        }
+    }
+}
+
+impl Fat32 {
+    pub(super) fn new(fs: FileSystem, root: FileNode) -> Self {
+        let state = Arc::new(Mutex::new(FatState {
+            fs,
+            inodes: BTreeMap::new(),
+        }));
+        Self {
+            root: Arc::new(FatInode::new(state, root)),
+        }
+    }
+}
+
+impl vfs::FileSystem for Fat32 {
+    fn name(&self) -> &'static str {
+        "fat32"
+    }
+
+    fn root(&self) -> InodeRef {
+        self.root.clone()
+    }
+
+    fn same_name(&self, a: &str, b: &str) -> bool {
+        eq_ignore_case(a, b)
+    }
+}
+
+impl FatState {
+    /// the inode of node, the one already handed out if there is one
+    fn inode(&mut self, state: &Arc<Mutex<FatState>>, node: FileNode) -> Arc<FatInode> {
+        let key = key_of(&node);
+        if let Some(inode) = self.inodes.get(&key).and_then(Weak::upgrade) {
+            return inode;
+        }
+        let inode = Arc::new(FatInode::new(state.clone(), node));
+        self.inodes.retain(|_, inode| inode.strong_count() > 0);
+        self.inodes.insert(key, Arc::downgrade(&inode));
+        inode
+    }
+}
+
+impl FatInode {
+    fn new(state: Arc<Mutex<FatState>>, node: FileNode) -> Self {
+        Self {
+            state,
+            node: Mutex::new(NodeState {
+                node,
+                is_deleted: false,
+            }),
+        }
+    }
+
+    /// a copy of the node, which stays valid as long as the shared state is locked
+    fn node(&self) -> Result<FileNode, VfsError> {
+        interrupts::without_interrupts(|| {
+            let node = self.node.lock();
+            if node.is_deleted {
+                Err(VfsError::NotFound)
+            } else {
+                Ok(node.node.clone())
+            }
+        })
+    }
+
+    fn set_file(&self, file: File) {
+        interrupts::without_interrupts(|| self.node.lock().node.file = file);
+    }
+
+    fn file(&self) -> Result<File, VfsError> {
+        interrupts::without_interrupts(|| {
+            let node = self.node.lock();
+            if node.is_deleted { Err(VfsError::NotFound) } else { Ok(node.node.file) }
+        })
+    }
+}
+
+impl vfs::Inode for FatInode {
+    fn metadata(&self) -> Result<Metadata, VfsError> {
+        let (file, inode) = interrupts::without_interrupts(|| {
+            let node = self.node.lock();
+            (node.node.file, inode_number(&node.node))
+        });
+        let attributes = file.get_attributes();
+        // fat has no permissions beyond the read only flag
+        let mode = if attributes.read_only() == 1 { 0o555 } else { 0o755 };
+        Ok(Metadata {
+            inode,
+            file_type: file_type(&file),
+            size: file.get_size() as u64,
+            mode,
+            attributes: u8::from(attributes) as u32,
+            created: file.get_created(),
+            modified: file.get_modified(),
+            accessed: file.get_accessed(),
+        })
+    }
+
+    fn as_any(&self) -> &dyn Any {
+        self
+    }
+
+    fn lookup(&self, name: &str) -> Result<InodeRef, VfsError> {
+        let mut state = self.state.lock();
+        let directory = self.node()?;
+        let child = state.fs.child(&directory, name)?;
+        Ok(state.inode(&self.state, child))
+    }
+
+    fn read_dir(&self) -> Result<Vec<DirEntry>, VfsError> {
+        let mut state = self.state.lock();
+        let directory = self.node()?;
+        let entries = state.fs.entries(&directory)?
+            .iter()
+            .map(|child| DirEntry {
+                name: child.name(),
+                inode: inode_number(child),
+                file_type: file_type(&child.file),
+            })
+            .collect();
+        Ok(entries)
+    }
+
+    fn create(&self, name: &str, file_type: FileType) -> Result<InodeRef, VfsError> {
+        let mut state = self.state.lock();
+        let directory = self.node()?;
+        let child = match file_type {
+            FileType::Regular => state.fs.create_file(&directory, name)?,
+            FileType::Directory => state.fs.create_directory(&directory, name)?,
+            FileType::CharDevice | FileType::BlockDevice => return Err(VfsError::NotSupported),
+        };
+        Ok(state.inode(&self.state, child))
+    }
+
+    fn remove(&self, name: &str) -> Result<(), VfsError> {
+        let mut state = self.state.lock();
+        let directory = self.node()?;
+        let child = state.fs.child(&directory, name)?;
+        state.fs.remove(&child)?;
+        let inode = state.inodes.remove(&key_of(&child)).and_then(|inode| inode.upgrade());
+        if let Some(inode) = inode {
+            interrupts::without_interrupts(|| inode.node.lock().is_deleted = true);
+        }
+        Ok(())
+    }
+
+    fn rename(
+        &self, old_name: &str, new_parent: &InodeRef, new_name: &str
+    ) -> Result<(), VfsError> {
+        let new_parent = new_parent.as_any()
+            .downcast_ref::<FatInode>()
+            .ok_or(VfsError::CrossDevice)?;
+        let mut state = self.state.lock();
+        let directory = self.node()?;
+        let new_directory = new_parent.node()?;
+        let child = state.fs.child(&directory, old_name)?;
+        let replaced = match state.fs.child(&new_directory, new_name) {
+            Ok(target) if key_of(&target) != key_of(&child) => Some(target),
+            Ok(_) | Err(FsError::NotFound) => None,
+            Err(err) => return Err(err.into()),
+        };
+        let moved = state.fs.rename(&child, &new_directory, new_name, replaced.as_ref())?;
+        if let Some(replaced) = replaced {
+            let inode = state.inodes.remove(&key_of(&replaced)).and_then(|inode| inode.upgrade());
+            if let Some(inode) = inode {
+                interrupts::without_interrupts(|| inode.node.lock().is_deleted = true);
+            }
+        }
+        let inode = state.inodes.remove(&key_of(&child)).and_then(|inode| inode.upgrade());
+        if let Some(inode) = inode {
+            state.inodes.insert(key_of(&moved), Arc::downgrade(&inode));
+            interrupts::without_interrupts(|| inode.node.lock().node = moved);
+        }
+        Ok(())
+    }
+
+    fn read_at(&self, offset: u64, buffer: &mut [u8]) -> Result<usize, VfsError> {
+        // only the node lock, page faults cannot wait for the shared state
+        let file = self.file()?;
+        if file.is_directory() {
+            return Err(VfsError::IsADirectory);
+        }
+        let mapper = crate::MAPPER.get()
+            .expect("mapper not initialized");
+        Ok(file.read_at(mapper, offset, buffer)?)
+    }
+
+    fn write_at(&self, offset: u64, data: &[u8]) -> Result<usize, VfsError> {
+        let mut state = self.state.lock();
+        let node = self.node()?;
+        let file = state.fs.write_at(&node, offset, data)?;
+        self.set_file(file);
+        Ok(data.len())
+    }
+
+    fn truncate(&self, len: u64) -> Result<(), VfsError> {
+        let mut state = self.state.lock();
+        let node = self.node()?;
+        if node.file.is_directory() {
+            return Err(VfsError::IsADirectory);
+        }
+        let file = state.fs.set_len(&node, len)?;
+        self.set_file(file);
+        Ok(())
+    }
+}
+
+/// the inode number of node, made of the directory and slot of its entry
+///
+/// the root has no entry and is numbered after its cluster instead
+fn inode_number(node: &FileNode) -> u64 {
+    let (directory, slot) = key_of(node);
+    (directory as u64) << 32 | slot as u64
+}
+
+fn file_type(file: &File) -> FileType {
+    if file.is_directory() { FileType::Directory } else { FileType::Regular }
+}
+
+/// the directory and slot of the entry of node, the root has (0, its cluster)
+fn key_of(node: &FileNode) -> (u32, u32) {
+    match node.location {
+        Some(location) => (location.directory, location.slot),
+        None => (0, node.file.get_data_addr()),
+    }
+}
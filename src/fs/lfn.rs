@@ -0,0 +1,148 @@
+/// attribute byte of the entries holding parts of a long name
+pub const LFN_ATTRIBUTE: u8 = 0x0F;
+/// long names are at most this many utf16 code units
+const MAX_NAME_LEN: usize = 255;
+const CHARS_PER_ENTRY: usize = 13;
+const MAX_ENTRIES: usize = 20;
+/// set in the sequence number of the entry holding the end of the name, which comes first
+pub const LAST_ENTRY: u8 = 0x40;
+const SEQUENCE_MASK: u8 = 0x1F;
+/// byte offsets of the name characters within an entry
+const CHAR_OFFSETS: [usize; CHARS_PER_ENTRY] = [1, 3, 5, 7, 9, 14, 16, 18, 20, 22, 24, 28, 30];
+
+/// collects the long name entries that precede a short directory entry
+///
+/// they are stored in reverse, the entry with the end of the name comes first and the one
+/// with sequence number 1 last, right before the short entry they belong to
+pub struct LongName {
+    chars: [u16; MAX_ENTRIES * CHARS_PER_ENTRY],
+    /// checksum of the short name every part must carry
+    checksum: u8,
+    /// sequence number of the part expected next, 0 once the name is complete
+    next: u8,
+    /// false when there is no name in progress or its parts were out of order
+    is_valid: bool,
+}
+
+impl LongName {
+    pub const fn new() -> Self {
+        Self {
+            chars: [0; MAX_ENTRIES * CHARS_PER_ENTRY],
+            checksum: 0,
+            next: 0,
+            is_valid: false,
+        }
+    }
+
+    /// adds a long name entry, a new name starts at each entry marked as the last part
+    pub fn push(&mut self, entry: &[u8; 32]) {
+        let sequence = entry[0] & SEQUENCE_MASK;
+        if entry[0] & LAST_ENTRY != 0 {
+            self.is_valid = sequence != 0 && sequence as usize <= MAX_ENTRIES;
+            self.checksum = entry[13];
+            self.next = sequence;
+            self.chars = [0xFFFF; MAX_ENTRIES * CHARS_PER_ENTRY];
+        } else if sequence == 0 || sequence != self.next || entry[13] != self.checksum {
+            self.is_valid = false;
+        }
+        if !self.is_valid {
+            return;
+        }
+
+        let start = (sequence as usize - 1) * CHARS_PER_ENTRY;
+        for (i, offset) in CHAR_OFFSETS.iter().enumerate() {
+            self.chars[start + i] = u16::from_le_bytes([entry[*offset], entry[*offset + 1]]);
+        }
+        self.next = sequence - 1;
+    }
+
+    /// forgets the name in progress, as a deleted entry interrupts it
+    pub fn reset(&mut self) {
+        self.is_valid = false;
+    }
+
+    /// returns the utf16 name if it is complete and belongs to short_name, then starts over
+    ///
+    /// a checksum mismatch means the short entry was changed by software that does not know
+    /// about long names, so the long name is stale
+    pub fn take(&mut self, short_name: &[u8; 11]) -> Option<&[u16]> {
+        let is_complete = self.is_valid && self.next == 0 && self.checksum == checksum(short_name);
+        self.is_valid = false;
+        if !is_complete {
+            return None;
+        }
+        let len = self.chars.iter()
+            .position(|c| *c == 0 || *c == 0xFFFF)
+            .unwrap_or(self.chars.len());
+        (len > 0 && len <= MAX_NAME_LEN).then_some(&self.chars[..len])
+    }
+}
+
+/// checksum of a padded 8.3 name as stored in each of its long name entries
+pub fn checksum(short_name: &[u8; 11]) -> u8 {
+    short_name.iter()
+        .fold(0u8, |sum, byte| (sum >> 1 | sum << 7).wrapping_add(*byte))
+}
+
+/// number of entries a long name of len utf16 code units takes
+pub fn entry_count(len: usize) -> usize {
+    (len + CHARS_PER_ENTRY - 1) / CHARS_PER_ENTRY
+}
+
+/// builds the entry with the given sequence number for the long name units
+///
+/// the name ends in a nul unless it fills its last entry exactly, the rest is padding
+pub fn entry(units: &[u16], sequence: u8, checksum: u8) -> [u8; 32] {
+    let mut entry = [0u8; 32];
+    entry[0] = if sequence as usize == entry_count(units.len()) {
+        sequence | LAST_ENTRY
+    } else {
+        sequence
+    };
+    entry[11] = LFN_ATTRIBUTE;
+    entry[13] = checksum;
+    let start = (sequence as usize - 1) * CHARS_PER_ENTRY;
+    for (i, offset) in CHAR_OFFSETS.iter().enumerate() {
+        let c = match units.get(start + i) {
+            Some(c) => *c,
+            None if start + i == units.len() => 0,
+            None => 0xFFFF,
+        };
+        entry[*offset..*offset + 2].copy_from_slice(&c.to_le_bytes());
+    }
+    entry
+}
+
+#[test_case]
+fn test_lfn_checksum() {
+    assert_eq!(checksum(b"README  TXT"), 0x73);
+}
+
+#[test_case]
+fn test_assemble_long_name() {
+    let short_name = b"LONGFI~1TXT";
+    let sum = checksum(short_name);
+    // "long file name.txt" is 18 characters, so it takes two entries
+    let name: [u16; 18] = [
+        0x6c, 0x6f, 0x6e, 0x67, 0x20, 0x66, 0x69, 0x6c, 0x65, 0x20, 0x6e, 0x61, 0x6d,
+        0x65, 0x2e, 0x74, 0x78, 0x74,
+    ];
+    assert_eq!(entry_count(name.len()), 2);
+    let mut long_name = LongName::new();
+    long_name.push(&entry(&name, 2, sum));
+    long_name.push(&entry(&name, 1, sum));
+    assert_eq!(long_name.take(short_name), Some(&name[..]));
+    // the name is used up by the short entry
+    assert_eq!(long_name.take(short_name), None);
+
+    // parts with the wrong checksum or out of order are dropped
+    long_name.push(&entry(&name, 2, sum));
+    long_name.push(&entry(&name, 1, sum.wrapping_add(1)));
+    assert_eq!(long_name.take(short_name), None);
+    long_name.push(&entry(&name, 2, sum));
+    assert_eq!(long_name.take(short_name), None);
+    long_name.push(&entry(&name[..13], 1, sum));
+    assert_eq!(long_name.take(b"OTHER   TXT"), None);
+    long_name.push(&entry(&name[..13], 1, sum));
+    assert_eq!(long_name.take(short_name), Some(&name[..13]));
+}
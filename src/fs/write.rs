@@ -0,0 +1,573 @@
+use alloc::string::ToString;
+use alloc::vec;
+use alloc::vec::Vec;
+use core::cmp::min;
+use core::mem::transmute;
+use x86_64::structures::paging::OffsetPageTable;
+use crate::disk::{read_sectors, write_sectors, DiskAccessError};
+use super::fat::{self, Volume};
+use super::lfn;
+use super::{to_short_name, EntryLocation, File, FileNode, FileSystem, FsError, VOLUME};
+
+/// first byte of a deleted directory entry
+const DELETED: u8 = 0xE5;
+const ENTRY_SIZE: u64 = 32;
+/// characters no name may contain
+const INVALID_CHARS: &str = "\"*/:<>?\\|";
+/// punctuation allowed in 8.3 names besides letters and digits
+const SHORT_NAME_CHARS: &[u8] = b"$%'-_@~`!(){}^#&";
+/// long names are at most this many utf16 code units
+const MAX_NAME_LEN: usize = 255;
+
+/// the 8.3 name a long name is abbreviated to before a ~N tail makes it unique
+struct ShortBasis {
+    base: [u8; 8],
+    base_len: usize,
+    extension: [u8; 3],
+    extension_len: usize,
+}
+
+impl File {
+    fn to_bytes(&self) -> [u8; 32] {
+        unsafe { transmute(*self) }
+    }
+
+    fn set_data_addr(&mut self, cluster: u32) {
+        self.cluster_l = cluster as u16;
+        self.cluster_h = (cluster >> 16) as u16;
+    }
+}
+
+impl FileSystem {
+    /// creates an empty file called name in directory
+    ///
+    /// names that do not fit 8.3 get a generated short name and long name entries
+    pub fn create_file(&mut self, directory: &FileNode, name: &str) -> Result<FileNode, FsError> {
+        let mut file = File::default();
+        file.attributes.set_archive(1);
+        self.insert_entry(directory, name, file)
+    }
+
+    /// deletes the file or empty directory of node and frees its clusters
+    pub fn remove(&mut self, node: &FileNode) -> Result<(), FsError> {
+        if node.file.is_directory() && !self.entries(node)?.is_empty() {
+            return Err(FsError::NotEmpty);
+        }
+        self.delete_entry(node)
+    }
+
+    /// writes data at offset into the file of node, growing it as needed
+    ///
+    /// a gap between the old end of the file and offset reads as zeroes. returns the updated
+    /// entry
+    pub fn write_at(
+        &mut self, node: &FileNode, offset: u64, data: &[u8]
+    ) -> Result<File, FsError> {
+        let mut file = node.file;
+        if file.is_directory() {
+            return Err(FsError::IsADirectory);
+        }
+        if data.is_empty() {
+            return Ok(file);
+        }
+        let end = offset.checked_add(data.len() as u64).ok_or(FsError::FileTooLarge)?;
+        if end > file.file_size as u64 {
+            file = self.set_len(node, end)?;
+        }
+
+        let mapper = crate::MAPPER.get()
+            .expect("mapper not initialized");
+        let volume = VOLUME.get()
+            .expect("volume not initialized");
+        let chain = fat::cluster_chain(mapper, volume, file.get_data_addr())?;
+        write_data(mapper, volume, &chain, offset, data)?;
+        Ok(file)
+    }
+
+    /// creates a directory called name in directory with its "." and ".." entries
+    pub fn create_directory(
+        &mut self, directory: &FileNode, name: &str
+    ) -> Result<FileNode, FsError> {
+        // checked before a cluster is allocated
+        entry_name(name)?;
+        if !directory.file.is_directory() {
+            return Err(FsError::NotADirectory);
+        }
+        let mapper = crate::MAPPER.get()
+            .expect("mapper not initialized");
+        let volume = VOLUME.get()
+            .expect("volume not initialized");
+
+        let parent_cluster = dot_dot_cluster(directory);
+        let cluster = fat::allocate_cluster(mapper, volume, None)?;
+        let mut new_directory = File::default();
+        new_directory.attributes.set_directory(1);
+        new_directory.set_data_addr(cluster);
+        let result = update_slots(mapper, volume, cluster, 0, 2, |i, entry| {
+            let mut dot = new_directory;
+            if i == 0 {
+                dot.name = *b".          ";
+            } else {
+                dot.name = *b"..         ";
+                dot.set_data_addr(parent_cluster);
+            }
+            entry.copy_from_slice(&dot.to_bytes());
+        }).and_then(|()| self.insert_entry(directory, name, new_directory));
+        if result.is_err() {
+            fat::free_chain(mapper, volume, cluster)?;
+        }
+        result
+    }
+
+    /// moves the entry of node to name in directory and returns it at its new place
+    ///
+    /// the name has to be free unless it names node itself, such as when only its case
+    /// changes, or replaced, the entry it names. replaced is only deleted once node has moved,
+    /// so a failed move keeps it. a directory that moves to another directory gets its ".."
+    /// entry updated
+    pub fn rename(
+        &mut self, node: &FileNode, directory: &FileNode, name: &str, replaced: Option<&FileNode>
+    ) -> Result<FileNode, FsError> {
+        if let Some(replaced) = replaced {
+            if replaced.file.is_directory() && !self.entries(replaced)?.is_empty() {
+                return Err(FsError::NotEmpty);
+            }
+        }
+        let file = node.file;
+        let old_location = node.location.ok_or(FsError::Busy)?;
+        let same_parent = directory.file.get_data_addr() == old_location.directory;
+
+        let mapper = crate::MAPPER.get()
+            .expect("mapper not initialized");
+        let volume = VOLUME.get()
+            .expect("volume not initialized");
+        let skip: Vec<u32> = same_parent.then_some(old_location.slot)
+            .into_iter()
+            .chain(replaced.and_then(|replaced| replaced.location).map(|location| location.slot))
+            .collect();
+        let new_node = self.write_entry(directory, name, file, &skip)?;
+        update_slots(
+            mapper, volume, old_location.directory, old_location.first_slot,
+            old_location.slot - old_location.first_slot + 1,
+            |_, entry| entry[0] = DELETED
+        )?;
+        if file.is_directory() && !same_parent {
+            let parent_cluster = dot_dot_cluster(directory);
+            update_slots(mapper, volume, file.get_data_addr(), 1, 1, |_, entry| {
+                entry[20..22].copy_from_slice(&((parent_cluster >> 16) as u16).to_le_bytes());
+                entry[26..28].copy_from_slice(&(parent_cluster as u16).to_le_bytes());
+            })?;
+        }
+        self.dentries.remove(&old_location);
+        self.dentries.insert(new_node.clone());
+        if let Some(replaced) = replaced {
+            self.delete_entry(replaced)?;
+        }
+        Ok(new_node)
+    }
+
+    /// stores file under name in the directory and adds it to the dentry cache
+    fn insert_entry(
+        &mut self, directory: &FileNode, name: &str, file: File
+    ) -> Result<FileNode, FsError> {
+        let node = self.write_entry(directory, name, file, &[])?;
+        self.dentries.insert(node.clone());
+        Ok(node)
+    }
+
+    /// stores file under name in the directory, returning its node
+    ///
+    /// the entries at the slots in skip are ignored when looking for files with the same name
+    fn write_entry(
+        &mut self, directory: &FileNode, name: &str, mut file: File, skip: &[u32]
+    ) -> Result<FileNode, FsError> {
+        let name = entry_name(name)?;
+        let units: Vec<u16> = name.encode_utf16().collect();
+        if !directory.file.is_directory() {
+            return Err(FsError::NotADirectory);
+        }
+
+        let entries = self.entries(directory)?;
+        let is_taken = entries.iter()
+            .filter(|entry| !entry.location.map_or(false, |location| skip.contains(&location.slot)))
+            .any(|entry| entry.is_named(name));
+        if is_taken {
+            return Err(FsError::AlreadyExists);
+        }
+        let (short_name, needs_long_name) = match exact_short_name(name) {
+            Some(short_name) => (short_name, false),
+            None => {
+                let basis = short_basis(name);
+                let short_name = (1..1_000_000)
+                    .map(|n| numbered_short_name(&basis, n))
+                    .find(|short_name| entries.iter().all(|entry| entry.file.name != *short_name))
+                    .ok_or(FsError::NoSpace)?;
+                (short_name, true)
+            },
+        };
+        let directory_cluster = directory.file.get_data_addr();
+
+        file.name = short_name;
+        let mut slots = Vec::new();
+        if needs_long_name {
+            let checksum = lfn::checksum(&short_name);
+            for sequence in (1..=lfn::entry_count(units.len())).rev() {
+                slots.push(lfn::entry(&units, sequence as u8, checksum));
+            }
+        }
+        slots.push(file.to_bytes());
+
+        let mapper = crate::MAPPER.get()
+            .expect("mapper not initialized");
+        let volume = VOLUME.get()
+            .expect("volume not initialized");
+        let first_slot = free_slots(mapper, volume, directory_cluster, slots.len() as u32)?;
+        update_slots(
+            mapper, volume, directory_cluster, first_slot, slots.len() as u32,
+            |i, entry| entry.copy_from_slice(&slots[i])
+        )?;
+
+        Ok(FileNode {
+            file,
+            long_name: needs_long_name.then(|| name.to_string()),
+            location: Some(EntryLocation {
+                directory: directory_cluster,
+                first_slot,
+                slot: first_slot + slots.len() as u32 - 1,
+            }),
+        })
+    }
+
+    /// removes the entry of node from its directory and frees its clusters
+    fn delete_entry(&mut self, node: &FileNode) -> Result<(), FsError> {
+        let location = node.location.ok_or(FsError::Busy)?;
+        let first_cluster = node.file.get_data_addr();
+
+        let mapper = crate::MAPPER.get()
+            .expect("mapper not initialized");
+        let volume = VOLUME.get()
+            .expect("volume not initialized");
+        // the entry goes first, stopping in between leaks clusters instead of sharing them
+        update_slots(
+            mapper, volume, location.directory, location.first_slot,
+            location.slot - location.first_slot + 1,
+            |_, entry| entry[0] = DELETED
+        )?;
+        fat::free_chain(mapper, volume, first_cluster)?;
+        self.dentries.remove(&location);
+        if node.file.is_directory() {
+            self.dentries.forget(first_cluster);
+        }
+        Ok(())
+    }
+
+    /// resizes the file of node to len bytes, new bytes read as zeroes
+    pub fn set_len(&mut self, node: &FileNode, len: u64) -> Result<File, FsError> {
+        if len > u32::MAX as u64 {
+            return Err(FsError::FileTooLarge);
+        }
+        let mapper = crate::MAPPER.get()
+            .expect("mapper not initialized");
+        let volume = VOLUME.get()
+            .expect("volume not initialized");
+
+        let mut file = node.file;
+        let old_len = file.file_size as u64;
+        let chain = resize_chain(mapper, volume, &mut file, len)?;
+        // the rest of the last cluster may still hold data from before the file shrank,
+        // clusters allocated just now are already zeroed
+        let cluster_size = volume.cluster_size();
+        let tail_end = min(len, (old_len + cluster_size - 1) / cluster_size * cluster_size);
+        if tail_end > old_len {
+            let zeroes = vec![0; (tail_end - old_len) as usize];
+            write_data(mapper, volume, &chain, old_len, &zeroes)?;
+        }
+        file.file_size = len as u32;
+        self.store(node, file)
+    }
+
+    /// replaces the entry of node on disk and in the dentry cache
+    fn store(&mut self, node: &FileNode, file: File) -> Result<File, FsError> {
+        if let Some(location) = node.location {
+            let mapper = crate::MAPPER.get()
+                .expect("mapper not initialized");
+            let volume = VOLUME.get()
+                .expect("volume not initialized");
+            update_slots(
+                mapper, volume, location.directory, location.slot, 1,
+                |_, entry| entry.copy_from_slice(&file.to_bytes())
+            )?;
+            self.dentries.update(&location, file);
+        }
+        Ok(file)
+    }
+}
+
+/// the cluster ".." entries of subdirectories of directory point to, 0 for the root
+fn dot_dot_cluster(directory: &FileNode) -> u32 {
+    if directory.location.is_none() {
+        0
+    } else {
+        directory.file.get_data_addr()
+    }
+}
+
+/// the name as it is stored in a directory entry, if it can be stored at all
+fn entry_name(name: &str) -> Result<&str, FsError> {
+    // FAT drops trailing dots and spaces from names, which also turns "." and ".." to ""
+    let name = name.trim_end_matches(|c| c == '.' || c == ' ');
+    let is_valid = !name.is_empty()
+        && name.encode_utf16().count() <= MAX_NAME_LEN
+        && !name.chars().any(|c| c.is_control() || INVALID_CHARS.contains(c));
+    if is_valid { Ok(name) } else { Err(FsError::InvalidName) }
+}
+
+fn is_short_name_char(byte: u8) -> bool {
+    byte.is_ascii_uppercase() || byte.is_ascii_digit() || SHORT_NAME_CHARS.contains(&byte)
+}
+
+/// the 8.3 name of name if it can be stored without a long name
+///
+/// that takes an upper case name made of characters valid in 8.3 names with at most one dot
+fn exact_short_name(name: &str) -> Option<[u8; 11]> {
+    let (base, extension) = name.split_once('.').unwrap_or((name, ""));
+    let is_valid = base.bytes().chain(extension.bytes()).all(is_short_name_char);
+    to_short_name(name).filter(|_| is_valid)
+}
+
+/// abbreviates a long name the way windows does, dropping spaces and leading dots and
+/// replacing characters 8.3 names cannot hold with underscores
+fn short_basis(name: &str) -> ShortBasis {
+    let name = name.trim_start_matches('.');
+    let (base, extension) = name.rsplit_once('.').unwrap_or((name, ""));
+    let mut basis = ShortBasis {
+        base: [b' '; 8],
+        base_len: 0,
+        extension: [b' '; 3],
+        extension_len: 0,
+    };
+    let convert = |c: char| {
+        let byte = if c.is_ascii() { c.to_ascii_uppercase() as u8 } else { b'_' };
+        if is_short_name_char(byte) { byte } else { b'_' }
+    };
+    for byte in base.chars().filter(|c| *c != ' ' && *c != '.').map(convert).take(8) {
+        basis.base[basis.base_len] = byte;
+        basis.base_len += 1;
+    }
+    for byte in extension.chars().filter(|c| *c != ' ').map(convert).take(3) {
+        basis.extension[basis.extension_len] = byte;
+        basis.extension_len += 1;
+    }
+    if basis.base_len == 0 {
+        basis.base[0] = b'_';
+        basis.base_len = 1;
+    }
+    basis
+}
+
+/// the basis with a ~n tail, the base is shortened to keep it within 8 characters
+fn numbered_short_name(basis: &ShortBasis, n: u32) -> [u8; 11] {
+    let mut digits = [0u8; 10];
+    let mut digit_count = 0;
+    let mut rest = n;
+    loop {
+        digits[digit_count] = b'0' + (rest % 10) as u8;
+        digit_count += 1;
+        rest /= 10;
+        if rest == 0 {
+            break;
+        }
+    }
+
+    let mut name = [b' '; 11];
+    let kept = min(basis.base_len, 8 - 1 - digit_count);
+    name[..kept].copy_from_slice(&basis.base[..kept]);
+    name[kept] = b'~';
+    for i in 0..digit_count {
+        name[kept + 1 + i] = digits[digit_count - 1 - i];
+    }
+    name[8..].copy_from_slice(&basis.extension);
+    name
+}
+
+/// finds count consecutive free slots in directory, extending it if there are none
+fn free_slots(
+    mapper: &OffsetPageTable, volume: &Volume, directory: u32, count: u32
+) -> Result<u32, FsError> {
+    let mut chain = fat::cluster_chain(mapper, volume, directory)?;
+    if chain.is_empty() {
+        return Err(DiskAccessError::CorruptFileSystem.into());
+    }
+    let slots_per_cluster = (volume.cluster_size() / ENTRY_SIZE) as u32;
+    let slot_count = chain.len() as u32 * slots_per_cluster;
+    // start of the free slots at the end of the directory, if there are any
+    let mut run_start = slot_count;
+    let mut run = 0;
+    'clusters: for (i, cluster) in chain.iter().enumerate() {
+        let data = read_sectors(
+            mapper, volume.drive_num, volume.cluster_sector(*cluster),
+            volume.sectors_per_cluster as u16
+        )?;
+        for (j, entry) in data.chunks(ENTRY_SIZE as usize).enumerate() {
+            let slot = i as u32 * slots_per_cluster + j as u32;
+            match entry[0] {
+                // every slot from the end marker on is free
+                0 => {
+                    if run == 0 {
+                        run_start = slot;
+                    }
+                    break 'clusters;
+                },
+                DELETED => {
+                    if run == 0 {
+                        run_start = slot;
+                    }
+                    run += 1;
+                    if run == count {
+                        return Ok(run_start);
+                    }
+                },
+                _ => {
+                    run = 0;
+                    run_start = slot_count;
+                },
+            }
+        }
+    }
+
+    // the free slots at the end of the directory are too few or there are none
+    while (chain.len() as u32) * slots_per_cluster < run_start + count {
+        let cluster = fat::allocate_cluster(mapper, volume, chain.last().copied())?;
+        chain.push(cluster);
+    }
+    Ok(run_start)
+}
+
+/// runs patch on count directory entries of directory starting at first_slot and writes
+/// them back, it gets the index of the entry and its 32 bytes
+fn update_slots(
+    mapper: &OffsetPageTable, volume: &Volume, directory: u32, first_slot: u32, count: u32,
+    mut patch: impl FnMut(usize, &mut [u8])
+) -> Result<(), FsError> {
+    let chain = fat::cluster_chain(mapper, volume, directory)?;
+    let cluster_size = volume.cluster_size();
+    let bytes_per_sector = volume.bytes_per_sector as u64;
+    // entries are written a sector at a time
+    let mut pending: Option<(u64, Vec<u8>)> = None;
+    for i in 0..count {
+        let position = (first_slot + i) as u64 * ENTRY_SIZE;
+        let cluster = *chain.get((position / cluster_size) as usize)
+            .ok_or(DiskAccessError::CorruptFileSystem)?;
+        let sector = volume.cluster_sector(cluster) + position % cluster_size / bytes_per_sector;
+        if pending.as_ref().map_or(true, |(pending_sector, _)| *pending_sector != sector) {
+            if let Some((pending_sector, data)) = pending.take() {
+                write_sectors(mapper, volume.drive_num, pending_sector, 1, data)?;
+            }
+            pending = Some((sector, read_sectors(mapper, volume.drive_num, sector, 1)?));
+        }
+        let (_, data) = pending.as_mut().unwrap();
+        let offset = (position % bytes_per_sector) as usize;
+        patch(i as usize, &mut data[offset..offset + ENTRY_SIZE as usize]);
+    }
+    if let Some((sector, data)) = pending {
+        write_sectors(mapper, volume.drive_num, sector, 1, data)?;
+    }
+    Ok(())
+}
+
+/// makes the chain of file just long enough for len bytes and returns it
+///
+/// the first cluster of file changes when its chain starts or ends. if the volume runs out
+/// of space the chain is left as it was
+fn resize_chain(
+    mapper: &OffsetPageTable, volume: &Volume, file: &mut File, len: u64
+) -> Result<Vec<u32>, FsError> {
+    let cluster_size = volume.cluster_size();
+    let needed = ((len + cluster_size - 1) / cluster_size) as usize;
+    let mut chain = fat::cluster_chain(mapper, volume, file.get_data_addr())?;
+    let old_len = chain.len();
+
+    if needed < old_len {
+        if needed == 0 {
+            fat::free_chain(mapper, volume, chain[0])?;
+            file.set_data_addr(0);
+        } else {
+            fat::truncate_chain(mapper, volume, chain[needed - 1])?;
+        }
+        chain.truncate(needed);
+    }
+    while chain.len() < needed {
+        match fat::allocate_cluster(mapper, volume, chain.last().copied()) {
+            Ok(cluster) => chain.push(cluster),
+            Err(err) => {
+                if chain.len() > old_len && old_len == 0 {
+                    fat::free_chain(mapper, volume, chain[0])?;
+                } else if chain.len() > old_len {
+                    fat::truncate_chain(mapper, volume, chain[old_len - 1])?;
+                }
+                return Err(err);
+            },
+        }
+    }
+    if old_len == 0 && !chain.is_empty() {
+        file.set_data_addr(chain[0]);
+    }
+    Ok(chain)
+}
+
+/// writes data at offset into the clusters of chain, which have to cover the range
+fn write_data(
+    mapper: &OffsetPageTable, volume: &Volume, chain: &[u32], offset: u64, data: &[u8]
+) -> Result<(), FsError> {
+    let cluster_size = volume.cluster_size();
+    let bytes_per_sector = volume.bytes_per_sector as u64;
+    let count = data.len() as u64;
+    let mut done = 0;
+    while done < count {
+        let position = offset + done;
+        let cluster = *chain.get((position / cluster_size) as usize)
+            .ok_or(DiskAccessError::CorruptFileSystem)?;
+        let cluster_offset = position % cluster_size;
+        let len = min(count - done, cluster_size - cluster_offset);
+
+        let start_sector = cluster_offset / bytes_per_sector;
+        let end_sector = (cluster_offset + len + bytes_per_sector - 1) / bytes_per_sector;
+        let sector = volume.cluster_sector(cluster) + start_sector;
+        let sector_count = end_sector - start_sector;
+        let skip = (cluster_offset % bytes_per_sector) as usize;
+        // sectors only partly covered keep the rest of their contents
+        let mut buffer = if skip == 0 && len % bytes_per_sector == 0 {
+            vec![0; (sector_count * bytes_per_sector) as usize]
+        } else {
+            read_sectors(mapper, volume.drive_num, sector, sector_count as u16)?
+        };
+        buffer[skip..skip + len as usize]
+            .copy_from_slice(&data[done as usize..(done + len) as usize]);
+        write_sectors(mapper, volume.drive_num, sector, sector_count as u16, buffer)?;
+        done += len;
+    }
+    Ok(())
+}
+
+#[test_case]
+fn test_short_names() {
+    assert_eq!(exact_short_name("README.TXT"), Some(*b"README  TXT"));
+    // lower case and a second dot need a long name
+    assert_eq!(exact_short_name("readme.txt"), None);
+    assert_eq!(exact_short_name("A.B.C"), None);
+    assert_eq!(exact_short_name("A+B"), None);
+
+    let basis = short_basis("long file name.text");
+    assert_eq!(numbered_short_name(&basis, 1), *b"LONGFI~1TEX");
+    assert_eq!(numbered_short_name(&basis, 12), *b"LONGF~12TEX");
+    let basis = short_basis(".a+b");
+    assert_eq!(numbered_short_name(&basis, 3), *b"A_B~3      ");
+}
+
+#[test_case]
+fn test_entry_name() {
+    assert_eq!(entry_name("notes.txt. ").ok(), Some("notes.txt"));
+    assert!(entry_name("..").is_err());
+    assert!(entry_name("a:b").is_err());
+    assert!(entry_name("").is_err());
+}
@@ -73,4 +73,11 @@ pub unsafe fn set_usermode_segments() -> (u16, u16) {
     DS::set_reg(ds);
 
     (cs.0, ds.0)
-}
\ No newline at end of file
+}
+
+/// sets the stack the cpu switches to when an interrupt arrives in ring 3
+pub unsafe fn set_kernel_stack(stack_top: VirtAddr) {
+    // the tss is only read by the cpu, which does so on the next privilege change
+    let tss = &*TSS as *const TaskStateSegment as *mut TaskStateSegment;
+    (*tss).privilege_stack_table[0] = stack_top;
+}
@@ -1,16 +1,29 @@
 
+pub mod trap;
+
 use x86_64::structures::idt::{InterruptDescriptorTable, InterruptStackFrame, PageFaultErrorCode};
+use x86_64::instructions::interrupts;
+use x86_64::registers::rflags::RFlags;
 use crate::{gdt, hlt_loop, println, serial_println};
-use crate::threading::scheduler::SCHEDULER;
+use crate::process::address_space;
+use crate::process::ptrace::TRAP_FLAG;
+use crate::process::signal::{self, SIGBUS, SIGFPE, SIGILL, SIGSEGV, SIGTRAP};
+use crate::threading::scheduler::{current_pid, with_current_process, SCHEDULER};
 use lazy_static::lazy_static;
 use spin::Mutex;
 use pic8259::ChainedPics;
 use x86_64::instructions::port::Port;
+use core::sync::atomic::{AtomicU64, Ordering};
+use x86_64::VirtAddr;
+use trap::{trap_entry, TrapFrame};
 
 pub const PIC_1_OFFSET: u8 = 32;
 pub const TIMER_FREQUENCY: u32 = 1073;
 const TIMER_FREQUENCY_BASE: u32 = 1193182;
 
+/// timer interrupts since boot
+static TICKS: AtomicU64 = AtomicU64::new(0);
+
 pub static PICS: Mutex<ChainedPics> = Mutex::new(unsafe {
     ChainedPics::new_contiguous(PIC_1_OFFSET)
 });
@@ -18,23 +31,33 @@ pub static PICS: Mutex<ChainedPics> = Mutex::new(unsafe {
 lazy_static! {
     static ref IDT: InterruptDescriptorTable = {
         let mut idt = InterruptDescriptorTable::new();
-        idt.breakpoint.set_handler_fn(breakpoint_handler);
         unsafe {
             // backup stack for double fault
             idt.double_fault.set_handler_fn(double_fault_handler)
                 .set_stack_index(gdt::DOUBLE_FAULT_IST_INDEX);
+
+            // the timer and exceptions user code can cause need the full trap frame to
+            // deliver signals and switch tasks
+            idt[InterruptIndex::Timer.as_usize()]
+                .set_handler_addr(VirtAddr::new(timer_entry as u64));
+            // int3 is executed by user code, so it needs a gate ring 3 may use
+            idt.breakpoint.set_handler_addr(VirtAddr::new(breakpoint_entry as u64))
+                .set_privilege_level(x86_64::PrivilegeLevel::Ring3);
+            idt.debug.set_handler_addr(VirtAddr::new(debug_entry as u64));
+            idt.divide_error.set_handler_addr(VirtAddr::new(divide_error_entry as u64));
+            idt.page_fault.set_handler_addr(VirtAddr::new(page_fault_entry as u64));
+            idt.general_protection_fault
+                .set_handler_addr(VirtAddr::new(protection_fault_entry as u64));
+            idt.stack_segment_fault
+                .set_handler_addr(VirtAddr::new(stack_segment_fault_entry as u64));
+            idt.invalid_opcode.set_handler_addr(VirtAddr::new(invalid_opcode_entry as u64));
+            idt.alignment_check.set_handler_addr(VirtAddr::new(alignment_entry as u64));
+            idt.segment_not_present
+                .set_handler_addr(VirtAddr::new(segment_not_present_entry as u64));
         }
-        idt.page_fault.set_handler_fn(page_fault_handler);
-        idt[InterruptIndex::Timer.as_usize()]
-            .set_handler_fn(timer_interrupt_handler);
         idt[InterruptIndex::Keyboard.as_usize()]
             .set_handler_fn(keyboard_interrupt_handler);
-        idt.general_protection_fault.set_handler_fn(protection_fault_handler);
-        idt.stack_segment_fault.set_handler_fn(stack_segment_fault_handler);
-        idt.invalid_opcode.set_handler_fn(invalid_opcode_handler);
         idt.invalid_tss.set_handler_fn(invalid_tss_handler);
-        idt.alignment_check.set_handler_fn(alignment_handler);
-        idt.segment_not_present.set_handler_fn(segment_not_present_handler);
         idt
     };
 }
@@ -51,6 +74,16 @@ impl InterruptIndex {
     fn as_usize(self) -> usize { usize::from(self.as_u8()) }
 }
 
+/// returns the number of timer ticks since boot
+pub fn ticks() -> u64 {
+    TICKS.load(Ordering::Relaxed)
+}
+
+/// converts a duration in milliseconds to timer ticks, rounding up
+pub fn ms_to_ticks(ms: u64) -> u64 {
+    (ms * TIMER_FREQUENCY as u64 + 999) / 1000
+}
+
 pub fn init_idt() {
     // set timer frequency
     let mut timer_port = Port::new(0x40);
@@ -61,38 +94,63 @@ pub fn init_idt() {
     IDT.load();
 }
 
-extern "x86-interrupt" fn segment_not_present_handler(
-    stack_frame: InterruptStackFrame, error_code: u64
-) {
-    println!("SEGMENT NOT PRESENT {}\n{:#?}", error_code, stack_frame);
+trap_entry!(segment_not_present_entry, segment_not_present_handler, error_code);
+trap_entry!(alignment_entry, alignment_handler, error_code);
+trap_entry!(invalid_opcode_entry, invalid_opcode_handler);
+trap_entry!(breakpoint_entry, breakpoint_handler);
+trap_entry!(debug_entry, debug_handler);
+trap_entry!(divide_error_entry, divide_error_handler);
+trap_entry!(stack_segment_fault_entry, stack_segment_fault_handler, error_code);
+trap_entry!(protection_fault_entry, protection_fault_handler, error_code);
+trap_entry!(page_fault_entry, page_fault_handler, error_code);
+
+extern "C" fn segment_not_present_handler(frame: &mut TrapFrame) {
+    fault("SEGMENT NOT PRESENT", SIGBUS, frame);
 }
 
-extern "x86-interrupt" fn alignment_handler(stack_frame: InterruptStackFrame, error_code: u64) {
-    println!("ALIGNMENT CHECK {}\n{:#?}", error_code, stack_frame);
+extern "C" fn alignment_handler(frame: &mut TrapFrame) {
+    fault("ALIGNMENT CHECK", SIGBUS, frame);
 }
 
 extern "x86-interrupt" fn invalid_tss_handler(stack_frame: InterruptStackFrame, error_code: u64) {
     println!("INVALID TSS {}\n{:#?}", error_code, stack_frame);
 }
 
-extern "x86-interrupt" fn invalid_opcode_handler(stack_frame: InterruptStackFrame) {
-    println!("INVALID OPCODE\n{:#?}", stack_frame);
+extern "C" fn invalid_opcode_handler(frame: &mut TrapFrame) {
+    fault("INVALID OPCODE", SIGILL, frame);
 }
 
-extern "x86-interrupt" fn breakpoint_handler(stack_frame: InterruptStackFrame) {
-    println!("BREAKPOINT EXCEPTION\n{:#?}", stack_frame);
+extern "C" fn breakpoint_handler(frame: &mut TrapFrame) {
+    if frame.is_user() {
+        with_current_process(|process| process.signals_mut().force(SIGTRAP));
+        signal::deliver(frame);
+    } else {
+        println!("BREAKPOINT EXCEPTION\n{:#x?}", frame);
+    }
 }
 
-extern "x86-interrupt" fn stack_segment_fault_handler(
-    stack_frame: InterruptStackFrame, error_code: u64
-) {
-    println!("STACK SEGMENT FAULT: code {}\n{:#?}", error_code, stack_frame);
+/// raised after each instruction while a tracer single steps a process
+extern "C" fn debug_handler(frame: &mut TrapFrame) {
+    if frame.is_user() {
+        // the tracer sets the flag again for every step
+        frame.rflags &= !TRAP_FLAG;
+        with_current_process(|process| process.signals_mut().force(SIGTRAP));
+        signal::deliver(frame);
+    } else {
+        println!("DEBUG EXCEPTION\n{:#x?}", frame);
+    }
+}
+
+extern "C" fn divide_error_handler(frame: &mut TrapFrame) {
+    fault("DIVIDE ERROR", SIGFPE, frame);
+}
+
+extern "C" fn stack_segment_fault_handler(frame: &mut TrapFrame) {
+    fault("STACK SEGMENT FAULT", SIGBUS, frame);
 }
 
-extern "x86-interrupt" fn protection_fault_handler(
-    stack_frame: InterruptStackFrame, error_code: u64
-) {
-    println!("PROTECTION FAULT: code {}\n{:#?}", error_code, stack_frame);
+extern "C" fn protection_fault_handler(frame: &mut TrapFrame) {
+    fault("PROTECTION FAULT", SIGSEGV, frame);
 }
 
 extern "x86-interrupt" fn double_fault_handler(
@@ -101,14 +159,25 @@ extern "x86-interrupt" fn double_fault_handler(
     panic!("DOUBLE FAULT EXCEPTION\nERROR CODE: {}\n{:#?}", error_code, stack_frame);
 }
 
-extern "x86-interrupt" fn timer_interrupt_handler(_stack_frame: InterruptStackFrame) {
+trap_entry!(timer_entry, timer_interrupt_handler);
+
+extern "C" fn timer_interrupt_handler(frame: &mut TrapFrame) {
+    TICKS.fetch_add(1, Ordering::Relaxed);
+
     // signal end of interrupt
     unsafe {
         PICS.lock()
             .notify_end_of_interrupt(InterruptIndex::Timer.as_u8());
     }
 
-    SCHEDULER.lock().tick();
+    // the scheduler may already be held by a task that is idling for a wakeup
+    if let Some(mut scheduler) = SCHEDULER.try_lock() {
+        scheduler.tick();
+    }
+
+    if frame.is_user() {
+        crate::process::signal::deliver(frame);
+    }
 }
 
 extern "x86-interrupt" fn keyboard_interrupt_handler(_stack_frame: InterruptStackFrame) {
@@ -118,6 +187,7 @@ extern "x86-interrupt" fn keyboard_interrupt_handler(_stack_frame: InterruptStac
     // keyboard ps/2 port
     let mut port = Port::new(0x60);
     let scancode: u8 = unsafe { port.read() };
+    crate::console::add_scancode(scancode);
     keyboard::add_scancode(scancode);
 
     // signal end of interrupt
@@ -127,17 +197,64 @@ extern "x86-interrupt" fn keyboard_interrupt_handler(_stack_frame: InterruptStac
     }
 }
 
-extern "x86-interrupt" fn page_fault_handler(
-    stack_frame: InterruptStackFrame, error_code: PageFaultErrorCode)
-{
+extern "C" fn page_fault_handler(frame: &mut TrapFrame) {
     use x86_64::registers::control::Cr2;
 
+    let address = Cr2::read().as_u64();
+    let error_code = PageFaultErrorCode::from_bits_truncate(frame.error_code);
+    // user pages are mapped when first touched. loading one may read the disk, which waits for
+    // locks other tasks hold with interrupts enabled, so the fault runs with them enabled too
+    // unless the faulting code had turned them off
+    if !error_code.contains(PageFaultErrorCode::PROTECTION_VIOLATION) {
+        let can_block = frame.rflags & RFlags::INTERRUPT_FLAG.bits() != 0;
+        if can_block {
+            interrupts::enable();
+        }
+        let is_handled = address_space::handle_page_fault(
+            address, error_code.contains(PageFaultErrorCode::CAUSED_BY_WRITE)
+        );
+        // the trap returns with interrupts disabled
+        interrupts::disable();
+        if is_handled {
+            return;
+        }
+    }
+
+    if frame.is_user() {
+        fault("PAGE FAULT", SIGSEGV, frame);
+        return;
+    }
+    // syscalls fault in user buffers before they take locks, so even a bad user address
+    // faulting in the kernel is a kernel bug
     serial_println!(
-        "PAGE FAULT EXCEPTION\nAddress: {:?}\nError Code: {:?}\n{:#?}",
+        "PAGE FAULT EXCEPTION\nAddress: {:?}\nError Code: {:?}\n{:#x?}",
         Cr2::read(),
-        error_code,
-        stack_frame
+        PageFaultErrorCode::from_bits_truncate(frame.error_code),
+        frame
     );
 
     hlt_loop();
 }
+
+/// handles an exception by signalling the process that caused it
+///
+/// the process is terminated unless it handles the signal. exceptions in the kernel are fatal
+fn fault(name: &str, signal: u8, frame: &mut TrapFrame) {
+    use x86_64::registers::control::Cr2;
+
+    if !frame.is_user() {
+        panic!("{} IN KERNEL: code {:#x}\n{:#x?}", name, frame.error_code, frame);
+    }
+
+    let pid = current_pid();
+    println!(
+        "process {:?} crashed: {} at {:#x} (code {:#x}, address {:#x})",
+        pid, name, frame.rip, frame.error_code, Cr2::read().as_u64()
+    );
+    serial_println!("CRASH REPORT\nprocess: {:?}\nexception: {}\nsignal: {}\n{:#x?}",
+        pid, name, signal, frame);
+
+    with_current_process(|process| process.signals_mut().force(signal));
+    // terminates the process with status 128 + signal unless a handler is installed
+    signal::deliver(frame);
+}
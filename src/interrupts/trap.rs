@@ -0,0 +1,107 @@
+use core::arch::asm;
+
+/// every general purpose register of the interrupted context followed by the frame pushed by
+/// the cpu, laid out as the trap entry stubs push them
+#[repr(C)]
+#[derive(Debug, Default, Copy, Clone)]
+pub struct TrapFrame {
+    pub r15: u64,
+    pub r14: u64,
+    pub r13: u64,
+    pub r12: u64,
+    pub r11: u64,
+    pub r10: u64,
+    pub r9: u64,
+    pub r8: u64,
+    pub rbp: u64,
+    pub rdi: u64,
+    pub rsi: u64,
+    pub rdx: u64,
+    pub rcx: u64,
+    pub rbx: u64,
+    pub rax: u64,
+    pub error_code: u64,
+    pub rip: u64,
+    pub cs: u64,
+    pub rflags: u64,
+    pub rsp: u64,
+    pub ss: u64,
+}
+
+impl TrapFrame {
+    /// true if the trap interrupted ring 3
+    pub fn is_user(&self) -> bool {
+        self.cs & 0b11 == 3
+    }
+}
+
+/// defines a naked interrupt entry that saves a TrapFrame on the kernel stack and passes it to
+/// `extern "C" fn $handler(&mut TrapFrame)`
+///
+/// vectors where the cpu does not push an error code get a zero in its place
+macro_rules! trap_entry {
+    ($name:ident, $handler:path) => {
+        trap_entry!($name, $handler, "push 0 // no error code");
+    };
+    ($name:ident, $handler:path, error_code) => {
+        trap_entry!($name, $handler, "// error code pushed by the cpu");
+    };
+    ($name:ident, $handler:path, $error_code:literal) => {
+        #[naked]
+        pub extern "C" fn $name() {
+            unsafe { core::arch::asm!(concat!($error_code, "
+                push rax
+                push rbx
+                push rcx
+                push rdx
+                push rsi
+                push rdi
+                push rbp
+                push r8
+                push r9
+                push r10
+                push r11
+                push r12
+                push r13
+                push r14
+                push r15
+                mov rdi, rsp // the trap frame is the only argument
+                sub rsp, 8 // the cpu aligned rsp before pushing 21 words
+                call {handler}
+                add rsp, 8
+                jmp {trap_return}
+            "),
+            handler = sym $handler,
+            trap_return = sym $crate::interrupts::trap::trap_return,
+            options(noreturn)
+            ); }
+        }
+    };
+}
+pub(crate) use trap_entry;
+
+/// restores the registers in the TrapFrame at the top of the stack and returns from the trap
+#[naked]
+pub extern "C" fn trap_return() {
+    unsafe { asm!("\
+        pop r15
+        pop r14
+        pop r13
+        pop r12
+        pop r11
+        pop r10
+        pop r9
+        pop r8
+        pop rbp
+        pop rdi
+        pop rsi
+        pop rdx
+        pop rcx
+        pop rbx
+        pop rax
+        add rsp, 8 // discard error code
+        iretq
+    ",
+    options(noreturn)
+    ); }
+}
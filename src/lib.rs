@@ -16,18 +16,23 @@
 
 extern crate alloc;
 
+use alloc::sync::Arc;
 use alloc::vec::Vec;
 use core::panic::PanicInfo;
 use bootloader::BootInfo;
 #[cfg(test)]
 use bootloader::{entry_point, BootInfo};
 use conquer_once::spin::OnceCell;
+use spin::Mutex;
 use x86_64::structures::paging::OffsetPageTable;
 use x86_64::VirtAddr;
-use crate::fs::FileSystem;
 use crate::memory::{BuddyAllocator, LinearFrameAllocator};
 use crate::process::Process;
 use crate::threading::scheduler::SCHEDULER;
+use crate::vfs::InodeRef;
+use crate::vfs::devfs::Devfs;
+use crate::vfs::procfs::Procfs;
+use crate::vfs::tmpfs::Tmpfs;
 
 pub mod display;
 pub mod interrupts;
@@ -40,14 +45,21 @@ pub mod threading;
 pub mod userspace;
 pub mod syscall;
 pub mod fs;
+pub mod vfs;
 pub mod disk;
 pub mod acpi;
 pub mod pci;
 pub mod elf;
+pub mod console;
+pub mod random;
+pub mod rtc;
 
 #[cfg(test)]
 entry_point!(test_kernel_main);
 
+/// bytes of file data /tmp holds, which come out of the kernel heap
+const TMP_CAPACITY: u64 = 0x40000;
+
 pub static BOOT_INFO: OnceCell<&'static BootInfo> = OnceCell::uninit();
 pub static MAPPER: OnceCell<OffsetPageTable> = OnceCell::uninit();
 
@@ -68,7 +80,7 @@ pub static MAPPER: OnceCell<OffsetPageTable> = OnceCell::uninit();
 ///
 /// 7. initializes acpi, pci, and disk drivers
 ///
-/// 8. parses the file system
+/// 8. mounts the file system, a tmpfs at /tmp, the devices at /dev and process status at /proc
 ///
 /// 9. finds /bin/bash and executes it
 pub fn init(boot_info: &'static BootInfo) {
@@ -79,11 +91,15 @@ pub fn init(boot_info: &'static BootInfo) {
     interrupts::init_idt();
     unsafe { interrupts::PICS.lock().initialize() };
     x86_64::instructions::interrupts::enable();
+    rtc::init();
 
     // create physical mapping
-    let mut mapper = unsafe { memory::init() };
+    let mut mapper = unsafe {
+        memory::enable_no_execute();
+        memory::init()
+    };
 
-    let mut frame_allocator = unsafe {
+    let frame_allocator = unsafe {
         // make a simple allocator to get a kernel heap running
         let mut simple_allocator = LinearFrameAllocator::init(&boot_info.memory_map);
 
@@ -94,47 +110,40 @@ pub fn init(boot_info: &'static BootInfo) {
         BuddyAllocator::init(&boot_info.memory_map, simple_allocator.get_used())
     };
     MAPPER.init_once(|| mapper);
+    memory::FRAME_ALLOCATOR.init_once(|| Mutex::new(frame_allocator));
 
     acpi::init(boot_info.physical_memory_offset);
     pci::init(boot_info.physical_memory_offset);
     disk::init();
     // TODO make drive num dynamic
-    fs::init(1);
+    vfs::mount("/", Arc::new(fs::init(1)))
+        .expect("failed to mount the root file system");
+    vfs::mount("/tmp", Arc::new(Tmpfs::new(TMP_CAPACITY)))
+        .expect("failed to mount /tmp");
+    vfs::mount("/dev", Arc::new(Devfs::new()))
+        .expect("failed to mount /dev");
+    vfs::mount("/proc", Arc::new(Procfs::new()))
+        .expect("failed to mount /proc");
 
-    // find shell
-    let fs_guard = fs::FILE_SYSTEM.lock();
-    let fs = fs_guard.as_ref()
-        .expect("file system not initialized");
-    let shell = get_bash(fs);
+    let shell = get_bash();
 
     let process = unsafe {
-        Process::spawn_from_file(&shell, &mut frame_allocator)
+        let mut frame_allocator = memory::FRAME_ALLOCATOR.get()
+            .expect("frame allocator not initialized")
+            .lock();
+        Process::spawn_from_file(&shell, &["/BIN/BASH"], &mut *frame_allocator)
             .unwrap()
     };
     let mut scheduler = SCHEDULER.lock();
-    scheduler.push_task(process);
+    let pid = scheduler.push_task(process);
+    // the shell owns the console and hands it to its jobs
+    console::set_session_leader(pid);
     scheduler.enable();
 }
 
-fn get_bash(fs: &FileSystem) -> Vec<u8> {
-    let bash_file = fs.as_tree()
-        .root()
-        .iter()
-        .find(|n| {
-            let name = n.data().get_name();
-            name == "BIN        "
-        })
-        .expect("no bin folder in root directory")
-        .iter()
-        .find(|n| {
-            let name = n.data().get_name();
-            name == "BASH       "
-        })
+fn get_bash() -> InodeRef {
+    vfs::lookup("/bin/bash", "/")
         .expect("no bash executable in /bin")
-        .data();
-    let data = bash_file.get_data(MAPPER.get().unwrap())
-        .expect("failed to get bash data (corrupted disk?)");
-    data
 }
 
 /// CPU efficient loop
@@ -177,6 +186,13 @@ pub fn test_panic_handler(info: &PanicInfo) -> ! {
 #[cfg(test)]
 #[no_mangle]
 fn test_kernel_main(boot_info: &'static BootInfo) -> ! {
+    // only the heap is set up, for tests of data structures that allocate
+    BOOT_INFO.init_once(|| boot_info);
+    let mut mapper = unsafe { memory::init() };
+    let mut frame_allocator = unsafe { LinearFrameAllocator::init(&boot_info.memory_map) };
+    allocator::init_heap(&mut mapper, &mut frame_allocator)
+        .expect("Failed to initialize heap");
+
     test_main();
     hlt_loop();
 }
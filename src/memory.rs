@@ -4,10 +4,38 @@ use alloc::vec::Vec;
 use x86_64::structures::paging::{FrameAllocator, FrameDeallocator, OffsetPageTable, PageTable, PhysFrame, Size4KiB};
 use x86_64::{PhysAddr, VirtAddr};
 use bootloader::bootinfo::{MemoryMap, MemoryRegionType};
+use conquer_once::spin::OnceCell;
+use spin::Mutex;
+use x86_64::instructions::interrupts;
 use crate::println;
 
 const MAX_ORDER: usize = 0x100_000;
 
+/// frame allocator shared by the kernel once the heap is running
+pub static FRAME_ALLOCATOR: OnceCell<Mutex<BuddyAllocator>> = OnceCell::uninit();
+
+/// runs a closure with the global frame allocator
+///
+/// interrupts are disabled so a page fault handler cannot deadlock on the allocator
+pub fn with_frame_allocator<R>(f: impl FnOnce(&mut BuddyAllocator) -> R) -> R {
+    let allocator = FRAME_ALLOCATOR.get()
+        .expect("frame allocator not initialized");
+    interrupts::without_interrupts(|| f(&mut allocator.lock()))
+}
+
+/// returns the kernel virtual address of a physical frame through the physical memory mapping
+pub fn frame_to_virt(frame: PhysFrame) -> VirtAddr {
+    let phys_offset = crate::BOOT_INFO.get()
+        .expect("boot info not initialized")
+        .physical_memory_offset;
+    VirtAddr::new(phys_offset + frame.start_address().as_u64())
+}
+
+/// fills a frame with zeroes
+pub unsafe fn zero_frame(frame: PhysFrame) {
+    core::ptr::write_bytes(frame_to_virt(frame).as_mut_ptr::<u8>(), 0, 0x1000);
+}
+
 
 /// Initialize physical memory offset page table
 pub unsafe fn init() -> OffsetPageTable<'static> {
@@ -18,6 +46,13 @@ pub unsafe fn init() -> OffsetPageTable<'static> {
     OffsetPageTable::new(level_4_table, VirtAddr::new(phys_offset))
 }
 
+/// allows pages to be marked NO_EXECUTE, must run before any such page is mapped
+pub unsafe fn enable_no_execute() {
+    use x86_64::registers::model_specific::{Efer, EferFlags};
+
+    Efer::update(|flags| flags.insert(EferFlags::NO_EXECUTE_ENABLE));
+}
+
 pub unsafe fn active_level_4_table(physical_memory_offset: u64) -> &'static mut PageTable
 {
     use x86_64::registers::control::Cr3;
@@ -34,7 +69,13 @@ pub unsafe fn active_level_4_table(physical_memory_offset: u64) -> &'static mut
 /// Allocates frames from bootlaoder's memory map
 pub struct BuddyAllocator {
     memory_map: &'static MemoryMap,
-    frame_iter: Box<dyn Iterator<Item = PhysFrame>>
+    frame_iter: Box<dyn Iterator<Item = PhysFrame> + Send>,
+    /// frames returned by deallocate_frame, handed out before fresh ones
+    free_frames: Vec<PhysFrame>,
+    /// usable frames in the memory map
+    total_frames: usize,
+    /// frames handed out and not returned, including those used before this allocator existed
+    allocated_frames: usize,
 }
 
 impl BuddyAllocator {
@@ -50,10 +91,22 @@ impl BuddyAllocator {
 
         Self {
             memory_map,
-            frame_iter: Box::new(frame_iter)
+            frame_iter: Box::new(frame_iter),
+            free_frames: Vec::new(),
+            total_frames: Self::usable_frames(memory_map).count(),
+            allocated_frames: used,
         }
     }
 
+    pub fn total_frames(&self) -> usize {
+        self.total_frames
+    }
+
+    /// frames that can still be allocated
+    pub fn available_frames(&self) -> usize {
+        self.total_frames - self.allocated_frames
+    }
+
     /// Returns an iterator over all usable frames
     fn usable_frames(memory_map: &'static MemoryMap) -> impl Iterator<Item = PhysFrame> {
         let regions = memory_map.iter();
@@ -67,13 +120,19 @@ impl BuddyAllocator {
 
 unsafe impl FrameAllocator::<Size4KiB> for BuddyAllocator {
     fn allocate_frame(&mut self) -> Option<PhysFrame> {
-        self.frame_iter.next()
+        let frame = self.free_frames.pop()
+            .or_else(|| self.frame_iter.next());
+        if frame.is_some() {
+            self.allocated_frames += 1;
+        }
+        frame
     }
 }
 
 impl FrameDeallocator::<Size4KiB> for BuddyAllocator {
     unsafe fn deallocate_frame(&mut self, frame: PhysFrame<Size4KiB>) {
-        todo!()
+        self.free_frames.push(frame);
+        self.allocated_frames -= 1;
     }
 }
 
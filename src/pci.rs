@@ -174,6 +174,14 @@ impl DeviceConfigurationSpace {
         &*(addr as *const DeviceConfigurationSpace)
     }
 
+    pub fn get_vendor_id(&self) -> u16 {
+        self.vendor_id
+    }
+
+    pub fn get_device_id(&self) -> u16 {
+        self.device_id
+    }
+
     pub fn get_identification(&self) -> DeviceIdentification {
         DeviceIdentification {
             class: self.class_code,
@@ -1,7 +1,19 @@
+pub mod file_table;
+pub mod pipe;
+pub mod signal;
+pub mod vma;
+pub mod address_space;
+pub mod initial_stack;
+mod tls;
+pub mod shebang;
+pub mod core_dump;
+pub mod ptrace;
+
 use crate::memory::BuddyAllocator;
 
 use crate::{gdt, memory, println, process, serial_println, userspace};
 use alloc::boxed::Box;
+use alloc::format;
 use alloc::collections::BTreeMap;
 use alloc::vec::Vec;
 use core::arch::asm;
@@ -14,19 +26,56 @@ use x86_64::structures::paging::{FrameAllocator, Mapper, OffsetPageTable, Page,
 use x86_64::{PhysAddr, VirtAddr};
 use x86_64::instructions::{interrupts, tlb};
 use x86_64::registers::control::Cr3;
-use crate::elf::ProgramHeader;
+use x86_64::registers::model_specific::FsBase;
+use crate::elf::{self, ElfLoadError, ImageRole, Relocation};
+use crate::elf::parser::PROGRAM_HEADER_SIZE;
 use crate::threading::thread::State;
+use crate::process::file_table::FileTable;
+use crate::process::signal::SignalState;
+use crate::process::vma::{Vma, VmaKind, VmaList, PROT_READ, PROT_WRITE};
+use crate::process::address_space::{random_load_base, Layout, MMAP_BASE, USER_MEMORY_START};
+use crate::process::shebang::MAX_SHEBANG_LEN;
+use crate::process::ptrace::Tracee;
+use crate::process::initial_stack::{StackBuilder, AT_BASE, AT_ENTRY, AT_PAGESZ, AT_PHDR, AT_PHENT, AT_PHNUM};
+use crate::random::random_u64;
+use crate::vfs::{self, FileType, InodeRef};
 
 const USERSPACE_VIRT_BASE: u64 = 0x400000;
-const USERSPACE_STACK: u64 = 0x810000;
-const USERSPACE_STACK_BASE: u64 = 0x800000;
+/// the stack area a process starts with, it grows down on demand
+const INITIAL_STACK_SIZE: u64 = 0x10000;
+/// pages at the top of the stack holding the arguments, environment and auxiliary vector
+const INITIAL_STACK_PAGES: usize = 2;
+/// shared objects are found here, both by the kernel for interpreters and by the dynamic loader
+const LIBRARY_PATH: &str = "/LIB";
+const LIBRARY_PATH_ENV: &str = "LD_LIBRARY_PATH=/LIB";
+/// stack used by interrupts and syscalls while this process is running
+const KERNEL_STACK_SIZE: usize = 0x4000;
 
 pub struct Process {
     page_table: Box<PageTable>,
     process_state: Option<ProcessState>,
     page_table_addr: PhysAddr,
     entry_offset: u64,
-    regions: Vec<PhysFrame>,
+    files: FileTable,
+    signals: SignalState,
+    kernel_stack: Box<[u8]>,
+    vmas: VmaList,
+    /// start of the heap, just past the executable image
+    brk_start: u64,
+    /// current program break
+    brk: u64,
+    /// initial user stack pointer
+    stack_top: u64,
+    /// mmap places mappings below this address
+    mmap_top: u64,
+    /// relocations of a position independent executable, applied as its pages are loaded
+    relocations: Vec<Relocation>,
+    /// user thread pointer, loaded into the fs base when the process is activated
+    ///
+    /// user code cannot write the msr itself, so this is always up to date and needs no saving
+    fs_base: u64,
+    /// set while another process debugs this one
+    tracee: Option<Tracee>,
 }
 
 struct ProcessState {
@@ -41,22 +90,87 @@ struct ProcessState {
 
 #[derive(Debug)]
 pub enum ProcessSpawnError {
-    MapFail
+    MapFail,
+    ReadFail,
+    Elf(ElfLoadError),
+    InterpreterNotFound,
+    /// the "#!" line of a script is malformed
+    BadScript,
+    /// the arguments do not fit on the initial stack
+    ArgumentsTooLong,
 }
 
 impl Display for ProcessSpawnError {
     fn fmt(&self, f: &mut Formatter<'_>) -> core::fmt::Result {
-        write!(f, "failed to spawn process: {}", match self {
-            ProcessSpawnError::MapFail => "map process error",
-        })
+        write!(f, "failed to spawn process: ")?;
+        match self {
+            ProcessSpawnError::MapFail => write!(f, "map process error"),
+            ProcessSpawnError::ReadFail => write!(f, "failed to read executable"),
+            ProcessSpawnError::Elf(err) => write!(f, "invalid executable: {}", err),
+            ProcessSpawnError::InterpreterNotFound => write!(f, "interpreter not found"),
+            ProcessSpawnError::BadScript => write!(f, "bad interpreter line in script"),
+            ProcessSpawnError::ArgumentsTooLong => write!(f, "argument list too long"),
+        }
+    }
+}
+
+impl From<ElfLoadError> for ProcessSpawnError {
+    fn from(err: ElfLoadError) -> Self {
+        ProcessSpawnError::Elf(err)
     }
 }
 
 impl Error for ProcessSpawnError {}
 
+/// finds the regular file at an absolute path
+fn find_file(path: &str) -> Option<InodeRef> {
+    vfs::lookup(path, "/")
+        .ok()
+        .filter(|inode| {
+            inode.metadata().map_or(false, |metadata| metadata.file_type == FileType::Regular)
+        })
+}
+
+/// finds the interpreter at path, falling back to the file of the same name in LIBRARY_PATH
+fn find_interpreter(path: &str) -> Result<InodeRef, ProcessSpawnError> {
+    let name = path.rsplit('/').next().unwrap_or(path);
+    find_file(path)
+        .or_else(|| find_file(&format!("{}/{}", LIBRARY_PATH, name)))
+        .ok_or(ProcessSpawnError::InterpreterNotFound)
+}
+
 impl Process {
+    /// starts the executable with argv, whose first element is the path of executable
+    ///
+    /// scripts starting with "#!" are run by the interpreter they name, which gets the script
+    /// path in place of argv[0]
     pub unsafe fn spawn_from_file(
-        file: &Vec<u8>, frame_allocator: &mut impl FrameAllocator<Size4KiB>
+        executable: &InodeRef, argv: &[&str], frame_allocator: &mut impl FrameAllocator<Size4KiB>
+    ) -> Result<Self, ProcessSpawnError> {
+        let mut first_line = [0; MAX_SHEBANG_LEN];
+        let read = executable.read_at(0, &mut first_line)
+            .map_err(|_| ProcessSpawnError::ReadFail)?;
+        let shebang = match shebang::parse(&first_line[..read]) {
+            Ok(Some(shebang)) => shebang,
+            Ok(None) => return Self::spawn_elf(executable, argv, frame_allocator),
+            Err(_) => return Err(ProcessSpawnError::BadScript),
+        };
+
+        let (script_path, args) = argv.split_first()
+            .ok_or(ProcessSpawnError::BadScript)?;
+        // an interpreter that is a script itself fails to load as elf
+        let interpreter = find_file(shebang.interpreter)
+            .ok_or(ProcessSpawnError::InterpreterNotFound)?;
+        let mut interpreter_argv = Vec::with_capacity(argv.len() + 2);
+        interpreter_argv.push(shebang.interpreter);
+        interpreter_argv.extend(shebang.argument);
+        interpreter_argv.push(*script_path);
+        interpreter_argv.extend_from_slice(args);
+        Self::spawn_elf(&interpreter, &interpreter_argv, frame_allocator)
+    }
+
+    unsafe fn spawn_elf(
+        executable: &InodeRef, argv: &[&str], frame_allocator: &mut impl FrameAllocator<Size4KiB>
     ) -> Result<Self, ProcessSpawnError> {
         let mapper = crate::MAPPER.get()
             .expect("mapper not initialized");
@@ -64,47 +178,109 @@ impl Process {
         let current_page_table = memory::active_level_4_table(
             mapper.phys_offset().as_u64()
         );
-        let mut page_table = Self::new_page_table(current_page_table, frame_allocator, mapper)
+        let page_table = Self::new_page_table(current_page_table, frame_allocator, mapper)
             .expect("failed to create page table");
         let page_table_virt_addr = VirtAddr::new(addr_of!(*page_table) as u64);
         let page_table_addr = mapper.translate_addr(page_table_virt_addr)
             .unwrap();
-        let mut new_mapper = OffsetPageTable::new(
-            &mut page_table, mapper.phys_offset()
-        );
-
-        Self::map_stack(&mut new_mapper, frame_allocator);
-
-        // ensure no interrupts during mapping
-        interrupts::disable();
 
-        // temp swap contexts
-        let (current_table_frame, cr3_flags) = Cr3::read();
-        Cr3::write(
-            PhysFrame::containing_address(page_table_addr),
-            cr3_flags
-        );
-        tlb::flush_all();
-
-        // map elf file regions
-        let (regions, entry_point) = crate::elf::map_elf_file_to_process(
-            file, &mut new_mapper, frame_allocator
-        );
-
-        // swap back to previous context
-        Cr3::write(current_table_frame, cr3_flags);
-        tlb::flush_all();
+        let layout = Layout::random();
+        let image = elf::load_image(executable, ImageRole::Program, |_| Some(random_load_base()))?;
+        let mut vmas = VmaList::new();
+        for vma in &image.vmas {
+            vmas.insert(vma.clone())
+                .map_err(|_| ProcessSpawnError::MapFail)?;
+        }
+        let image_end = vmas.iter()
+            .map(|vma| vma.end)
+            .max()
+            .unwrap_or(USER_MEMORY_START);
+
+        // a dynamically linked program starts in its interpreter, which is placed like an mmap
+        let (entry_point, interpreter_base) = match &image.interpreter {
+            Some(path) => {
+                let interpreter_file = find_interpreter(path)?;
+                let interpreter = elf::load_image(&interpreter_file, ImageRole::Interpreter, |len| {
+                    vmas.find_free(len, MMAP_BASE, layout.mmap_top)
+                })?;
+                for vma in interpreter.vmas {
+                    vmas.insert(vma)
+                        .map_err(|_| ProcessSpawnError::MapFail)?;
+                }
+                (interpreter.entry, interpreter.load_base)
+            },
+            None => (image.entry, 0),
+        };
 
-        // enable interrupts
-        interrupts::enable();
+        let stack_end = (layout.stack_top & !0xfff) + 0x1000;
+        let stack = Vma::new(stack_end - INITIAL_STACK_SIZE, stack_end, PROT_READ | PROT_WRITE, VmaKind::Stack);
+        vmas.insert(stack)
+            .map_err(|_| ProcessSpawnError::MapFail)?;
 
-        Ok(Self {
+        let mut process = Self {
             page_table,
             process_state: None,
             page_table_addr,
             entry_offset: entry_point,
-            regions,
-        })
+            files: FileTable::with_console(),
+            signals: SignalState::new(),
+            kernel_stack: alloc::vec![0; KERNEL_STACK_SIZE].into_boxed_slice(),
+            vmas,
+            brk_start: image_end,
+            brk: image_end,
+            stack_top: layout.stack_top,
+            mmap_top: layout.mmap_top,
+            relocations: image.relocations,
+            fs_base: 0,
+            tracee: None,
+        };
+        // dynamic loaders set up thread local storage themselves
+        if let (Some(template), None) = (&image.tls, &image.interpreter) {
+            process.fs_base = process.setup_tls(executable, template, frame_allocator)?;
+        }
+
+        let auxv = [
+            (AT_PHDR, image.program_headers_addr.unwrap_or(0)),
+            (AT_PHENT, PROGRAM_HEADER_SIZE as u64),
+            (AT_PHNUM, image.program_header_count as u64),
+            (AT_PAGESZ, 0x1000),
+            (AT_BASE, interpreter_base),
+            (AT_ENTRY, image.entry),
+        ];
+        let mut random = [0; 16];
+        random[..8].copy_from_slice(&random_u64().to_le_bytes());
+        random[8..].copy_from_slice(&random_u64().to_le_bytes());
+
+        let mut initial_stack = alloc::vec![0; INITIAL_STACK_PAGES * 0x1000];
+        process.stack_top = StackBuilder::new(&mut initial_stack, stack_end)
+            .build(layout.stack_top, argv, &[LIBRARY_PATH_ENV], &auxv, random)
+            .map_err(|_| ProcessSpawnError::ArgumentsTooLong)?;
+        process.map_initialized(stack_end - initial_stack.len() as u64, &initial_stack, frame_allocator)
+            .map_err(|_| ProcessSpawnError::MapFail)?;
+        Ok(process)
+    }
+
+    pub fn files_mut(&mut self) -> &mut FileTable {
+        &mut self.files
+    }
+
+    pub fn signals_mut(&mut self) -> &mut SignalState {
+        &mut self.signals
+    }
+
+    pub fn fs_base(&self) -> u64 {
+        self.fs_base
+    }
+
+    /// changes the thread pointer, the caller loads it if this is the running process
+    pub fn set_fs_base(&mut self, fs_base: u64) {
+        self.fs_base = fs_base;
+    }
+
+    /// 16 byte aligned top of the kernel stack of the process
+    fn kernel_stack_top(&self) -> VirtAddr {
+        let end = self.kernel_stack.as_ptr() as u64 + self.kernel_stack.len() as u64;
+        VirtAddr::new(end & !0xf)
     }
 
     /// Creates a page table for a new process, copying over kernel and IO pages
@@ -155,28 +331,6 @@ impl Process {
         Some(page_table)
     }
 
-    unsafe fn map_stack(
-        mapper: &mut OffsetPageTable,
-        frame_allocator: &mut impl FrameAllocator<Size4KiB>
-    ) {
-        let start_page = Page::containing_address(VirtAddr::new(USERSPACE_STACK_BASE));
-        let end_page = Page::containing_address(VirtAddr::new(USERSPACE_STACK));
-        let stack_range = Page::range_inclusive(start_page, end_page);
-        for page in stack_range {
-            mapper
-                .map_to(
-                    page,
-                    frame_allocator.allocate_frame().unwrap(), // TODO this leaks
-                    PageTableFlags::PRESENT
-                        | PageTableFlags::WRITABLE
-                        | PageTableFlags::USER_ACCESSIBLE,
-                    frame_allocator
-                )
-                .unwrap()
-                .flush();
-        }
-    }
-
     /// Creates process virtual memory and maps to it
     /*unsafe fn map_process(
         old_mapper: &OffsetPageTable,
@@ -237,6 +391,10 @@ impl Process {
     }*/
 
     pub unsafe extern "C" fn activate(&mut self) -> bool {
+        // traps from ring 3 land on the kernel stack of the running process
+        let kernel_stack = self.kernel_stack_top();
+        gdt::set_kernel_stack(kernel_stack);
+        crate::syscall::set_kernel_stack(kernel_stack);
 
         let (_, cr3_flags) = Cr3::read();
         Cr3::write(
@@ -244,6 +402,7 @@ impl Process {
             cr3_flags
         );
         tlb::flush_all();
+        FsBase::write(VirtAddr::new(self.fs_base));
         x86_64::instructions::interrupts::enable();
 
         if let Some(state) = self.process_state.as_ref() {
@@ -340,9 +499,10 @@ impl Process {
         push 0x200 // 0x200
         push rdx
         push rdi
+        xor edx, edx // no function for the program to register with atexit
         iretq",
         in("rdi") entry_point,
-        in("rsi") USERSPACE_STACK,
+        in("rsi") self.stack_top,
         in("dx") cs_index,
         in("ax") ds_index,
         );
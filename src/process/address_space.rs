@@ -0,0 +1,457 @@
+use alloc::vec::Vec;
+use core::ptr::slice_from_raw_parts_mut;
+use x86_64::structures::paging::{FrameAllocator, FrameDeallocator, Mapper, OffsetPageTable, Page, PageTableFlags, PhysFrame, Size4KiB, Translate};
+use x86_64::VirtAddr;
+use crate::memory::{self, frame_to_virt, with_frame_allocator, zero_frame};
+use crate::process::Process;
+use crate::random::random_below;
+use crate::process::vma::{page_align_up, Vma, VmaBacking, VmaKind, VmaList, PROT_EXEC, PROT_READ, PROT_WRITE};
+use crate::threading::scheduler::with_current_process;
+use crate::vfs::VfsError;
+
+/// user mappings live between these addresses, the page tables covering them are private to
+/// each process while the rest of the lower half shares kernel page tables (see new_page_table)
+pub const USER_MEMORY_START: u64 = 0x200000;
+pub const USER_MEMORY_END: u64 = 0x4000_0000;
+/// mmap places mappings below USER_MEMORY_END and above this address
+pub const MMAP_BASE: u64 = 0x1000_0000;
+/// how far below its top a stack may grow
+const MAX_STACK_SIZE: u64 = 0x80_0000;
+/// position independent executables are loaded at a random page this far above PIE_BASE
+const PIE_BASE: u64 = 0x40_0000;
+const PIE_RANDOM_RANGE: u64 = 0x400_0000;
+/// the stack top is a random address this close to USER_MEMORY_END
+const STACK_RANDOM_RANGE: u64 = 0x100_0000;
+/// mmap starts searching at a random page this far below the room kept for the stack
+const MMAP_RANDOM_RANGE: u64 = 0x400_0000;
+
+const MAP_SHARED: u64 = 0x01;
+const MAP_PRIVATE: u64 = 0x02;
+const MAP_FIXED: u64 = 0x10;
+const MAP_ANONYMOUS: u64 = 0x20;
+
+/// randomized addresses of a new address space
+#[derive(Debug, Copy, Clone)]
+pub struct Layout {
+    /// 16 byte aligned initial stack pointer
+    pub stack_top: u64,
+    /// mmap places mappings between MMAP_BASE and this address
+    pub mmap_top: u64,
+}
+
+#[derive(Debug, Copy, Clone)]
+pub enum MemoryError {
+    InvalidArgument,
+    OutOfMemory,
+    /// the range is already in use
+    Overlap,
+}
+
+impl Process {
+    /// returns a mapper for the page table of this process
+    pub(super) fn mapper(&mut self) -> OffsetPageTable<'_> {
+        let phys_offset = crate::MAPPER.get()
+            .expect("mapper not initialized")
+            .phys_offset();
+        unsafe { OffsetPageTable::new(&mut self.page_table, phys_offset) }
+    }
+
+    pub fn vmas(&self) -> &VmaList {
+        &self.vmas
+    }
+
+    /// bytes of user memory that have been touched and so have a frame behind them
+    pub fn resident_size(&mut self) -> u64 {
+        let areas: Vec<(u64, u64)> = self.vmas.iter()
+            .map(|vma| (vma.start, vma.end))
+            .collect();
+        let mapper = self.mapper();
+        let pages = areas.into_iter()
+            .flat_map(|(start, end)| page_range(start, end))
+            .filter(|page| mapper.translate_addr(page.start_address()).is_some())
+            .count();
+        pages as u64 * 0x1000
+    }
+
+    /// unmaps [start, end) and frees the frames behind it, skipping pages that are not mapped
+    fn unmap_pages(&mut self, start: u64, end: u64) {
+        let mut mapper = self.mapper();
+        for page in page_range(start, end) {
+            if let Ok((frame, flush)) = mapper.unmap(page) {
+                flush.flush();
+                with_frame_allocator(|frame_allocator| unsafe {
+                    frame_allocator.deallocate_frame(frame)
+                });
+            }
+        }
+    }
+
+    /// applies flags to the mapped pages in [start, end)
+    fn update_page_flags(&mut self, start: u64, end: u64, flags: PageTableFlags) {
+        let mut mapper = self.mapper();
+        for page in page_range(start, end) {
+            if let Ok(flush) = unsafe { mapper.update_flags(page, flags) } {
+                flush.flush();
+            }
+        }
+    }
+
+    /// copies contents into new pages at addr, which must be page aligned and lie in an area
+    ///
+    /// used before the process first runs, while its page table is not the active one
+    pub(super) fn map_initialized(
+        &mut self,
+        addr: u64,
+        contents: &[u8],
+        frame_allocator: &mut impl FrameAllocator<Size4KiB>,
+    ) -> Result<(), MemoryError> {
+        let flags = self.vmas.find(addr)
+            .ok_or(MemoryError::InvalidArgument)?
+            .page_flags();
+        let mut mapper = self.mapper();
+        for (page, chunk) in page_range(addr, addr + contents.len() as u64).zip(contents.chunks(0x1000)) {
+            let frame = frame_allocator.allocate_frame()
+                .ok_or(MemoryError::OutOfMemory)?;
+            unsafe {
+                zero_frame(frame);
+                frame_to_virt(frame).as_mut_ptr::<u8>()
+                    .copy_from_nonoverlapping(chunk.as_ptr(), chunk.len());
+                let table_flags = PageTableFlags::PRESENT
+                    | PageTableFlags::WRITABLE
+                    | PageTableFlags::USER_ACCESSIBLE;
+                // the table is not active so there is nothing to flush
+                mapper.map_to_with_table_flags(page, frame, flags, table_flags, frame_allocator)
+                    .map_err(|_| MemoryError::OutOfMemory)?
+                    .ignore();
+            }
+        }
+        Ok(())
+    }
+
+    /// moves the program break to addr, or only reports it if addr is 0
+    ///
+    /// returns the new break, which is unchanged if the request could not be satisfied
+    pub fn brk(&mut self, addr: u64) -> u64 {
+        if addr < self.brk_start || addr >= MMAP_BASE {
+            return self.brk;
+        }
+        let (old_end, new_end) = match (page_align_up(self.brk), page_align_up(addr)) {
+            (Some(old_end), Some(new_end)) => (old_end, new_end),
+            _ => return self.brk,
+        };
+
+        // pages are allocated when first touched
+        if new_end > old_end {
+            if !self.vmas.is_free(old_end, new_end) {
+                return self.brk;
+            }
+        } else if new_end < old_end {
+            self.unmap_pages(new_end, old_end);
+        }
+
+        self.vmas.remove_range(self.brk_start, old_end.max(self.brk_start));
+        if new_end > self.brk_start {
+            let heap = Vma::new(self.brk_start, new_end, PROT_READ | PROT_WRITE, VmaKind::Heap);
+            self.vmas.insert(heap)
+                .expect("heap overlaps another mapping");
+        }
+        self.brk = addr;
+        self.brk
+    }
+
+    /// reserves len bytes of zeroed memory and returns its address
+    ///
+    /// addr is only used with MAP_FIXED, in which case existing mappings there are replaced
+    pub fn mmap(&mut self, addr: u64, len: u64, protection: u64, flags: u64) -> Result<u64, MemoryError> {
+        let len = page_align_up(len)
+            .filter(|len| *len > 0)
+            .ok_or(MemoryError::InvalidArgument)?;
+        if flags & MAP_ANONYMOUS == 0 || flags & (MAP_SHARED | MAP_PRIVATE) == 0 {
+            // there are no file or shared mappings yet
+            return Err(MemoryError::InvalidArgument);
+        }
+        if !is_valid_protection(protection) {
+            return Err(MemoryError::InvalidArgument);
+        }
+
+        let start = if flags & MAP_FIXED != 0 {
+            let end = addr.checked_add(len)
+                .ok_or(MemoryError::InvalidArgument)?;
+            if addr % 0x1000 != 0 || addr < USER_MEMORY_START || end > USER_MEMORY_END {
+                return Err(MemoryError::InvalidArgument);
+            }
+            self.munmap(addr, len)?;
+            addr
+        } else {
+            self.vmas.find_free(len, MMAP_BASE, self.mmap_top)
+                .ok_or(MemoryError::OutOfMemory)?
+        };
+
+        let vma = Vma::new(start, start + len, protection, VmaKind::Anonymous);
+        self.vmas.insert(vma)
+            .map_err(|_| MemoryError::Overlap)?;
+        Ok(start)
+    }
+
+    /// unmaps [addr, addr + len), parts of the range that are not mapped are ignored
+    pub fn munmap(&mut self, addr: u64, len: u64) -> Result<(), MemoryError> {
+        let end = self.check_range(addr, len)?;
+        for vma in self.vmas.remove_range(addr, end) {
+            self.unmap_pages(vma.start, vma.end);
+            if vma.kind == VmaKind::Heap {
+                // keep brk from growing over the hole
+                self.brk = self.brk.min(vma.start);
+            }
+        }
+        Ok(())
+    }
+
+    /// changes the protection of [addr, addr + len), which must be entirely mapped
+    pub fn mprotect(&mut self, addr: u64, len: u64, protection: u64) -> Result<(), MemoryError> {
+        let end = self.check_range(addr, len)?;
+        if !is_valid_protection(protection) {
+            return Err(MemoryError::InvalidArgument);
+        }
+        if !self.vmas.is_covered(addr, end) {
+            return Err(MemoryError::OutOfMemory);
+        }
+        for vma in self.vmas.protect_range(addr, end, protection) {
+            self.update_page_flags(vma.start, vma.end, vma.page_flags());
+        }
+        Ok(())
+    }
+
+    /// returns the area containing addr, growing a stack down to it if addr lies just below one
+    fn fault_vma(&mut self, addr: u64) -> Option<Vma> {
+        if let Some(vma) = self.vmas.find(addr) {
+            return Some(vma.clone());
+        }
+
+        let page = addr & !0xfff;
+        let stack = self.vmas.iter()
+            .find(|vma| vma.kind == VmaKind::Stack && vma.start > addr)
+            .cloned()?;
+        if stack.end - page > MAX_STACK_SIZE || !self.vmas.is_free(page, stack.start) {
+            return None;
+        }
+        // the area is keyed by its start so it is replaced rather than resized
+        self.vmas.remove_range(stack.start, stack.end);
+        let mut grown = stack;
+        grown.start = page;
+        self.vmas.insert(grown.clone()).ok()?;
+        Some(grown)
+    }
+
+    /// applies the relocations of the executable that fall in the page at page_addr to frame
+    unsafe fn relocate_page(&self, frame: PhysFrame, page_addr: u64) {
+        let first = self.relocations.partition_point(|relocation| relocation.addr < page_addr);
+        let page = frame_to_virt(frame).as_mut_ptr::<u8>();
+        for relocation in self.relocations[first..].iter()
+            .take_while(|relocation| relocation.addr < page_addr + 0x1000)
+        {
+            // relocations are 8 byte aligned so they never cross into the next page
+            *(page.add((relocation.addr - page_addr) as usize) as *mut u64) = relocation.value;
+        }
+    }
+
+    /// returns the frame behind the page at addr if it was touched before
+    pub(super) fn mapped_frame(&mut self, addr: u64) -> Option<PhysFrame> {
+        let page = Page::<Size4KiB>::containing_address(VirtAddr::new(addr));
+        self.mapper().translate_addr(page.start_address())
+            .map(PhysFrame::containing_address)
+    }
+
+    /// maps a frame filled by load_page at addr while the process is not running
+    ///
+    /// returns the frame now behind addr, which is the existing one if the page was mapped in
+    /// the meantime. frame is freed unless it was used
+    pub(super) fn map_loaded(&mut self, addr: u64, frame: PhysFrame) -> Option<PhysFrame> {
+        let page = Page::<Size4KiB>::containing_address(VirtAddr::new(addr));
+        let existing = self.mapped_frame(addr);
+        let vma = self.vmas.find(addr).cloned();
+        let vma = match (existing, vma) {
+            (None, Some(vma)) => vma,
+            _ => {
+                with_frame_allocator(|frame_allocator| unsafe { frame_allocator.deallocate_frame(frame) });
+                return existing;
+            },
+        };
+        if vma.kind == VmaKind::Image {
+            unsafe { self.relocate_page(frame, page.start_address().as_u64()) };
+        }
+        let mut mapper = self.mapper();
+        with_frame_allocator(|frame_allocator| unsafe {
+            let table_flags = PageTableFlags::PRESENT
+                | PageTableFlags::WRITABLE
+                | PageTableFlags::USER_ACCESSIBLE;
+            match mapper.map_to_with_table_flags(page, frame, vma.page_flags(), table_flags, frame_allocator) {
+                // the table is not active so there is nothing to flush
+                Ok(flush) => {
+                    flush.ignore();
+                    Some(frame)
+                },
+                Err(_) => {
+                    frame_allocator.deallocate_frame(frame);
+                    None
+                },
+            }
+        })
+    }
+
+    /// validates a page aligned user range and returns its page aligned end
+    fn check_range(&self, addr: u64, len: u64) -> Result<u64, MemoryError> {
+        let end = addr.checked_add(len)
+            .and_then(page_align_up)
+            .ok_or(MemoryError::InvalidArgument)?;
+        if addr % 0x1000 != 0 || len == 0 || addr < USER_MEMORY_START || end > USER_MEMORY_END {
+            return Err(MemoryError::InvalidArgument);
+        }
+        Ok(end)
+    }
+}
+
+impl Layout {
+    /// picks the stack and mmap addresses of a new process
+    pub fn random() -> Self {
+        let stack_page = USER_MEMORY_END - 0x1000 - random_page_offset(STACK_RANDOM_RANGE);
+        // the offset within the page is randomized too, the area above it stays mapped
+        let stack_top = stack_page + random_below(0x1000 / 16) * 16;
+        let mmap_top = stack_page - MAX_STACK_SIZE - random_page_offset(MMAP_RANDOM_RANGE);
+        Self {
+            stack_top,
+            mmap_top,
+        }
+    }
+}
+
+/// picks the base address of a position independent executable
+pub fn random_load_base() -> u64 {
+    PIE_BASE + random_page_offset(PIE_RANDOM_RANGE)
+}
+
+/// a random page aligned offset below range
+fn random_page_offset(range: u64) -> u64 {
+    random_below(range / 0x1000) * 0x1000
+}
+
+/// resolves a fault on a user page of the current process that is not present
+///
+/// allocates the page and fills it from the backing of its area. returns false if the access
+/// is not allowed, in which case the fault is a genuine error
+pub fn handle_page_fault(addr: u64, is_write: bool) -> bool {
+    if addr < USER_MEMORY_START || addr >= USER_MEMORY_END {
+        return false;
+    }
+    let vma = match with_current_process(|process| process.fault_vma(addr)).flatten() {
+        Some(vma) => vma,
+        None => return false,
+    };
+    if !vma.allows(is_write) {
+        return false;
+    }
+
+    let page = Page::<Size4KiB>::containing_address(VirtAddr::new(addr));
+    let frame = match load_page(page.start_address().as_u64(), &vma) {
+        Some(frame) => frame,
+        None => return false,
+    };
+    if vma.kind == VmaKind::Image {
+        // only touches the new frame, never user memory
+        with_current_process(|process| unsafe {
+            process.relocate_page(frame, page.start_address().as_u64())
+        });
+    }
+
+    // the faulting process is the active one, so its page table is the active table
+    let mut mapper = unsafe { memory::init() };
+    with_frame_allocator(|frame_allocator| unsafe {
+        // intermediate tables stay permissive, the leaf entry alone decides what is allowed
+        let table_flags = PageTableFlags::PRESENT
+            | PageTableFlags::WRITABLE
+            | PageTableFlags::USER_ACCESSIBLE;
+        match mapper.map_to_with_table_flags(page, frame, vma.page_flags(), table_flags, frame_allocator) {
+            Ok(flush) => {
+                flush.flush();
+                true
+            },
+            Err(_) => {
+                frame_allocator.deallocate_frame(frame);
+                false
+            },
+        }
+    })
+}
+
+/// faults in every page of [addr, addr + len) that the current process may access
+///
+/// the kernel calls this before touching user memory for a syscall. a page fault taken while a
+/// lock is held could need that same lock to load the page, and a bad pointer found that late
+/// could not be reported without leaving the lock held. false if any page is inaccessible
+pub fn populate_user_range(addr: u64, len: u64, is_write: bool) -> bool {
+    if len == 0 {
+        return true;
+    }
+    let end = match addr.checked_add(len) {
+        Some(end) if addr >= USER_MEMORY_START && end <= USER_MEMORY_END => end,
+        _ => return false,
+    };
+    page_range(addr & !0xfff, end).all(|page| {
+        let page_addr = page.start_address().as_u64();
+        // inaccessible pages may still be mapped for the kernel, so the area decides
+        let is_allowed = with_current_process(|process| {
+            process.fault_vma(page_addr).map_or(false, |vma| vma.allows(is_write))
+        }).unwrap_or(false);
+        let is_mapped = || unsafe { memory::init() }.translate_addr(page.start_address()).is_some();
+        is_allowed && (is_mapped() || handle_page_fault(page_addr, is_write))
+    })
+}
+
+/// allocates a frame holding the initial contents of the page at page_addr in vma
+///
+/// may read the disk, so it must not run while the scheduler is locked
+pub(super) fn load_page(page_addr: u64, vma: &Vma) -> Option<PhysFrame> {
+    let frame = with_frame_allocator(|frame_allocator| frame_allocator.allocate_frame())?;
+    unsafe {
+        zero_frame(frame);
+        if fill_page(frame, page_addr, vma).is_err() {
+            with_frame_allocator(|frame_allocator| frame_allocator.deallocate_frame(frame));
+            return None;
+        }
+    }
+    Some(frame)
+}
+
+/// copies the file contents of a file backed area that fall within the page at page_addr
+unsafe fn fill_page(frame: PhysFrame, page_addr: u64, vma: &Vma) -> Result<(), VfsError> {
+    let (file, offset, virt_addr, file_size) = match &vma.backing {
+        VmaBacking::Anonymous => return Ok(()),
+        VmaBacking::File { file, offset, virt_addr, file_size } => {
+            (file, *offset, *virt_addr, *file_size)
+        },
+    };
+    let start = page_addr.max(virt_addr);
+    let end = (page_addr + 0x1000).min(virt_addr + file_size);
+    if start >= end {
+        // the page only holds bss
+        return Ok(());
+    }
+
+    let page = &mut *slice_from_raw_parts_mut(frame_to_virt(frame).as_mut_ptr::<u8>(), 0x1000);
+    file.read_at(
+        offset + (start - virt_addr),
+        &mut page[(start - page_addr) as usize..(end - page_addr) as usize],
+    )?;
+    Ok(())
+}
+
+/// rejects unknown bits and memory that is both writable and executable
+fn is_valid_protection(protection: u64) -> bool {
+    protection & !(PROT_READ | PROT_WRITE | PROT_EXEC) == 0
+        && protection & (PROT_WRITE | PROT_EXEC) != PROT_WRITE | PROT_EXEC
+}
+
+fn page_range(start: u64, end: u64) -> impl Iterator<Item = Page<Size4KiB>> {
+    (start..end)
+        .step_by(0x1000)
+        .map(|addr| Page::containing_address(VirtAddr::new(addr)))
+}
@@ -0,0 +1,150 @@
+use alloc::format;
+use alloc::vec::Vec;
+use core::ptr::slice_from_raw_parts;
+use x86_64::structures::paging::{PhysFrame, Translate};
+use x86_64::instructions::interrupts;
+use x86_64::VirtAddr;
+use crate::elf::core_file::{self, CoreSegment, PrStatus};
+use crate::elf::parser::{PF_R, PF_W, PF_X};
+use crate::interrupts::trap::TrapFrame;
+use crate::memory::frame_to_virt;
+use crate::process::{ptrace, Process};
+use crate::process::signal::{SIGABRT, SIGBUS, SIGFPE, SIGILL, SIGQUIT, SIGSEGV, SIGTRAP};
+use crate::process::vma::{PROT_EXEC, PROT_READ, PROT_WRITE};
+use crate::serial_println;
+use crate::threading::scheduler::{current_pid, with_current_process};
+use crate::vfs::{self, File, VfsError};
+
+/// processes with more memory than this are not dumped
+const MAX_CORE_SIZE: u64 = 0x100_0000;
+const CORE_DIRECTORY: &str = "/CORE";
+
+/// true for signals whose default action also dumps core
+pub fn dumps_core(signal: u8) -> bool {
+    matches!(signal, SIGQUIT | SIGILL | SIGTRAP | SIGABRT | SIGBUS | SIGFPE | SIGSEGV)
+}
+
+/// writes a core of the current process, which is being killed by signal in frame
+///
+/// must be called with interrupts disabled and no lock held, as on the way back to user mode.
+/// they are enabled while the core is written, the file system waits for locks that other
+/// tasks hold with interrupts enabled, and disabled again before this returns
+pub fn dump_current(signal: u8, frame: &TrapFrame) {
+    let pid = match current_pid() {
+        Some(pid) => pid,
+        None => return,
+    };
+    let core = with_current_process(|process| process.core(pid.as_u64() as u32, signal, frame));
+    let (headers, frames) = match core.flatten() {
+        Some(core) => core,
+        None => {
+            serial_println!("process {:?} is too large to dump core", pid);
+            return;
+        },
+    };
+
+    let path = format!("{}/{}", CORE_DIRECTORY, pid.as_u64());
+    // the process never runs again, so its memory stays as it was while it is written
+    interrupts::enable();
+    let result = write_core(&path, &headers, &frames);
+    interrupts::disable();
+    match result {
+        Ok(size) => {
+            serial_println!("process {:?} dumped core to {} ({} bytes)", pid, path, size);
+        },
+        Err(err) => {
+            serial_println!("process {:?} could not dump core to {}: {:?}", pid, path, err);
+        },
+    }
+}
+
+/// writes the headers and then the pages of frames to a new file at path, returning its size
+///
+/// the core is written a page at a time, so it never has to fit in the kernel heap. the frames
+/// stay mapped since the process they belong to is stopped in the kernel until it is ended
+fn write_core(path: &str, headers: &[u8], frames: &[PhysFrame]) -> Result<u64, VfsError> {
+    match vfs::create_directory(CORE_DIRECTORY, "/") {
+        Ok(_) | Err(VfsError::AlreadyExists) => {},
+        Err(err) => return Err(err),
+    }
+    let file = vfs::open(&vfs::create(path, "/")?)?;
+    write_all(&*file, 0, headers)?;
+    let mut offset = headers.len() as u64;
+    for frame in frames {
+        let page = frame_to_virt(*frame);
+        let page = unsafe { &*slice_from_raw_parts(page.as_ptr::<u8>(), 0x1000) };
+        write_all(&*file, offset, page)?;
+        offset += 0x1000;
+    }
+    Ok(offset)
+}
+
+fn write_all(file: &dyn File, mut offset: u64, mut data: &[u8]) -> Result<(), VfsError> {
+    while !data.is_empty() {
+        let written = file.write(offset, data)?;
+        if written == 0 {
+            return Err(VfsError::NoSpace);
+        }
+        offset += written as u64;
+        data = &data[written..];
+    }
+    Ok(())
+}
+
+impl Process {
+    /// describes a core file with the registers in frame and every mapped user page
+    ///
+    /// returns the start of the file and the frames whose contents follow it. pages that were
+    /// never touched are left out
+    fn core(
+        &mut self, pid: u32, signal: u8, frame: &TrapFrame
+    ) -> Option<(Vec<u8>, Vec<PhysFrame>)> {
+        let vmas: Vec<_> = self.vmas.iter().cloned().collect();
+        let mapper = self.mapper();
+        let mut segments: Vec<CoreSegment> = Vec::new();
+        let mut frames = Vec::new();
+        for vma in vmas {
+            let flags = segment_flags(vma.protection);
+            let mut run: Option<CoreSegment> = None;
+            for addr in (vma.start..vma.end).step_by(0x1000) {
+                let phys_addr = match mapper.translate_addr(VirtAddr::new(addr)) {
+                    Some(phys_addr) => phys_addr,
+                    None => {
+                        segments.extend(run.take());
+                        continue;
+                    },
+                };
+                if (frames.len() as u64 + 1) * 0x1000 > MAX_CORE_SIZE {
+                    return None;
+                }
+                frames.push(PhysFrame::containing_address(phys_addr));
+                run.get_or_insert(CoreSegment { start: addr, flags, len: 0 }).len += 0x1000;
+            }
+            segments.extend(run);
+        }
+
+        let status = PrStatus {
+            signal,
+            pid,
+            pending: self.signals.pending(),
+            blocked: self.signals.blocked(),
+            registers: ptrace::user_regs(frame, self.fs_base),
+        };
+        Some((core_file::core_headers(&segments, &status), frames))
+    }
+}
+
+/// converts PROT_* bits to program header flags
+fn segment_flags(protection: u64) -> u32 {
+    let mut flags = 0;
+    if protection & PROT_READ != 0 {
+        flags |= PF_R;
+    }
+    if protection & PROT_WRITE != 0 {
+        flags |= PF_W;
+    }
+    if protection & PROT_EXEC != 0 {
+        flags |= PF_X;
+    }
+    flags
+}
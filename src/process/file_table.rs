@@ -0,0 +1,118 @@
+use alloc::boxed::Box;
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+use spin::Mutex;
+use crate::console;
+use crate::process::pipe::{PipeReader, PipeWriter};
+use crate::threading::scheduler::PID;
+use crate::vfs::{self, InodeRef};
+
+const MAX_FILES: usize = 64;
+
+pub const POLLIN: u16 = 0x1;
+pub const POLLOUT: u16 = 0x4;
+pub const POLLERR: u16 = 0x8;
+pub const POLLHUP: u16 = 0x10;
+pub const POLLNVAL: u16 = 0x20;
+
+/// per-process table mapping file descriptor numbers to open files
+pub struct FileTable {
+    descriptors: Vec<Option<FileDescriptor>>,
+}
+
+/// an entry in a file table
+///
+/// cloning shares the underlying open file, as dup does
+#[derive(Clone)]
+pub enum FileDescriptor {
+    Console,
+    File(Arc<Mutex<OpenFile>>),
+    PipeRead(Arc<PipeReader>),
+    PipeWrite(Arc<PipeWriter>),
+}
+
+/// a file or directory opened through the vfs
+///
+/// for directories the offset is the index of the next entry returned by getdents
+pub struct OpenFile {
+    pub inode: InodeRef,
+    pub file: Box<dyn vfs::File>,
+    pub offset: usize,
+}
+
+impl FileDescriptor {
+    /// returns the poll events that currently apply to the descriptor
+    pub fn poll(&self) -> u16 {
+        match self {
+            FileDescriptor::Console => {
+                if console::has_input() { POLLIN | POLLOUT } else { POLLOUT }
+            },
+            FileDescriptor::File(_) => POLLIN | POLLOUT,
+            FileDescriptor::PipeRead(reader) => reader.poll(),
+            FileDescriptor::PipeWrite(writer) => writer.poll(),
+        }
+    }
+
+    /// wakes pid the next time the result of poll may change
+    pub fn register_poller(&self, pid: PID) {
+        match self {
+            FileDescriptor::Console => console::register_reader(pid),
+            FileDescriptor::File(_) => {},
+            FileDescriptor::PipeRead(reader) => reader.register(pid),
+            FileDescriptor::PipeWrite(writer) => writer.register(pid),
+        }
+    }
+}
+
+impl FileTable {
+    pub fn new() -> Self {
+        Self {
+            descriptors: Vec::new(),
+        }
+    }
+
+    /// creates a table with stdin, stdout and stderr attached to the console
+    pub fn with_console() -> Self {
+        Self {
+            descriptors: alloc::vec![Some(FileDescriptor::Console); 3],
+        }
+    }
+
+    /// stores a descriptor in the lowest free slot and returns its number
+    pub fn insert(&mut self, descriptor: FileDescriptor) -> Option<usize> {
+        if let Some(fd) = self.descriptors.iter().position(|d| d.is_none()) {
+            self.descriptors[fd] = Some(descriptor);
+            return Some(fd);
+        }
+        if self.descriptors.len() >= MAX_FILES {
+            return None;
+        }
+        self.descriptors.push(Some(descriptor));
+        Some(self.descriptors.len() - 1)
+    }
+
+    /// stores a descriptor at a specific number, closing whatever was there
+    pub fn insert_at(&mut self, fd: usize, descriptor: FileDescriptor) -> Option<usize> {
+        if fd >= MAX_FILES {
+            return None;
+        }
+        if fd >= self.descriptors.len() {
+            self.descriptors.resize(fd + 1, None);
+        }
+        self.descriptors[fd] = Some(descriptor);
+        Some(fd)
+    }
+
+    /// returns a handle sharing the open file behind fd
+    pub fn get(&self, fd: usize) -> Option<FileDescriptor> {
+        self.descriptors.get(fd)?.clone()
+    }
+
+    pub fn get_mut(&mut self, fd: usize) -> Option<&mut FileDescriptor> {
+        self.descriptors.get_mut(fd)?.as_mut()
+    }
+
+    pub fn remove(&mut self, fd: usize) -> Option<FileDescriptor> {
+        self.descriptors.get_mut(fd)?.take()
+    }
+}
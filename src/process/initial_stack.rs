@@ -0,0 +1,125 @@
+use core::mem::size_of;
+
+pub const AT_NULL: u64 = 0;
+pub const AT_PHDR: u64 = 3;
+pub const AT_PHENT: u64 = 4;
+pub const AT_PHNUM: u64 = 5;
+pub const AT_PAGESZ: u64 = 6;
+pub const AT_BASE: u64 = 7;
+pub const AT_ENTRY: u64 = 9;
+pub const AT_RANDOM: u64 = 25;
+pub const AT_EXECFN: u64 = 31;
+
+/// bytes of random data pointed to by AT_RANDOM
+const RANDOM_LEN: u64 = 16;
+
+#[derive(Debug)]
+pub struct StackOverflow;
+
+/// the memory a stack is built in before it is mapped into a process
+pub struct StackBuilder<'a> {
+    memory: &'a mut [u8],
+    /// address of the first byte of memory in the process
+    base: u64,
+}
+
+impl<'a> StackBuilder<'a> {
+    /// memory will appear at stack_end - memory.len() in the process
+    pub fn new(memory: &'a mut [u8], stack_end: u64) -> Self {
+        let base = stack_end - memory.len() as u64;
+        Self {
+            memory,
+            base,
+        }
+    }
+
+    /// lays out what a program expects at its initial stack pointer below top
+    ///
+    /// from the stack pointer up: argc, argv, 0, envp, 0, the auxiliary vector ending in
+    /// AT_NULL, then the strings and random bytes they point to. AT_RANDOM and AT_EXECFN are
+    /// added to auxv. returns the 16 byte aligned stack pointer
+    pub fn build(
+        &mut self,
+        top: u64,
+        argv: &[&str],
+        envp: &[&str],
+        auxv: &[(u64, u64)],
+        random: [u8; RANDOM_LEN as usize],
+    ) -> Result<u64, StackOverflow> {
+        let random_addr = top.checked_sub(RANDOM_LEN).ok_or(StackOverflow)?;
+        let strings_len: u64 = argv.iter().chain(envp)
+            .map(|string| string.len() as u64 + 1)
+            .sum();
+        let strings_addr = random_addr.checked_sub(strings_len).ok_or(StackOverflow)?;
+        let words = 1 + argv.len() + 1 + envp.len() + 1 + 2 * (auxv.len() + 3);
+        let stack_pointer = strings_addr.checked_sub((words * size_of::<u64>()) as u64)
+            .ok_or(StackOverflow)?
+            & !0xf;
+        if stack_pointer < self.base || top > self.base + self.memory.len() as u64 {
+            return Err(StackOverflow);
+        }
+
+        self.write(random_addr, &random);
+        let mut word_addr = stack_pointer;
+        let mut string_addr = strings_addr;
+        self.push_word(&mut word_addr, argv.len() as u64);
+        for strings in [argv, envp] {
+            for string in strings {
+                self.push_word(&mut word_addr, string_addr);
+                self.write(string_addr, string.as_bytes());
+                self.write(string_addr + string.len() as u64, &[0]);
+                string_addr += string.len() as u64 + 1;
+            }
+            self.push_word(&mut word_addr, 0);
+        }
+
+        let execfn = if argv.is_empty() { 0 } else { strings_addr };
+        for (key, value) in [(AT_RANDOM, random_addr), (AT_EXECFN, execfn)].iter().chain(auxv) {
+            self.push_word(&mut word_addr, *key);
+            self.push_word(&mut word_addr, *value);
+        }
+        self.push_word(&mut word_addr, AT_NULL);
+        self.push_word(&mut word_addr, 0);
+        Ok(stack_pointer)
+    }
+
+    fn push_word(&mut self, addr: &mut u64, word: u64) {
+        self.write(*addr, &word.to_le_bytes());
+        *addr += size_of::<u64>() as u64;
+    }
+
+    fn write(&mut self, addr: u64, bytes: &[u8]) {
+        let start = (addr - self.base) as usize;
+        self.memory[start..start + bytes.len()].copy_from_slice(bytes);
+    }
+}
+
+#[test_case]
+fn test_build_stack() {
+    let mut memory = [0u8; 0x200];
+    let mut builder = StackBuilder::new(&mut memory, 0x10000);
+    let stack_pointer = builder.build(0xfff8, &["sh"], &["A=B"], &[(AT_PAGESZ, 0x1000)], [7; 16])
+        .unwrap();
+    assert_eq!(stack_pointer % 16, 0);
+
+    let word = |addr: u64| {
+        let start = (addr - (0x10000 - 0x200)) as usize;
+        u64::from_le_bytes(memory[start..start + 8].try_into().unwrap())
+    };
+    assert_eq!(word(stack_pointer), 1);
+    let argv0 = word(stack_pointer + 8);
+    assert_eq!(word(stack_pointer + 16), 0);
+    let start = (argv0 - (0x10000 - 0x200)) as usize;
+    assert_eq!(&memory[start..start + 3], b"sh\0");
+    assert_eq!(word(stack_pointer + 32), 0);
+    assert_eq!(word(stack_pointer + 40), AT_RANDOM);
+    assert_eq!(word(stack_pointer + 72), AT_PAGESZ);
+    assert_eq!(word(stack_pointer + 88), AT_NULL);
+}
+
+#[test_case]
+fn test_stack_overflow() {
+    let mut memory = [0u8; 0x40];
+    let mut builder = StackBuilder::new(&mut memory, 0x10000);
+    assert!(builder.build(0x10000, &["sh"], &[], &[], [0; 16]).is_err());
+}
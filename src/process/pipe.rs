@@ -0,0 +1,246 @@
+use alloc::boxed::Box;
+use alloc::sync::Arc;
+use alloc::vec;
+use core::cmp::min;
+use spin::Mutex;
+use x86_64::instructions::interrupts;
+use crate::process::file_table::{POLLERR, POLLHUP, POLLIN, POLLOUT};
+use crate::threading::scheduler::{self, PID, SCHEDULER};
+use crate::threading::wait_queue::{Interrupted, WaitQueue};
+
+const PIPE_CAPACITY: usize = 0x1000;
+
+/// fixed size byte queue backing a pipe
+pub struct RingBuffer {
+    /// allocated on the heap directly, kernel stacks are too small to build it on
+    data: Box<[u8]>,
+    head: usize,
+    len: usize,
+}
+
+struct PipeState {
+    buffer: RingBuffer,
+    reader_open: bool,
+    writer_open: bool,
+    read_waiters: WaitQueue,
+    write_waiters: WaitQueue,
+}
+
+/// read end of a pipe, the pipe is closed for writers once this is dropped
+pub struct PipeReader(Arc<Mutex<PipeState>>);
+
+/// write end of a pipe, readers see end of file once this is dropped
+pub struct PipeWriter(Arc<Mutex<PipeState>>);
+
+#[derive(Debug)]
+pub enum PipeError {
+    /// the read end is closed
+    BrokenPipe,
+    /// a signal arrived before anything was written
+    Interrupted,
+}
+
+/// creates an anonymous pipe and returns its read and write ends
+pub fn new_pipe() -> (PipeReader, PipeWriter) {
+    let state = Arc::new(Mutex::new(PipeState {
+        buffer: RingBuffer::new(),
+        reader_open: true,
+        writer_open: true,
+        read_waiters: WaitQueue::new(),
+        write_waiters: WaitQueue::new(),
+    }));
+    (PipeReader(state.clone()), PipeWriter(state))
+}
+
+impl RingBuffer {
+    pub fn new() -> Self {
+        Self {
+            data: vec![0; PIPE_CAPACITY].into_boxed_slice(),
+            head: 0,
+            len: 0,
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    pub fn is_full(&self) -> bool {
+        self.len == PIPE_CAPACITY
+    }
+
+    /// copies as many bytes as fit into the buffer and returns the amount copied
+    pub fn push(&mut self, bytes: &[u8]) -> usize {
+        let count = min(bytes.len(), PIPE_CAPACITY - self.len);
+        for (i, byte) in bytes[..count].iter().enumerate() {
+            self.data[(self.head + self.len + i) % PIPE_CAPACITY] = *byte;
+        }
+        self.len += count;
+        count
+    }
+
+    /// moves as many bytes as are available into buffer and returns the amount moved
+    pub fn pop(&mut self, buffer: &mut [u8]) -> usize {
+        let count = min(buffer.len(), self.len);
+        for (i, byte) in buffer[..count].iter_mut().enumerate() {
+            *byte = self.data[(self.head + i) % PIPE_CAPACITY];
+        }
+        self.head = (self.head + count) % PIPE_CAPACITY;
+        self.len -= count;
+        count
+    }
+}
+
+impl PipeReader {
+    /// reads at least one byte, blocking until data is written
+    ///
+    /// returns 0 once the buffer is empty and the write end is closed
+    pub fn read(&self, buffer: &mut [u8]) -> Result<usize, Interrupted> {
+        if buffer.is_empty() {
+            return Ok(0);
+        }
+        loop {
+            interrupts::disable();
+            {
+                let mut pipe = self.0.lock();
+                if !pipe.buffer.is_empty() || !pipe.writer_open {
+                    let count = pipe.buffer.pop(buffer);
+                    pipe.write_waiters.wake_all();
+                    drop(pipe);
+                    interrupts::enable();
+                    return Ok(count);
+                }
+                if scheduler::signal_pending() {
+                    drop(pipe);
+                    interrupts::enable();
+                    return Err(Interrupted);
+                }
+                if let Some(pid) = SCHEDULER.lock().current_pid() {
+                    pipe.read_waiters.register(pid);
+                }
+            }
+            // interrupts are re-enabled once this task is scheduled again
+            SCHEDULER.lock().block_current();
+        }
+    }
+
+    /// returns POLLIN if a read would not block and POLLHUP once the write end is closed
+    pub fn poll(&self) -> u16 {
+        interrupts::without_interrupts(|| {
+            let pipe = self.0.lock();
+            let mut events = 0;
+            if !pipe.buffer.is_empty() {
+                events |= POLLIN;
+            }
+            if !pipe.writer_open {
+                events |= POLLIN | POLLHUP;
+            }
+            events
+        })
+    }
+
+    /// wakes pid the next time data is written or the write end closes
+    pub fn register(&self, pid: PID) {
+        interrupts::without_interrupts(|| self.0.lock().read_waiters.register(pid));
+    }
+}
+
+impl PipeWriter {
+    /// writes all of bytes, blocking while the buffer is full
+    ///
+    /// a signal stops the write early, returning the amount already written if any
+    pub fn write(&self, bytes: &[u8]) -> Result<usize, PipeError> {
+        let mut written = 0;
+        while written < bytes.len() {
+            interrupts::disable();
+            {
+                let mut pipe = self.0.lock();
+                if !pipe.reader_open {
+                    drop(pipe);
+                    interrupts::enable();
+                    return Err(PipeError::BrokenPipe);
+                }
+                if !pipe.buffer.is_full() {
+                    written += pipe.buffer.push(&bytes[written..]);
+                    pipe.read_waiters.wake_all();
+                    drop(pipe);
+                    interrupts::enable();
+                    continue;
+                }
+                if scheduler::signal_pending() {
+                    drop(pipe);
+                    interrupts::enable();
+                    return match written {
+                        0 => Err(PipeError::Interrupted),
+                        written => Ok(written),
+                    };
+                }
+                if let Some(pid) = SCHEDULER.lock().current_pid() {
+                    pipe.write_waiters.register(pid);
+                }
+            }
+            SCHEDULER.lock().block_current();
+        }
+        Ok(written)
+    }
+
+    /// returns POLLOUT if a write would not block and POLLERR once the read end is closed
+    pub fn poll(&self) -> u16 {
+        interrupts::without_interrupts(|| {
+            let pipe = self.0.lock();
+            let mut events = 0;
+            if !pipe.buffer.is_full() {
+                events |= POLLOUT;
+            }
+            if !pipe.reader_open {
+                events |= POLLERR;
+            }
+            events
+        })
+    }
+
+    /// wakes pid the next time data is read or the read end closes
+    pub fn register(&self, pid: PID) {
+        interrupts::without_interrupts(|| self.0.lock().write_waiters.register(pid));
+    }
+}
+
+impl Drop for PipeReader {
+    fn drop(&mut self) {
+        interrupts::without_interrupts(|| {
+            let mut pipe = self.0.lock();
+            pipe.reader_open = false;
+            pipe.write_waiters.wake_all();
+        });
+    }
+}
+
+impl Drop for PipeWriter {
+    fn drop(&mut self) {
+        interrupts::without_interrupts(|| {
+            let mut pipe = self.0.lock();
+            pipe.writer_open = false;
+            pipe.read_waiters.wake_all();
+        });
+    }
+}
+
+#[test_case]
+fn test_ring_buffer_wraps() {
+    let mut ring = RingBuffer::new();
+    let mut out = [0u8; PIPE_CAPACITY];
+    assert_eq!(ring.push(&[1; PIPE_CAPACITY - 2]), PIPE_CAPACITY - 2);
+    assert_eq!(ring.pop(&mut out[..PIPE_CAPACITY - 2]), PIPE_CAPACITY - 2);
+    assert_eq!(ring.push(&[1, 2, 3, 4]), 4);
+    assert_eq!(ring.pop(&mut out[..8]), 4);
+    assert_eq!(out[..4], [1, 2, 3, 4]);
+    assert!(ring.is_empty());
+}
+
+#[test_case]
+fn test_ring_buffer_bounded() {
+    let mut ring = RingBuffer::new();
+    assert_eq!(ring.push(&[0; PIPE_CAPACITY + 16]), PIPE_CAPACITY);
+    assert!(ring.is_full());
+    assert_eq!(ring.push(&[0; 1]), 0);
+}
@@ -0,0 +1,293 @@
+use x86_64::instructions::interrupts;
+use x86_64::structures::paging::FrameDeallocator;
+use crate::elf::core_file::REGISTER_COUNT;
+use crate::interrupts::trap::TrapFrame;
+use crate::memory::{frame_to_virt, with_frame_allocator};
+use crate::process::Process;
+use crate::process::address_space::{load_page, USER_MEMORY_END};
+use crate::process::signal::{is_user_address, user_rflags, SIGKILL};
+use crate::threading::scheduler::{wake, with_current_process, with_process, PID, SCHEDULER};
+
+/// makes the cpu raise a debug exception after the next user instruction
+pub const TRAP_FLAG: u64 = 0x100;
+
+/// debugging state of a process traced by another
+pub struct Tracee {
+    tracer: PID,
+    /// address of the user context on the kernel stack while stopped for the tracer
+    frame: Option<u64>,
+    /// the signal the tracee stopped with
+    stop_signal: u8,
+    /// true once waitpid told the tracer about the current stop
+    stop_reported: bool,
+    /// the signal to act on once resumed, 0 for none
+    resume_signal: u8,
+}
+
+#[derive(Debug)]
+pub enum PtraceError {
+    /// the process is not traced by the caller
+    NotTraced,
+    AlreadyTraced,
+    /// the tracee is running and must stop first
+    NotStopped,
+    BadAddress,
+    NoProcess,
+}
+
+impl Process {
+    /// the process tracing this one
+    pub fn tracer(&self) -> Option<PID> {
+        self.tracee.as_ref().map(|tracee| tracee.tracer)
+    }
+
+    pub fn attach(&mut self, tracer: PID) -> Result<(), PtraceError> {
+        if self.tracee.is_some() {
+            return Err(PtraceError::AlreadyTraced);
+        }
+        self.tracee = Some(Tracee {
+            tracer,
+            frame: None,
+            stop_signal: 0,
+            stop_reported: false,
+            resume_signal: 0,
+        });
+        Ok(())
+    }
+
+    /// stops tracing this process
+    ///
+    /// returns true if it was stopped for its tracer, it has to be resumed then
+    pub fn detach(&mut self) -> bool {
+        match self.tracee.take().and_then(|tracee| tracee.frame) {
+            Some(frame) => {
+                // a pending single step must not trap once nobody listens
+                unsafe { (*(frame as *mut TrapFrame)).rflags &= !TRAP_FLAG };
+                true
+            },
+            None => false,
+        }
+    }
+
+    /// returns the signal of a stop that has not been reported to tracer yet
+    pub fn take_trace_stop(&mut self, tracer: PID) -> Result<Option<u8>, PtraceError> {
+        let tracee = self.traced_by(tracer)?;
+        if tracee.frame.is_none() || tracee.stop_reported {
+            return Ok(None);
+        }
+        tracee.stop_reported = true;
+        Ok(Some(tracee.stop_signal))
+    }
+
+    /// registers of a stopped tracee in the layout of user_regs_struct
+    pub fn traced_registers(&mut self, tracer: PID) -> Result<[u64; REGISTER_COUNT], PtraceError> {
+        let fs_base = self.fs_base;
+        Ok(user_regs(self.stopped_frame(tracer)?, fs_base))
+    }
+
+    /// replaces the registers of a stopped tracee, segments and privileged flags are kept
+    pub fn set_traced_registers(
+        &mut self, tracer: PID, registers: &[u64; REGISTER_COUNT]
+    ) -> Result<(), PtraceError> {
+        let frame = self.stopped_frame(tracer)?;
+        let (new_frame, fs_base) = frame_from_registers(registers, frame)?;
+        *frame = new_frame;
+        self.fs_base = fs_base;
+        Ok(())
+    }
+
+    /// lets a stopped tracee run again, acting on signal unless it is 0
+    ///
+    /// with single_step it stops again after one instruction. the caller moves the task back
+    /// to the ready state
+    pub fn resume_traced(
+        &mut self, tracer: PID, signal: u8, single_step: bool
+    ) -> Result<(), PtraceError> {
+        set_single_step(self.stopped_frame(tracer)?, single_step);
+        let tracee = self.traced_by(tracer)?;
+        tracee.frame = None;
+        tracee.resume_signal = signal;
+        Ok(())
+    }
+
+    fn traced_by(&mut self, tracer: PID) -> Result<&mut Tracee, PtraceError> {
+        self.tracee.as_mut()
+            .filter(|tracee| tracee.tracer == tracer)
+            .ok_or(PtraceError::NotTraced)
+    }
+
+    fn stopped_frame(&mut self, tracer: PID) -> Result<&mut TrapFrame, PtraceError> {
+        let frame = self.traced_by(tracer)?.frame.ok_or(PtraceError::NotStopped)?;
+        // the frame stays on the kernel stack of the tracee until it is resumed
+        Ok(unsafe { &mut *(frame as *mut TrapFrame) })
+    }
+}
+
+/// the frame and fs_base for registers in the layout of user_regs_struct
+///
+/// segments and privileged flags are taken from current
+fn frame_from_registers(
+    registers: &[u64; REGISTER_COUNT], current: &TrapFrame
+) -> Result<(TrapFrame, u64), PtraceError> {
+    let [
+        r15, r14, r13, r12, rbp, rbx, r11, r10, r9, r8, rax, rcx, rdx, rsi, rdi,
+        _orig_rax, rip, _cs, rflags, rsp, _ss, fs_base, ..
+    ] = *registers;
+    if ![rip, rsp, fs_base].into_iter().all(is_user_address) {
+        return Err(PtraceError::BadAddress);
+    }
+    let frame = TrapFrame {
+        r15, r14, r13, r12, r11, r10, r9, r8, rbp, rdi, rsi, rdx, rcx, rbx, rax,
+        error_code: 0,
+        rip,
+        cs: current.cs,
+        rflags: user_rflags(rflags, current.rflags),
+        rsp,
+        ss: current.ss,
+    };
+    Ok((frame, fs_base))
+}
+
+/// makes the user context in frame trap after one instruction, or stops it from doing so
+fn set_single_step(frame: &mut TrapFrame, single_step: bool) {
+    if single_step {
+        frame.rflags |= TRAP_FLAG;
+    } else {
+        frame.rflags &= !TRAP_FLAG;
+    }
+}
+
+/// user registers in the layout of user_regs_struct, as debuggers and core files expect them
+pub fn user_regs(frame: &TrapFrame, fs_base: u64) -> [u64; REGISTER_COUNT] {
+    [
+        frame.r15, frame.r14, frame.r13, frame.r12, frame.rbp, frame.rbx, frame.r11,
+        frame.r10, frame.r9, frame.r8, frame.rax, frame.rcx, frame.rdx, frame.rsi,
+        frame.rdi,
+        // orig_rax, there is no interrupted syscall
+        u64::MAX,
+        frame.rip, frame.cs, frame.rflags, frame.rsp, frame.ss,
+        // fs_base, gs_base, ds, es, fs, gs
+        fs_base, 0, frame.ss, frame.ss, 0, 0,
+    ]
+}
+
+/// stops a traced current process before it acts on signal and waits for its tracer
+///
+/// returns the signal to act on, which the tracer may have replaced or discarded. untraced
+/// processes and SIGKILL pass straight through. must be called with interrupts disabled
+pub fn report_signal(frame: &mut TrapFrame, signal: u8) -> Option<u8> {
+    if signal == SIGKILL {
+        return Some(signal);
+    }
+    let frame_addr = frame as *mut TrapFrame as u64;
+    let tracer = with_current_process(|process| {
+        let tracee = process.tracee.as_mut()?;
+        tracee.frame = Some(frame_addr);
+        tracee.stop_signal = signal;
+        tracee.stop_reported = false;
+        tracee.resume_signal = 0;
+        Some(tracee.tracer)
+    }).flatten();
+    let tracer = match tracer {
+        Some(tracer) => tracer,
+        None => return Some(signal),
+    };
+
+    wake(tracer);
+    SCHEDULER.lock().stop_current();
+    // resumed by the tracer with interrupts enabled
+    interrupts::disable();
+
+    let resume_signal = with_current_process(|process| match process.tracee.as_mut() {
+        Some(tracee) => {
+            // SIGCONT from elsewhere resumes without the tracer
+            tracee.frame = None;
+            tracee.resume_signal
+        },
+        // the tracer detached or exited
+        None => 0,
+    }).unwrap_or(0);
+    (resume_signal != 0).then_some(resume_signal)
+}
+
+/// reads the aligned word at addr in a stopped tracee
+pub fn peek(tracer: PID, pid: PID, addr: u64) -> Result<u64, PtraceError> {
+    with_tracee_word(tracer, pid, addr, |word| *word)
+}
+
+/// writes the aligned word at addr in a stopped tracee, even in read only memory
+pub fn poke(tracer: PID, pid: PID, addr: u64, value: u64) -> Result<(), PtraceError> {
+    with_tracee_word(tracer, pid, addr, |word| *word = value)
+}
+
+/// runs f on the word at addr of a stopped tracee through the physical mapping
+///
+/// pages the tracee never touched are loaded first, outside the scheduler lock as that may
+/// read the disk
+fn with_tracee_word<R>(
+    tracer: PID, pid: PID, addr: u64, f: impl FnOnce(&mut u64) -> R
+) -> Result<R, PtraceError> {
+    if addr % 8 != 0 || addr >= USER_MEMORY_END {
+        return Err(PtraceError::BadAddress);
+    }
+
+    let unloaded = with_process(pid, |process| {
+        process.stopped_frame(tracer)?;
+        if process.mapped_frame(addr).is_some() {
+            return Ok(None);
+        }
+        process.vmas.find(addr).cloned()
+            .map(Some)
+            .ok_or(PtraceError::BadAddress)
+    }).ok_or(PtraceError::NoProcess)??;
+    let loaded = match unloaded {
+        Some(vma) => Some(load_page(addr & !0xfff, &vma).ok_or(PtraceError::BadAddress)?),
+        None => None,
+    };
+
+    let result = with_process(pid, |process| {
+        // mapping first keeps the loaded frame from leaking if the tracee was resumed
+        let frame = match loaded {
+            Some(frame) => process.map_loaded(addr, frame),
+            None => process.mapped_frame(addr),
+        };
+        process.stopped_frame(tracer)?;
+        let frame = frame.ok_or(PtraceError::BadAddress)?;
+        let word = (frame_to_virt(frame) + (addr & 0xfff)).as_mut_ptr::<u64>();
+        Ok(f(unsafe { &mut *word }))
+    });
+    match result {
+        Some(result) => result,
+        None => {
+            // the tracee exited while its page was loaded
+            if let Some(frame) = loaded {
+                with_frame_allocator(|frame_allocator| unsafe { frame_allocator.deallocate_frame(frame) });
+            }
+            Err(PtraceError::NoProcess)
+        },
+    }
+}
+
+#[test_case]
+fn test_registers_must_be_canonical() {
+    let current = TrapFrame { cs: 0x23, ss: 0x1b, ..TrapFrame::default() };
+    let mut registers = user_regs(&TrapFrame { rip: 0x401000, rsp: 0x7000_0000, ..current }, 0);
+    let (frame, _) = frame_from_registers(&registers, &current).unwrap();
+    assert_eq!((frame.rip, frame.rsp, frame.cs, frame.ss), (0x401000, 0x7000_0000, 0x23, 0x1b));
+
+    // rip and rsp are the 17th and 20th registers
+    registers[16] = 0x0000_8000_0000_0000;
+    assert!(matches!(frame_from_registers(&registers, &current), Err(PtraceError::BadAddress)));
+    registers[16] = 0x401000;
+    registers[19] = 0xffff_8000_0000_0000;
+    assert!(matches!(frame_from_registers(&registers, &current), Err(PtraceError::BadAddress)));
+}
+
+#[test_case]
+fn test_single_step_trap_flag() {
+    let mut frame = TrapFrame { rflags: 0x202, ..TrapFrame::default() };
+    set_single_step(&mut frame, true);
+    assert_eq!(frame.rflags, 0x202 | TRAP_FLAG);
+    set_single_step(&mut frame, false);
+    assert_eq!(frame.rflags, 0x202);
+}
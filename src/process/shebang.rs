@@ -0,0 +1,77 @@
+/// scripts start with "#!" followed by the interpreter and an optional argument on this many
+/// bytes, including the newline
+pub const MAX_SHEBANG_LEN: usize = 128;
+
+/// the interpreter line of a script
+#[derive(Debug, PartialEq)]
+pub struct Shebang<'a> {
+    pub interpreter: &'a str,
+    /// everything after the interpreter is passed as one argument, as other unixes do
+    pub argument: Option<&'a str>,
+}
+
+#[derive(Debug, PartialEq)]
+pub struct BadShebang;
+
+/// parses the interpreter line at the start of a file, returning None if it is not a script
+///
+/// bytes is what could be read of the first MAX_SHEBANG_LEN bytes, so fewer bytes mean the file
+/// ended and the line may end without a newline
+pub fn parse(bytes: &[u8]) -> Result<Option<Shebang>, BadShebang> {
+    if !bytes.starts_with(b"#!") {
+        return Ok(None);
+    }
+    let bytes = &bytes[..bytes.len().min(MAX_SHEBANG_LEN)];
+    let line_end = match bytes.iter().position(|byte| *byte == b'\n') {
+        Some(line_end) => line_end,
+        None if bytes.len() < MAX_SHEBANG_LEN => bytes.len(),
+        None => return Err(BadShebang),
+    };
+    let is_blank = |c: char| c == ' ' || c == '\t';
+    let line = core::str::from_utf8(&bytes[2..line_end])
+        .map_err(|_| BadShebang)?
+        .trim_end_matches('\r')
+        .trim_matches(is_blank);
+
+    let (interpreter, argument) = match line.split_once(is_blank) {
+        Some((interpreter, argument)) => (interpreter, Some(argument.trim_matches(is_blank))),
+        None => (line, None),
+    };
+    if interpreter.is_empty() {
+        return Err(BadShebang);
+    }
+    Ok(Some(Shebang {
+        interpreter,
+        argument: argument.filter(|argument| !argument.is_empty()),
+    }))
+}
+
+#[test_case]
+fn test_parse_shebang() {
+    assert_eq!(parse(b"\x7FELF"), Ok(None));
+    assert_eq!(parse(b"#!/BIN/SH\necho"), Ok(Some(Shebang {
+        interpreter: "/BIN/SH",
+        argument: None,
+    })));
+    assert_eq!(parse(b"#! /BIN/AWK -f  -v \n"), Ok(Some(Shebang {
+        interpreter: "/BIN/AWK",
+        argument: Some("-f  -v"),
+    })));
+    // a script that is only the interpreter line may leave out the newline
+    assert_eq!(parse(b"#!/BIN/SH -e"), Ok(Some(Shebang {
+        interpreter: "/BIN/SH",
+        argument: Some("-e"),
+    })));
+}
+
+#[test_case]
+fn test_bad_shebang() {
+    assert_eq!(parse(b"#!  \n"), Err(BadShebang));
+    assert_eq!(parse(b"#!"), Err(BadShebang));
+    let mut long = [b'a'; MAX_SHEBANG_LEN + 1];
+    long[..2].copy_from_slice(b"#!");
+    long[MAX_SHEBANG_LEN] = b'\n';
+    assert_eq!(parse(&long), Err(BadShebang));
+    // the whole first MAX_SHEBANG_LEN bytes without a newline is a line too long, not the end
+    assert_eq!(parse(&long[..MAX_SHEBANG_LEN]), Err(BadShebang));
+}
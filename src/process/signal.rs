@@ -0,0 +1,361 @@
+use core::mem::size_of;
+use x86_64::instructions::interrupts;
+use crate::interrupts::trap::TrapFrame;
+use crate::process::{address_space, core_dump, ptrace};
+use crate::threading::scheduler::{with_current_process, SCHEDULER};
+
+pub const SIGHUP: u8 = 1;
+pub const SIGINT: u8 = 2;
+pub const SIGQUIT: u8 = 3;
+pub const SIGILL: u8 = 4;
+pub const SIGTRAP: u8 = 5;
+pub const SIGABRT: u8 = 6;
+pub const SIGBUS: u8 = 7;
+pub const SIGFPE: u8 = 8;
+pub const SIGKILL: u8 = 9;
+pub const SIGUSR1: u8 = 10;
+pub const SIGSEGV: u8 = 11;
+pub const SIGUSR2: u8 = 12;
+pub const SIGPIPE: u8 = 13;
+pub const SIGALRM: u8 = 14;
+pub const SIGTERM: u8 = 15;
+pub const SIGCHLD: u8 = 17;
+pub const SIGCONT: u8 = 18;
+pub const SIGSTOP: u8 = 19;
+pub const SIGTSTP: u8 = 20;
+pub const SIGTTIN: u8 = 21;
+pub const SIGTTOU: u8 = 22;
+pub const SIGNAL_COUNT: usize = 32;
+
+pub const SIG_DFL: u64 = 0;
+pub const SIG_IGN: u64 = 1;
+
+pub const SA_RESTORER: u64 = 0x04000000;
+pub const SA_NODEFER: u64 = 0x40000000;
+pub const SA_RESETHAND: u64 = 0x80000000;
+
+/// signals that can be neither caught, ignored nor blocked
+const UNBLOCKABLE: u64 = 1 << SIGKILL | 1 << SIGSTOP;
+/// bytes below the interrupted stack pointer that leaf functions may use without moving it
+const RED_ZONE: u64 = 128;
+/// rflags bits user code may change through sigreturn
+const USER_FLAGS: u64 = 0xdd5;
+const INTERRUPT_FLAG: u64 = 0x200;
+/// end of the lower canonical half, user addresses lie below it
+const USER_ADDRESS_END: u64 = 0x0000_8000_0000_0000;
+
+/// what happens to a process receiving a signal without a handler
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum DefaultAction {
+    Terminate,
+    Ignore,
+    Stop,
+    Continue,
+}
+
+/// handler registration passed to and from sigaction
+///
+/// handlers return into restorer, which is expected to invoke sigreturn
+#[repr(C)]
+#[derive(Debug, Copy, Clone)]
+pub struct SigAction {
+    pub handler: u64,
+    pub flags: u64,
+    pub restorer: u64,
+    pub mask: u64,
+}
+
+/// pushed onto the user stack when a handler is invoked
+///
+/// the restorer address sits where the handler expects its return address
+#[repr(C)]
+struct SignalFrame {
+    restorer: u64,
+    signal: u64,
+    blocked: u64,
+    registers: TrapFrame,
+}
+
+/// per-process signal dispositions, masks and pending signals
+pub struct SignalState {
+    pending: u64,
+    blocked: u64,
+    actions: [SigAction; SIGNAL_COUNT],
+}
+
+#[derive(Debug)]
+pub struct InvalidSignal;
+
+pub fn default_action(signal: u8) -> DefaultAction {
+    match signal {
+        SIGCHLD => DefaultAction::Ignore,
+        SIGCONT => DefaultAction::Continue,
+        SIGSTOP | SIGTSTP | SIGTTIN | SIGTTOU => DefaultAction::Stop,
+        _ => DefaultAction::Terminate,
+    }
+}
+
+/// true for signal numbers that may be sent, 0 is only used to probe for a process
+pub fn is_valid(signal: u64) -> bool {
+    signal > 0 && signal < SIGNAL_COUNT as u64
+}
+
+impl SigAction {
+    pub const fn default() -> Self {
+        Self {
+            handler: SIG_DFL,
+            flags: 0,
+            restorer: 0,
+            mask: 0,
+        }
+    }
+}
+
+impl SignalState {
+    pub const fn new() -> Self {
+        Self {
+            pending: 0,
+            blocked: 0,
+            actions: [SigAction::default(); SIGNAL_COUNT],
+        }
+    }
+
+    /// marks a signal as pending, delivery happens when the process next returns to user mode
+    pub fn raise(&mut self, signal: u8) {
+        self.pending |= 1 << signal;
+        if signal == SIGCONT {
+            self.pending &= !(1 << SIGSTOP | 1 << SIGTSTP | 1 << SIGTTIN | 1 << SIGTTOU);
+        } else if default_action(signal) == DefaultAction::Stop {
+            self.pending &= !(1 << SIGCONT);
+        }
+    }
+
+    /// raises a signal caused by the process itself
+    ///
+    /// a fault cannot be ignored or blocked without looping forever, so in that case the
+    /// default action is restored
+    pub fn force(&mut self, signal: u8) {
+        let action = &mut self.actions[signal as usize];
+        if self.blocked & 1 << signal != 0 || action.handler == SIG_IGN {
+            *action = SigAction::default();
+            self.blocked &= !(1 << signal);
+        }
+        self.raise(signal);
+    }
+
+    /// true if a pending signal is not blocked
+    pub fn has_deliverable(&self) -> bool {
+        self.pending & !self.blocked != 0
+    }
+
+    /// true if the signal would not be ignored once delivered
+    pub fn is_handled(&self, signal: u8) -> bool {
+        match self.actions[signal as usize].handler {
+            SIG_IGN => false,
+            SIG_DFL => default_action(signal) != DefaultAction::Ignore,
+            _ => true,
+        }
+    }
+
+    pub fn action(&self, signal: u8) -> SigAction {
+        self.actions[signal as usize]
+    }
+
+    pub fn set_action(&mut self, signal: u8, action: SigAction) -> Result<(), InvalidSignal> {
+        if UNBLOCKABLE & 1 << signal != 0 {
+            return Err(InvalidSignal);
+        }
+        // there is no vdso to return through, so handlers need a restorer
+        if action.handler > SIG_IGN && action.flags & SA_RESTORER == 0 {
+            return Err(InvalidSignal);
+        }
+        self.actions[signal as usize] = action;
+        // ignoring a signal discards it even while pending
+        if !self.is_handled(signal) {
+            self.pending &= !(1 << signal);
+        }
+        Ok(())
+    }
+
+    pub fn pending(&self) -> u64 {
+        self.pending
+    }
+
+    pub fn blocked(&self) -> u64 {
+        self.blocked
+    }
+
+    pub fn set_blocked(&mut self, mask: u64) {
+        self.blocked = mask & !UNBLOCKABLE & !1;
+    }
+
+    /// removes the lowest pending signal that is not blocked and returns it with its action
+    ///
+    /// one shot handlers are reset to the default action
+    fn take_deliverable(&mut self) -> Option<(u8, SigAction)> {
+        let deliverable = self.pending & !self.blocked;
+        if deliverable == 0 {
+            return None;
+        }
+        let signal = deliverable.trailing_zeros() as u8;
+        self.pending &= !(1 << signal);
+
+        let action = self.actions[signal as usize];
+        if action.handler > SIG_IGN && action.flags & SA_RESETHAND != 0 {
+            self.actions[signal as usize] = SigAction::default();
+        }
+        Some((signal, action))
+    }
+}
+
+/// acts on the pending signals of the current process before it returns to user mode
+///
+/// either runs default actions, which may never return, or redirects frame to a handler.
+/// must be called with interrupts disabled and frame pointing at the user context
+pub fn deliver(frame: &mut TrapFrame) {
+    loop {
+        let next = with_current_process(|process| process.signals_mut().take_deliverable());
+        let (signal, action) = match next.flatten() {
+            Some(next) => next,
+            None => return,
+        };
+        // a tracer sees signals first and may replace or discard them
+        let (signal, action) = match ptrace::report_signal(frame, signal) {
+            Some(reported) if reported == signal => (signal, action),
+            Some(replaced) => match with_current_process(|process| process.signals_mut().action(replaced)) {
+                Some(action) => (replaced, action),
+                None => return,
+            },
+            None => continue,
+        };
+
+        match action.handler {
+            SIG_IGN => continue,
+            SIG_DFL => match default_action(signal) {
+                DefaultAction::Ignore | DefaultAction::Continue => continue,
+                DefaultAction::Stop => {
+                    SCHEDULER.lock().stop_current();
+                    // resumed by SIGCONT with interrupts enabled
+                    interrupts::disable();
+                },
+                DefaultAction::Terminate => {
+                    if core_dump::dumps_core(signal) {
+                        core_dump::dump_current(signal, frame);
+                    }
+                    SCHEDULER.lock().end_current_task(128 + signal as i64)
+                },
+            },
+            _ => {
+                if unsafe { push_signal_frame(frame, signal, &action) }.is_err() {
+                    // the stack is unusable so the handler could never run
+                    SCHEDULER.lock().end_current_task(128 + SIGSEGV as i64);
+                }
+                return;
+            },
+        }
+    }
+}
+
+/// saves the user context on its stack and points frame at the handler
+unsafe fn push_signal_frame(
+    frame: &mut TrapFrame, signal: u8, action: &SigAction
+) -> Result<(), InvalidSignal> {
+    let stack_top = (frame.rsp.checked_sub(RED_ZONE).ok_or(InvalidSignal)?) & !0xf;
+    // the handler is entered as if called, so rsp + 8 must be 16 byte aligned
+    let frame_addr = stack_top.checked_sub(size_of::<SignalFrame>() as u64 + 8)
+        .ok_or(InvalidSignal)?;
+    if !address_space::populate_user_range(frame_addr, size_of::<SignalFrame>() as u64, true) {
+        return Err(InvalidSignal);
+    }
+
+    let blocked = with_current_process(|process| {
+        let signals = process.signals_mut();
+        let blocked = signals.blocked;
+        let mut mask = blocked | action.mask;
+        if action.flags & SA_NODEFER == 0 {
+            mask |= 1 << signal;
+        }
+        signals.set_blocked(mask);
+        blocked
+    }).ok_or(InvalidSignal)?;
+
+    *(frame_addr as *mut SignalFrame) = SignalFrame {
+        restorer: action.restorer,
+        signal: signal as u64,
+        blocked,
+        registers: *frame,
+    };
+
+    frame.rip = action.handler;
+    frame.rsp = frame_addr;
+    frame.rdi = signal as u64;
+    frame.rsi = 0;
+    frame.rdx = 0;
+    Ok(())
+}
+
+/// takes the bits of requested that user code may change and the rest from current
+pub fn user_rflags(requested: u64, current: u64) -> u64 {
+    (requested & USER_FLAGS) | (current & !USER_FLAGS) | INTERRUPT_FLAG
+}
+
+/// true if a user context may hold addr in rip, rsp or fs_base
+///
+/// iretq to a non canonical rip or rsp faults in the kernel instead of in user mode
+pub fn is_user_address(addr: u64) -> bool {
+    addr < USER_ADDRESS_END
+}
+
+/// restores the context saved by push_signal_frame once a handler returns into its restorer
+///
+/// the restorer's return popped the frame's first word, so it starts just below rsp
+pub unsafe fn restore_signal_frame(frame: &mut TrapFrame) -> Result<(), InvalidSignal> {
+    let frame_addr = frame.rsp.checked_sub(8).ok_or(InvalidSignal)?;
+    if !address_space::populate_user_range(frame_addr, size_of::<SignalFrame>() as u64, false) {
+        return Err(InvalidSignal);
+    }
+    let saved = &*(frame_addr as *const SignalFrame);
+
+    let mut registers = saved.registers;
+    if !is_user_address(registers.rip) || !is_user_address(registers.rsp) {
+        return Err(InvalidSignal);
+    }
+    // never let user memory choose segments or privileged flags
+    registers.cs = frame.cs;
+    registers.ss = frame.ss;
+    registers.rflags = user_rflags(registers.rflags, frame.rflags);
+    registers.error_code = 0;
+    *frame = registers;
+
+    let blocked = saved.blocked;
+    with_current_process(|process| process.signals_mut().set_blocked(blocked))
+        .ok_or(InvalidSignal)
+}
+
+#[test_case]
+fn test_signal_masking() {
+    let mut signals = SignalState::new();
+    signals.set_blocked(1 << SIGINT | 1 << SIGKILL);
+    assert_eq!(signals.blocked(), 1 << SIGINT);
+    signals.raise(SIGINT);
+    assert!(!signals.has_deliverable());
+    signals.raise(SIGTERM);
+    assert_eq!(signals.take_deliverable().map(|(signal, _)| signal), Some(SIGTERM));
+    assert!(signals.take_deliverable().is_none());
+}
+
+#[test_case]
+fn test_continue_discards_stop() {
+    let mut signals = SignalState::new();
+    signals.raise(SIGTSTP);
+    signals.raise(SIGCONT);
+    assert_eq!(signals.take_deliverable().map(|(signal, _)| signal), Some(SIGCONT));
+    assert!(!signals.has_deliverable());
+}
+
+#[test_case]
+fn test_user_address() {
+    assert!(is_user_address(0x7fff_ffff_f000));
+    assert!(!is_user_address(USER_ADDRESS_END));
+    assert!(!is_user_address(0xffff_8000_0000_0000));
+}
@@ -0,0 +1,87 @@
+use x86_64::structures::paging::{FrameAllocator, Size4KiB};
+use crate::elf::{self, TlsTemplate};
+use crate::process::{Process, ProcessSpawnError};
+use crate::process::address_space::MMAP_BASE;
+use crate::process::vma::{page_align_up, Vma, VmaKind, PROT_READ, PROT_WRITE};
+use crate::random::random_u64;
+use crate::vfs::InodeRef;
+
+/// bytes reserved for the thread control block fs points at
+const TCB_SIZE: u64 = 0x100;
+/// offsets in the thread control block where x86_64 libcs expect these values
+const TCB_SELF: usize = 0x0;
+const TCB_SELF_COPY: usize = 0x10;
+const TCB_STACK_GUARD: usize = 0x28;
+const TCB_POINTER_GUARD: usize = 0x30;
+
+impl Process {
+    /// creates the thread local storage of the first thread of a statically linked program
+    ///
+    /// returns the thread pointer to load into the fs base
+    pub(super) unsafe fn setup_tls(
+        &mut self,
+        executable: &InodeRef,
+        template: &TlsTemplate,
+        frame_allocator: &mut impl FrameAllocator<Size4KiB>,
+    ) -> Result<u64, ProcessSpawnError> {
+        let block_size = tls_block_size(template);
+        let len = page_align_up(block_size + TCB_SIZE)
+            .ok_or(ProcessSpawnError::MapFail)?;
+        let start = self.vmas.find_free(len, MMAP_BASE, self.mmap_top)
+            .ok_or(ProcessSpawnError::MapFail)?;
+        self.vmas.insert(Vma::new(start, start + len, PROT_READ | PROT_WRITE, VmaKind::Anonymous))
+            .map_err(|_| ProcessSpawnError::MapFail)?;
+
+        let data = elf::read_file(executable, template.offset, template.file_size)?;
+        let mut memory = alloc::vec![0; len as usize];
+        let thread_pointer = build_tls(&mut memory, start, template, &data, [random_u64(), random_u64()]);
+        self.map_initialized(start, &memory, frame_allocator)
+            .map_err(|_| ProcessSpawnError::MapFail)?;
+        Ok(thread_pointer)
+    }
+}
+
+/// size of the tls block, rounded so the thread pointer after it stays aligned
+fn tls_block_size(template: &TlsTemplate) -> u64 {
+    (template.mem_size + template.align - 1) & !(template.align - 1)
+}
+
+/// lays out the tls block and the thread control block after it in memory, which will appear
+/// at the page aligned address start
+///
+/// x86_64 places the block right below the thread pointer, which points at the control block
+/// and at itself. returns the thread pointer
+fn build_tls(
+    memory: &mut [u8], start: u64, template: &TlsTemplate, data: &[u8], guards: [u64; 2]
+) -> u64 {
+    let block_size = tls_block_size(template) as usize;
+    let thread_pointer = start + block_size as u64;
+    memory[..data.len()].copy_from_slice(data);
+
+    let tcb = &mut memory[block_size..block_size + TCB_SIZE as usize];
+    for (offset, value) in [
+        (TCB_SELF, thread_pointer),
+        (TCB_SELF_COPY, thread_pointer),
+        (TCB_STACK_GUARD, guards[0]),
+        (TCB_POINTER_GUARD, guards[1]),
+    ] {
+        tcb[offset..offset + 8].copy_from_slice(&value.to_le_bytes());
+    }
+    thread_pointer
+}
+
+#[test_case]
+fn test_build_tls() {
+    let template = TlsTemplate {
+        offset: 0,
+        file_size: 4,
+        mem_size: 0x14,
+        align: 0x10,
+    };
+    let mut memory = [0u8; 0x200];
+    let thread_pointer = build_tls(&mut memory, 0x1000, &template, &[1, 2, 3, 4], [7, 8]);
+    assert_eq!(thread_pointer, 0x1020);
+    assert_eq!(&memory[..5], &[1, 2, 3, 4, 0]);
+    assert_eq!(memory[0x20..0x28], 0x1020u64.to_le_bytes());
+    assert_eq!(memory[0x48..0x50], 7u64.to_le_bytes());
+}
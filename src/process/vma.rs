@@ -0,0 +1,287 @@
+use alloc::collections::BTreeMap;
+use alloc::vec::Vec;
+use x86_64::structures::paging::PageTableFlags;
+use crate::vfs::InodeRef;
+
+pub const PROT_NONE: u64 = 0x0;
+pub const PROT_READ: u64 = 0x1;
+pub const PROT_WRITE: u64 = 0x2;
+pub const PROT_EXEC: u64 = 0x4;
+
+const PAGE_SIZE: u64 = 0x1000;
+
+/// what a virtual memory area is used for
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum VmaKind {
+    /// a segment of the executable
+    Image,
+    /// memory between the start of the heap and the program break
+    Heap,
+    Stack,
+    /// memory from mmap
+    Anonymous,
+}
+
+/// where the contents of a page come from when it is first touched
+#[derive(Debug, Clone)]
+pub enum VmaBacking {
+    /// zero filled
+    Anonymous,
+    /// file_size bytes of file at offset appear at virt_addr, the rest of the area is zero filled
+    File {
+        file: InodeRef,
+        offset: u64,
+        virt_addr: u64,
+        file_size: u64,
+    },
+}
+
+/// a page aligned range of user memory with uniform protection
+#[derive(Debug, Clone)]
+pub struct Vma {
+    pub start: u64,
+    pub end: u64,
+    pub protection: u64,
+    pub kind: VmaKind,
+    pub backing: VmaBacking,
+}
+
+/// the virtual memory areas of a process, ordered by start address and never overlapping
+pub struct VmaList {
+    areas: BTreeMap<u64, Vma>,
+}
+
+#[derive(Debug)]
+pub struct VmaOverlap;
+
+impl Vma {
+    /// creates a zero filled area
+    pub fn new(start: u64, end: u64, protection: u64, kind: VmaKind) -> Self {
+        Self {
+            start,
+            end,
+            protection,
+            kind,
+            backing: VmaBacking::Anonymous,
+        }
+    }
+
+    pub fn with_backing(mut self, backing: VmaBacking) -> Self {
+        self.backing = backing;
+        self
+    }
+
+    /// true if the area allows the access, inaccessible areas allow nothing
+    pub fn allows(&self, is_write: bool) -> bool {
+        self.protection != PROT_NONE && (!is_write || self.protection & PROT_WRITE != 0)
+    }
+
+    pub fn contains(&self, addr: u64) -> bool {
+        self.start <= addr && addr < self.end
+    }
+
+    /// flags for the pages of this area
+    ///
+    /// inaccessible pages stay mapped for the kernel so their contents survive mprotect
+    pub fn page_flags(&self) -> PageTableFlags {
+        to_page_flags(self.protection)
+    }
+}
+
+/// converts PROT_* bits to page table flags
+pub fn to_page_flags(protection: u64) -> PageTableFlags {
+    let mut flags = PageTableFlags::PRESENT;
+    if protection != PROT_NONE {
+        flags |= PageTableFlags::USER_ACCESSIBLE;
+    }
+    if protection & PROT_WRITE != 0 {
+        flags |= PageTableFlags::WRITABLE;
+    }
+    if protection & PROT_EXEC == 0 {
+        flags |= PageTableFlags::NO_EXECUTE;
+    }
+    flags
+}
+
+/// rounds up to a page boundary, None on overflow
+pub fn page_align_up(addr: u64) -> Option<u64> {
+    addr.checked_add(PAGE_SIZE - 1)
+        .map(|addr| addr & !(PAGE_SIZE - 1))
+}
+
+impl VmaList {
+    pub fn new() -> Self {
+        Self {
+            areas: BTreeMap::new(),
+        }
+    }
+
+    /// returns the area containing addr
+    pub fn find(&self, addr: u64) -> Option<&Vma> {
+        self.areas.range(..=addr)
+            .next_back()
+            .map(|(_, vma)| vma)
+            .filter(|vma| vma.contains(addr))
+    }
+
+    pub fn find_mut(&mut self, addr: u64) -> Option<&mut Vma> {
+        self.areas.range_mut(..=addr)
+            .next_back()
+            .map(|(_, vma)| vma)
+            .filter(|vma| vma.contains(addr))
+    }
+
+    /// true if no area intersects [start, end)
+    pub fn is_free(&self, start: u64, end: u64) -> bool {
+        let before = self.areas.range(..start)
+            .next_back()
+            .map_or(false, |(_, vma)| vma.end > start);
+        let inside = self.areas.range(start..end).next().is_some();
+        !before && !inside
+    }
+
+    pub fn insert(&mut self, vma: Vma) -> Result<(), VmaOverlap> {
+        if vma.start >= vma.end || !self.is_free(vma.start, vma.end) {
+            return Err(VmaOverlap);
+        }
+        self.areas.insert(vma.start, vma);
+        Ok(())
+    }
+
+    /// finds the highest free range of len bytes that lies within [base, limit)
+    pub fn find_free(&self, len: u64, base: u64, limit: u64) -> Option<u64> {
+        let mut end = limit;
+        for vma in self.areas.values().rev() {
+            if vma.start >= end {
+                continue;
+            }
+            let gap_start = vma.end.max(base);
+            if end >= gap_start && end - gap_start >= len {
+                return Some(end - len);
+            }
+            end = vma.start;
+            if end <= base {
+                return None;
+            }
+        }
+        if end >= base && end - base >= len {
+            Some(end - len)
+        } else {
+            None
+        }
+    }
+
+    /// removes [start, end) from every area, splitting areas that only partly overlap
+    ///
+    /// returns the removed pieces
+    pub fn remove_range(&mut self, start: u64, end: u64) -> Vec<Vma> {
+        let mut removed = Vec::new();
+        for vma in self.split_range(start, end) {
+            if let Some(vma) = self.areas.remove(&vma.start) {
+                removed.push(vma);
+            }
+        }
+        removed
+    }
+
+    /// changes the protection of [start, end), splitting areas that only partly overlap
+    ///
+    /// returns the changed pieces
+    pub fn protect_range(&mut self, start: u64, end: u64, protection: u64) -> Vec<Vma> {
+        let mut changed = Vec::new();
+        for vma in self.split_range(start, end) {
+            if let Some(vma) = self.areas.get_mut(&vma.start) {
+                vma.protection = protection;
+                changed.push(vma.clone());
+            }
+        }
+        changed
+    }
+
+    /// true if every byte of [start, end) belongs to some area
+    pub fn is_covered(&self, start: u64, end: u64) -> bool {
+        let mut addr = start;
+        while addr < end {
+            match self.find(addr) {
+                Some(vma) => addr = vma.end,
+                None => return false,
+            }
+        }
+        true
+    }
+
+    /// splits areas at start and end and returns the areas now lying inside [start, end)
+    fn split_range(&mut self, start: u64, end: u64) -> Vec<Vma> {
+        self.split_at(start);
+        self.split_at(end);
+        self.areas.range(start..end)
+            .map(|(_, vma)| vma.clone())
+            .collect()
+    }
+
+    fn split_at(&mut self, addr: u64) {
+        if let Some(vma) = self.find_mut(addr) {
+            if vma.start == addr {
+                return;
+            }
+            let mut upper = vma.clone();
+            vma.end = addr;
+            upper.start = addr;
+            self.areas.insert(addr, upper);
+        }
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = &Vma> {
+        self.areas.values()
+    }
+}
+
+#[test_case]
+fn test_remove_range_splits() {
+    let mut vmas = VmaList::new();
+    vmas.insert(Vma::new(0x1000, 0x5000, PROT_READ | PROT_WRITE, VmaKind::Anonymous)).unwrap();
+    let removed = vmas.remove_range(0x2000, 0x3000);
+    assert_eq!(removed.len(), 1);
+    assert_eq!((removed[0].start, removed[0].end), (0x2000, 0x3000));
+    assert!(vmas.find(0x2000).is_none());
+    assert_eq!(vmas.find(0x1fff).map(|vma| (vma.start, vma.end)), Some((0x1000, 0x2000)));
+    assert_eq!(vmas.find(0x3000).map(|vma| (vma.start, vma.end)), Some((0x3000, 0x5000)));
+
+    // a range over several areas and the holes between them
+    let removed = vmas.remove_range(0x1800, 0x4000);
+    assert_eq!(removed.len(), 2);
+    assert!(vmas.is_covered(0x1000, 0x1800));
+    assert!(vmas.is_free(0x1800, 0x4000));
+    assert!(vmas.is_covered(0x4000, 0x5000));
+}
+
+#[test_case]
+fn test_protect_range_splits() {
+    let mut vmas = VmaList::new();
+    vmas.insert(Vma::new(0x1000, 0x5000, PROT_READ | PROT_WRITE, VmaKind::Heap)).unwrap();
+    let changed = vmas.protect_range(0x2000, 0x4000, PROT_READ);
+    assert_eq!(changed.len(), 1);
+    assert_eq!(vmas.iter().count(), 3);
+    assert_eq!(vmas.find(0x1000).unwrap().protection, PROT_READ | PROT_WRITE);
+    assert_eq!(vmas.find(0x3fff).unwrap().protection, PROT_READ);
+    assert!(!vmas.find(0x2000).unwrap().allows(true));
+    assert_eq!(vmas.find(0x4000).unwrap().protection, PROT_READ | PROT_WRITE);
+    // the pieces keep the kind of the area they were split from
+    assert!(vmas.iter().all(|vma| vma.kind == VmaKind::Heap));
+}
+
+#[test_case]
+fn test_find_free() {
+    let mut vmas = VmaList::new();
+    vmas.insert(Vma::new(0x1000, 0x2000, PROT_READ, VmaKind::Anonymous)).unwrap();
+    vmas.insert(Vma::new(0x4000, 0x5000, PROT_READ, VmaKind::Anonymous)).unwrap();
+    assert!(vmas.insert(Vma::new(0x1800, 0x2800, PROT_READ, VmaKind::Anonymous)).is_err());
+
+    // the highest gap that fits is used
+    assert_eq!(vmas.find_free(0x1000, 0x1000, 0x6000), Some(0x5000));
+    assert_eq!(vmas.find_free(0x2000, 0x1000, 0x4000), Some(0x2000));
+    assert_eq!(vmas.find_free(0x1000, 0x3000, 0x4000), Some(0x3000));
+    // no gap between base and limit is large enough
+    assert_eq!(vmas.find_free(0x3000, 0x1000, 0x5000), None);
+    assert_eq!(vmas.find_free(0x1000, 0x1000, 0x2000), None);
+}
@@ -0,0 +1,46 @@
+use core::arch::x86_64::_rdtsc;
+use core::sync::atomic::{AtomicU64, Ordering};
+use x86_64::instructions::random::RdRand;
+
+/// rdrand may fail transiently when the hardware pool is drained
+const RDRAND_RETRIES: usize = 10;
+
+/// state of the fallback generator, stirred with the timestamp counter on every use
+static STATE: AtomicU64 = AtomicU64::new(0x853c_49e6_748f_ea9b);
+
+/// returns 64 random bits for the kernel
+///
+/// uses rdrand when the cpu has it, otherwise a splitmix64 stream seeded from the timestamp
+/// counter, which is not cryptographically secure but still differs between boots and calls
+pub fn random_u64() -> u64 {
+    if let Some(rdrand) = RdRand::new() {
+        for _ in 0..RDRAND_RETRIES {
+            if let Some(value) = rdrand.get_u64() {
+                return value;
+            }
+        }
+    }
+    let tsc = unsafe { _rdtsc() };
+    let state = STATE.fetch_add(0x9e37_79b9_7f4a_7c15 ^ tsc, Ordering::Relaxed);
+    splitmix64(state ^ tsc)
+}
+
+/// returns a random number in [0, bound), bound must not be 0
+pub fn random_below(bound: u64) -> u64 {
+    // multiplying keeps the bias below bound / 2^64
+    ((random_u64() as u128 * bound as u128) >> 64) as u64
+}
+
+fn splitmix64(mut value: u64) -> u64 {
+    value = value.wrapping_add(0x9e37_79b9_7f4a_7c15);
+    value = (value ^ (value >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+    value = (value ^ (value >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+    value ^ (value >> 31)
+}
+
+#[test_case]
+fn test_random_below() {
+    for bound in [1, 2, 7, 0x1000] {
+        assert!(random_below(bound) < bound);
+    }
+}
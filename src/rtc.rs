@@ -0,0 +1,141 @@
+use conquer_once::spin::OnceCell;
+use x86_64::instructions::interrupts;
+use x86_64::instructions::port::Port;
+use crate::interrupts::{ticks, TIMER_FREQUENCY};
+
+const CMOS_ADDRESS: u16 = 0x70;
+const CMOS_DATA: u16 = 0x71;
+/// setting this bit in the address port keeps nmis disabled while the cmos is read
+const NMI_DISABLE: u8 = 0x80;
+const REGISTER_SECONDS: u8 = 0x00;
+const REGISTER_MINUTES: u8 = 0x02;
+const REGISTER_HOURS: u8 = 0x04;
+const REGISTER_DAY: u8 = 0x07;
+const REGISTER_MONTH: u8 = 0x08;
+const REGISTER_YEAR: u8 = 0x09;
+const REGISTER_STATUS_A: u8 = 0x0A;
+const REGISTER_STATUS_B: u8 = 0x0B;
+/// status a, the clock is changing its registers
+const UPDATE_IN_PROGRESS: u8 = 0x80;
+/// status b, registers hold binary rather than bcd values
+const BINARY_MODE: u8 = 0x04;
+/// status b, hours count to 23 rather than to 12
+const HOURS_24: u8 = 0x02;
+/// set in the hours register for pm times in 12 hour mode
+const HOURS_PM: u8 = 0x80;
+
+/// seconds since the unix epoch at the tick count in the second field
+static BOOT_TIME: OnceCell<(i64, u64)> = OnceCell::uninit();
+
+/// the date and time as the clock stores it
+#[derive(Debug, Copy, Clone, PartialEq)]
+struct ClockTime {
+    seconds: u8,
+    minutes: u8,
+    hours: u8,
+    day: u8,
+    month: u8,
+    year: u8,
+}
+
+/// reads the real time clock, the time then advances with the timer
+pub fn init() {
+    BOOT_TIME.init_once(|| {
+        let time = interrupts::without_interrupts(|| unsafe { read_clock() });
+        (time, ticks())
+    });
+}
+
+/// the current time in seconds since the unix epoch
+///
+/// counts from the epoch itself if the clock was never read
+pub fn now() -> i64 {
+    let (boot_time, boot_ticks) = BOOT_TIME.get().copied().unwrap_or((0, 0));
+    boot_time + ((ticks() - boot_ticks) / TIMER_FREQUENCY as u64) as i64
+}
+
+/// days between the unix epoch and a date in the proleptic gregorian calendar
+pub fn days_from_civil(year: i64, month: i64, day: i64) -> i64 {
+    let year = if month <= 2 { year - 1 } else { year };
+    let era = year.div_euclid(400);
+    let year_of_era = year - era * 400;
+    let day_of_year = (153 * ((month + 9) % 12) + 2) / 5 + day - 1;
+    let day_of_era = year_of_era * 365 + year_of_era / 4 - year_of_era / 100 + day_of_year;
+    era * 146097 + day_of_era - 719468
+}
+
+/// reads the clock until two reads in a row agree, so no update happened in between
+unsafe fn read_clock() -> i64 {
+    let mut time = read_registers();
+    loop {
+        let again = read_registers();
+        if again == time {
+            break;
+        }
+        time = again;
+    }
+    to_unix_time(time, read_register(REGISTER_STATUS_B))
+}
+
+unsafe fn read_registers() -> ClockTime {
+    while read_register(REGISTER_STATUS_A) & UPDATE_IN_PROGRESS != 0 {}
+    ClockTime {
+        seconds: read_register(REGISTER_SECONDS),
+        minutes: read_register(REGISTER_MINUTES),
+        hours: read_register(REGISTER_HOURS),
+        day: read_register(REGISTER_DAY),
+        month: read_register(REGISTER_MONTH),
+        year: read_register(REGISTER_YEAR),
+    }
+}
+
+unsafe fn read_register(register: u8) -> u8 {
+    Port::<u8>::new(CMOS_ADDRESS).write(NMI_DISABLE | register);
+    Port::<u8>::new(CMOS_DATA).read()
+}
+
+/// converts the clock registers in the format status b describes
+///
+/// the clock only stores two digits of the year, which are taken to be in the 2000s
+fn to_unix_time(time: ClockTime, status_b: u8) -> i64 {
+    let decode = |value: u8| {
+        if status_b & BINARY_MODE != 0 { value } else { (value & 0x0F) + (value >> 4) * 10 }
+    };
+    let is_pm = time.hours & HOURS_PM != 0;
+    let mut hours = decode(time.hours & !HOURS_PM);
+    if status_b & HOURS_24 == 0 {
+        // 12 am is midnight and 12 pm is noon
+        hours = hours % 12 + if is_pm { 12 } else { 0 };
+    }
+    let days = days_from_civil(
+        2000 + decode(time.year) as i64, decode(time.month) as i64, decode(time.day) as i64
+    );
+    days * 86400
+        + hours as i64 * 3600
+        + decode(time.minutes) as i64 * 60
+        + decode(time.seconds) as i64
+}
+
+#[test_case]
+fn test_clock_to_unix_time() {
+    // 2023-07-14 13:45:30 in bcd and 24 hour mode
+    let time = ClockTime {
+        seconds: 0x30,
+        minutes: 0x45,
+        hours: 0x13,
+        day: 0x14,
+        month: 0x07,
+        year: 0x23,
+    };
+    assert_eq!(to_unix_time(time, HOURS_24), 1689342330);
+    // the same time as 1 pm in binary and 12 hour mode
+    let time = ClockTime {
+        seconds: 30,
+        minutes: 45,
+        hours: 1 | HOURS_PM,
+        day: 14,
+        month: 7,
+        year: 23,
+    };
+    assert_eq!(to_unix_time(time, BINARY_MODE), 1689342330);
+}
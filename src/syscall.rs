@@ -1,18 +1,62 @@
 mod display;
 mod process;
+mod fs;
+mod memory;
+mod poll;
+mod signal;
+mod ptrace;
 
-use core::alloc::Layout;
 use core::arch::asm;
+use core::mem::size_of;
+use core::ptr::{slice_from_raw_parts, slice_from_raw_parts_mut};
+use x86_64::instructions::interrupts;
 use x86_64::registers::model_specific::{Msr};
+use x86_64::VirtAddr;
 
 use crate::{println, serial_println, syscall};
+use crate::interrupts::trap::{trap_return, TrapFrame};
+use crate::process::address_space;
 
 const MSR_SCE: u32 = 0xC0000080;
 const IA32_STAR: u32 = 0xC0000081;
 const IA32_LSTAR: u32 = 0xC0000082;
 const IA32_FMASK: u32 = 0xC0000084;
 
-const STACK_SIZE: usize = 0x1000;
+/// kernel stack of the running process, syscall does not switch stacks on its own
+static mut KERNEL_STACK_TOP: u64 = 0;
+/// user stack pointer while the trap frame is being built, interrupts are off at that point
+static mut USER_STACK: u64 = 0;
+
+/// errors reported to user space as negative return values
+#[derive(Debug, Copy, Clone)]
+#[repr(i64)]
+pub enum SyscallError {
+    InvalidUtf8 = -1,
+    NotFound = -2,
+    BadDescriptor = -3,
+    NotADirectory = -4,
+    IsADirectory = -5,
+    DiskError = -6,
+    TooManyFiles = -7,
+    NoProcess = -8,
+    BrokenPipe = -9,
+    Interrupted = -10,
+    InvalidArgument = -11,
+    OutOfMemory = -12,
+    NotPermitted = -13,
+    AlreadyExists = -14,
+    NoSpace = -15,
+    NotEmpty = -16,
+    CrossDevice = -17,
+    /// a pointer does not refer to memory the process may access
+    BadAddress = -18,
+}
+
+impl SyscallError {
+    pub fn code(self) -> i64 {
+        self as i64
+    }
+}
 
 pub unsafe fn init() {
     // enable system call extensions
@@ -20,9 +64,10 @@ pub unsafe fn init() {
     let new_sce = sce.read() | 0b1;
     sce.write(new_sce);
 
-    // magic value for clearing interrupt flag
+    // magic value for clearing the interrupt and trap flags, so single stepping a syscall
+    // instruction does not step through the kernel
     let mut sfmask = Msr::new(IA32_FMASK);
-    sfmask.write(0x200);
+    sfmask.write(0x300);
 
     let handler_addr = syscall_wrapper as *const () as u64;
     let mut lstar = Msr::new(IA32_LSTAR);
@@ -33,81 +78,96 @@ pub unsafe fn init() {
     ia32_star_reg.write(0x23000800000000);
 }
 
+/// sets the stack syscalls run on, see gdt::set_kernel_stack for interrupts
+pub unsafe fn set_kernel_stack(stack_top: VirtAddr) {
+    KERNEL_STACK_TOP = stack_top.as_u64();
+}
+
 #[naked]
 extern "C" fn syscall_wrapper() {
     unsafe { asm!("\
-        push rcx // preserve callee-saved registers
-        push r11
-        push rbp
+        mov [rip + {user_stack}], rsp // switch to the kernel stack of the process
+        mov rsp, [rip + {kernel_stack}]
+        push 0x2b // build the frame an interrupt from ring 3 would push, selectors match IA32_STAR
+        push qword ptr [rip + {user_stack}]
+        push r11 // rflags
+        push 0x33
+        push rcx // return address
+        push 0 // no error code
+        push rax
         push rbx
+        push rcx
+        push rdx
+        push rsi
+        push rdi
+        push rbp
+        push r8
+        push r9
+        push r10
+        push r11
         push r12
         push r13
         push r14
         push r15
-        mov rbp, rsp // start new stack frame
-        push rax // preserve caller-saved registers (syscall args)
-        push rdi
-        push rsi
-        push rdx
-        push r10
-
-        call {setup_stack} // setup stack pointer
-        mov r9, rax
-
-        sti
-        pop r8 // restore caller-saved registers (syscall args)
-        pop rcx
-        pop rdx
-        pop rsi
-        pop rdi
-        mov rsp, r9 // use new stack
-        push r9 // store stack
+        mov rdi, rsp // the trap frame is the only argument
+        sub rsp, 8 // align stack
         call {syscall_handler}
-
-        cli
-        pop rdi // restore stack pointer base
-        call {free_stack}
-
-        mov rsp, rbp // restore stack
-        pop r15
-        pop r14
-        pop r13
-        pop r12
-        pop rbx
-        pop rbp // restore stack
-        pop r11
-        pop rcx
-        sysretq // return to ring 3
+        add rsp, 8
+        jmp {trap_return} // return to ring 3 with every register restored
     ",
-    setup_stack = sym setup_syscall_stack,
+    user_stack = sym USER_STACK,
+    kernel_stack = sym KERNEL_STACK_TOP,
     syscall_handler = sym syscall_handler,
-    free_stack = sym delete_syscall_stack,
+    trap_return = sym trap_return,
     options(noreturn)
     ); }
 }
 
-unsafe extern "C" fn setup_syscall_stack() -> *mut u8 {
-    let stack_layout = Layout::from_size_align_unchecked(STACK_SIZE, 0x8);
-    let syscall_stack = alloc::alloc::alloc(stack_layout);
-    
-    syscall_stack.add(stack_layout.size())
-}
-
-unsafe extern "C" fn delete_syscall_stack(stack_end_ptr: *mut u8) {
-    let stack_layout = Layout::from_size_align_unchecked(STACK_SIZE, 0x8);
-    let stack_ptr = stack_end_ptr.offset(-(stack_layout.size() as isize));
-    alloc::alloc::dealloc(stack_ptr, stack_layout);
-}
+unsafe extern "C" fn syscall_handler(frame: &mut TrapFrame) {
+    interrupts::enable();
+    let (syscall_id, arg0, arg1, arg2, arg3) = (frame.rax, frame.rdi, frame.rsi, frame.rdx, frame.r10);
 
-unsafe extern "C" fn syscall_handler(
-    syscall_id: u64, arg0: u64, arg1: u64, arg2: u64, arg3: u64, stack_addr: u64
-) -> i64 {
     // body of syscall handler
-    match syscall_id {
+    let result = match syscall_id {
         0 => display::print_vga_text(arg0, arg1),
-        1 => process::exit(arg0, stack_addr),
+        1 => process::exit(arg0),
+        2 => fs::open(arg0, arg1),
+        3 => fs::close(arg0),
+        4 => fs::read(arg0, arg1, arg2),
+        5 => fs::stat(arg0, arg1, arg2),
+        6 => fs::fstat(arg0, arg1),
+        7 => fs::getdents(arg0, arg1, arg2),
+        8 => fs::write(arg0, arg1, arg2),
+        9 => fs::pipe(arg0),
+        10 => fs::dup(arg0),
+        11 => fs::dup2(arg0, arg1),
+        12 => poll::poll(arg0, arg1, arg2),
+        13 => signal::kill(arg0, arg1),
+        14 => signal::sigaction(arg0, arg1, arg2),
+        15 => signal::sigprocmask(arg0, arg1, arg2),
+        16 => signal::sigreturn(frame),
+        17 => signal::getpid(),
+        18 => signal::set_foreground(arg0),
+        19 => memory::brk(arg0),
+        20 => memory::sbrk(arg0),
+        21 => memory::mmap(arg0, arg1, arg2, arg3),
+        22 => memory::munmap(arg0, arg1),
+        23 => memory::mprotect(arg0, arg1, arg2),
+        24 => process::arch_prctl(arg0, arg1),
+        25 => ptrace::ptrace(arg0, arg1, arg2, arg3),
+        26 => ptrace::waitpid(arg0, arg1),
+        27 => fs::create(arg0, arg1),
+        28 => fs::unlink(arg0, arg1),
+        29 => fs::ftruncate(arg0, arg1),
+        30 => fs::mkdir(arg0, arg1),
+        31 => fs::rmdir(arg0, arg1),
+        32 => fs::rename(arg0, arg1, arg2, arg3),
         _ => default_syscall(syscall_id)
-    }
+    };
+    frame.rax = result as u64;
+
+    interrupts::disable();
+    crate::process::signal::deliver(frame);
 }
 
 fn default_syscall(syscall_id: u64) -> i64 {
@@ -115,3 +175,54 @@ fn default_syscall(syscall_id: u64) -> i64 {
     0
 }
 
+/// borrows a utf8 string from user memory
+unsafe fn read_user_str<'a>(addr: u64, len: u64) -> Result<&'a str, SyscallError> {
+    core::str::from_utf8(user_slice(addr, len)?)
+        .map_err(|_| SyscallError::InvalidUtf8)
+}
+
+/// borrows count values from user memory
+///
+/// every page is made present first, so using the slice cannot fault while a lock is held
+unsafe fn user_slice<'a, T>(addr: u64, count: u64) -> Result<&'a [T], SyscallError> {
+    if count == 0 {
+        return Ok(&[]);
+    }
+    let len = count.checked_mul(size_of::<T>() as u64)
+        .ok_or(SyscallError::BadAddress)?;
+    if !address_space::populate_user_range(addr, len, false) {
+        return Err(SyscallError::BadAddress);
+    }
+    Ok(&*slice_from_raw_parts(addr as *const T, count as usize))
+}
+
+/// like user_slice for memory the syscall writes to
+unsafe fn user_slice_mut<'a, T>(addr: u64, count: u64) -> Result<&'a mut [T], SyscallError> {
+    if count == 0 {
+        return Ok(&mut []);
+    }
+    let len = count.checked_mul(size_of::<T>() as u64)
+        .ok_or(SyscallError::BadAddress)?;
+    if !address_space::populate_user_range(addr, len, true) {
+        return Err(SyscallError::BadAddress);
+    }
+    Ok(&mut *slice_from_raw_parts_mut(addr as *mut T, count as usize))
+}
+
+/// reads a value from user memory
+unsafe fn read_user<T: Copy>(addr: u64) -> Result<T, SyscallError> {
+    user_slice::<T>(addr, 1).map(|value| value[0])
+}
+
+/// writes a value to user memory
+unsafe fn write_user<T>(addr: u64, value: T) -> Result<(), SyscallError> {
+    user_slice_mut::<T>(addr, 1).map(|slot| slot[0] = value)
+}
+
+fn to_return_code(result: Result<i64, SyscallError>) -> i64 {
+    match result {
+        Ok(value) => value,
+        Err(err) => err.code(),
+    }
+}
+
@@ -0,0 +1,394 @@
+use alloc::sync::Arc;
+use core::cmp::min;
+use core::mem::size_of;
+use spin::Mutex;
+use crate::{console, print};
+use crate::process::file_table::{FileDescriptor, OpenFile};
+use crate::process::pipe::{new_pipe, PipeError};
+use crate::process::signal::SIGPIPE;
+use crate::threading::scheduler::with_current_process;
+use crate::vfs::{self, FileType, InodeRef, Metadata, VfsError};
+use super::{SyscallError, read_user_str, to_return_code, user_slice, user_slice_mut, write_user};
+
+const S_IFIFO: u32 = 0o010000;
+const S_IFCHR: u32 = 0o020000;
+const S_IFDIR: u32 = 0o040000;
+const S_IFBLK: u32 = 0o060000;
+const S_IFREG: u32 = 0o100000;
+const DT_CHR: u8 = 2;
+const DT_DIR: u8 = 4;
+const DT_BLK: u8 = 6;
+const DT_REG: u8 = 8;
+const MAX_NAME_LEN: usize = 255;
+
+/// file metadata written by stat and fstat
+#[repr(C)]
+#[derive(Debug, Copy, Clone)]
+pub struct Stat {
+    pub inode: u64,
+    pub size: u64,
+    pub mode: u32,
+    pub attributes: u32,
+    pub created: i64,
+    pub modified: i64,
+    pub accessed: i64,
+}
+
+/// directory entry written by getdents, name is nul terminated
+#[repr(C)]
+#[derive(Copy, Clone)]
+pub struct DirEntry {
+    pub inode: u64,
+    pub file_type: u8,
+    pub name_len: u8,
+    pub name: [u8; MAX_NAME_LEN + 1],
+}
+
+impl Stat {
+    /// metadata for descriptors that are not backed by the file system
+    fn anonymous(mode: u32) -> Self {
+        Self {
+            inode: 0,
+            size: 0,
+            mode,
+            attributes: 0,
+            created: 0,
+            modified: 0,
+            accessed: 0,
+        }
+    }
+
+    fn from_metadata(metadata: &Metadata) -> Self {
+        let file_type = match metadata.file_type {
+            FileType::Regular => S_IFREG,
+            FileType::Directory => S_IFDIR,
+            FileType::CharDevice => S_IFCHR,
+            FileType::BlockDevice => S_IFBLK,
+        };
+        Self {
+            inode: metadata.inode,
+            size: metadata.size,
+            mode: file_type | metadata.mode,
+            attributes: metadata.attributes,
+            created: metadata.created,
+            modified: metadata.modified,
+            accessed: metadata.accessed,
+        }
+    }
+}
+
+impl DirEntry {
+    fn from_entry(entry: &vfs::DirEntry) -> Self {
+        let name = &entry.name;
+        // long names can take more bytes in utf8, never cut one in the middle of a character
+        let mut name_len = min(name.len(), MAX_NAME_LEN);
+        while !name.is_char_boundary(name_len) {
+            name_len -= 1;
+        }
+        let mut dir_entry = Self {
+            inode: entry.inode,
+            file_type: match entry.file_type {
+                FileType::Regular => DT_REG,
+                FileType::Directory => DT_DIR,
+                FileType::CharDevice => DT_CHR,
+                FileType::BlockDevice => DT_BLK,
+            },
+            name_len: name_len as u8,
+            name: [0; MAX_NAME_LEN + 1],
+        };
+        dir_entry.name[..name_len].copy_from_slice(&name.as_bytes()[..name_len]);
+        dir_entry
+    }
+}
+
+impl From<VfsError> for SyscallError {
+    fn from(err: VfsError) -> Self {
+        match err {
+            VfsError::NotFound => SyscallError::NotFound,
+            VfsError::NotADirectory => SyscallError::NotADirectory,
+            VfsError::IsADirectory => SyscallError::IsADirectory,
+            VfsError::AlreadyExists => SyscallError::AlreadyExists,
+            VfsError::NotEmpty => SyscallError::NotEmpty,
+            VfsError::NoSpace => SyscallError::NoSpace,
+            VfsError::InvalidName | VfsError::InvalidArgument => SyscallError::InvalidArgument,
+            VfsError::Busy | VfsError::NotSupported => SyscallError::NotPermitted,
+            VfsError::CrossDevice => SyscallError::CrossDevice,
+            VfsError::Interrupted => SyscallError::Interrupted,
+            VfsError::Disk(_) => SyscallError::DiskError,
+        }
+    }
+}
+
+/// opens the file at path for reading and writing
+///
+/// returns the new file descriptor
+pub unsafe fn open(path_addr: u64, path_len: u64) -> i64 {
+    to_return_code(try_open(path_addr, path_len))
+}
+
+unsafe fn try_open(path_addr: u64, path_len: u64) -> Result<i64, SyscallError> {
+    let path = read_user_str(path_addr, path_len)?;
+    let inode = vfs::lookup(path, "/")?;
+    insert_file(inode)
+}
+
+/// creates an empty file at path and opens it, an existing file is truncated
+///
+/// returns the new file descriptor
+pub unsafe fn create(path_addr: u64, path_len: u64) -> i64 {
+    to_return_code(try_create(path_addr, path_len))
+}
+
+unsafe fn try_create(path_addr: u64, path_len: u64) -> Result<i64, SyscallError> {
+    let path = read_user_str(path_addr, path_len)?;
+    let inode = vfs::create(path, "/")?;
+    insert_file(inode)
+}
+
+/// deletes the file at path
+pub unsafe fn unlink(path_addr: u64, path_len: u64) -> i64 {
+    let result = read_user_str(path_addr, path_len)
+        .and_then(|path| vfs::remove(path, "/").map_err(SyscallError::from));
+    to_return_code(result.map(|()| 0))
+}
+
+/// creates an empty directory at path
+pub unsafe fn mkdir(path_addr: u64, path_len: u64) -> i64 {
+    let result = read_user_str(path_addr, path_len)
+        .and_then(|path| vfs::create_directory(path, "/").map_err(SyscallError::from));
+    to_return_code(result.map(|_| 0))
+}
+
+/// deletes the empty directory at path
+pub unsafe fn rmdir(path_addr: u64, path_len: u64) -> i64 {
+    let result = read_user_str(path_addr, path_len)
+        .and_then(|path| vfs::remove_directory(path, "/").map_err(SyscallError::from));
+    to_return_code(result.map(|()| 0))
+}
+
+/// moves the file or directory at the old path to the new one, replacing a file or empty
+/// directory already there
+pub unsafe fn rename(old_addr: u64, old_len: u64, new_addr: u64, new_len: u64) -> i64 {
+    let result = read_user_str(old_addr, old_len).and_then(|old_path| {
+        let new_path = read_user_str(new_addr, new_len)?;
+        vfs::rename(old_path, new_path, "/").map_err(SyscallError::from)
+    });
+    to_return_code(result.map(|()| 0))
+}
+
+/// cuts an open file down to len bytes or extends it with zeroes
+pub fn ftruncate(fd: u64, len: u64) -> i64 {
+    let result = match get_descriptor(fd) {
+        Ok(FileDescriptor::File(file)) => file.lock().inode.truncate(len)
+            .map(|()| 0)
+            .map_err(SyscallError::from),
+        Ok(_) => Err(SyscallError::InvalidArgument),
+        Err(err) => Err(err),
+    };
+    to_return_code(result)
+}
+
+fn insert_file(inode: InodeRef) -> Result<i64, SyscallError> {
+    let descriptor = FileDescriptor::File(Arc::new(Mutex::new(OpenFile {
+        file: vfs::open(&inode)?,
+        inode,
+        offset: 0,
+    })));
+
+    with_current_process(|process| process.files_mut().insert(descriptor))
+        .ok_or(SyscallError::NoProcess)?
+        .map(|fd| fd as i64)
+        .ok_or(SyscallError::TooManyFiles)
+}
+
+/// closes a file descriptor
+pub fn close(fd: u64) -> i64 {
+    let descriptor = with_current_process(|process| {
+        process.files_mut().remove(fd as usize)
+    }).flatten();
+    match descriptor {
+        Some(_) => 0,
+        None => SyscallError::BadDescriptor.code(),
+    }
+}
+
+/// reads up to len bytes from a file descriptor into buffer
+///
+/// returns the number of bytes read, 0 indicates end of file
+pub unsafe fn read(fd: u64, buffer_addr: u64, len: u64) -> i64 {
+    to_return_code(try_read(fd, buffer_addr, len))
+}
+
+unsafe fn try_read(fd: u64, buffer_addr: u64, len: u64) -> Result<i64, SyscallError> {
+    let buffer = user_slice_mut::<u8>(buffer_addr, len)?;
+    match get_descriptor(fd)? {
+        FileDescriptor::Console => console::read(buffer)
+            .map(|count| count as i64)
+            .map_err(|_| SyscallError::Interrupted),
+        FileDescriptor::File(file) => read_file(&file, buffer),
+        FileDescriptor::PipeRead(reader) => reader.read(buffer)
+            .map(|count| count as i64)
+            .map_err(|_| SyscallError::Interrupted),
+        FileDescriptor::PipeWrite(_) => Err(SyscallError::BadDescriptor),
+    }
+}
+
+fn read_file(file: &Mutex<OpenFile>, buffer: &mut [u8]) -> Result<i64, SyscallError> {
+    let mut file = file.lock();
+    let count = file.file.read(file.offset as u64, buffer)?;
+    file.offset += count;
+    Ok(count as i64)
+}
+
+/// writes len bytes from buffer to a file descriptor
+///
+/// returns the number of bytes written
+pub unsafe fn write(fd: u64, buffer_addr: u64, len: u64) -> i64 {
+    to_return_code(try_write(fd, buffer_addr, len))
+}
+
+unsafe fn try_write(fd: u64, buffer_addr: u64, len: u64) -> Result<i64, SyscallError> {
+    let bytes = user_slice::<u8>(buffer_addr, len)?;
+    match get_descriptor(fd)? {
+        FileDescriptor::Console => {
+            let string = core::str::from_utf8(bytes)
+                .map_err(|_| SyscallError::InvalidUtf8)?;
+            print!("{}", string);
+            Ok(len as i64)
+        },
+        FileDescriptor::PipeWrite(writer) => match writer.write(bytes) {
+            Ok(count) => Ok(count as i64),
+            Err(PipeError::BrokenPipe) => {
+                with_current_process(|process| process.signals_mut().raise(SIGPIPE));
+                Err(SyscallError::BrokenPipe)
+            },
+            Err(PipeError::Interrupted) => Err(SyscallError::Interrupted),
+        },
+        FileDescriptor::File(file) => write_file(&file, bytes),
+        FileDescriptor::PipeRead(_) => Err(SyscallError::BadDescriptor),
+    }
+}
+
+fn write_file(file: &Mutex<OpenFile>, bytes: &[u8]) -> Result<i64, SyscallError> {
+    let mut file = file.lock();
+    let count = file.file.write(file.offset as u64, bytes)?;
+    file.offset += count;
+    Ok(count as i64)
+}
+
+/// creates a pipe and writes its read and write descriptors to fds
+pub unsafe fn pipe(fds_addr: u64) -> i64 {
+    // checked first so the descriptors are never created for a bad address
+    let fds_slot = match user_slice_mut::<[u32; 2]>(fds_addr, 1) {
+        Ok(slot) => slot,
+        Err(err) => return err.code(),
+    };
+    let (reader, writer) = new_pipe();
+    let fds = with_current_process(|process| {
+        let files = process.files_mut();
+        let read_fd = files.insert(FileDescriptor::PipeRead(Arc::new(reader)))?;
+        match files.insert(FileDescriptor::PipeWrite(Arc::new(writer))) {
+            Some(write_fd) => Some([read_fd as u32, write_fd as u32]),
+            None => {
+                files.remove(read_fd);
+                None
+            }
+        }
+    }).flatten();
+
+    match fds {
+        Some(fds) => {
+            fds_slot[0] = fds;
+            0
+        },
+        None => SyscallError::TooManyFiles.code(),
+    }
+}
+
+/// duplicates a file descriptor into the lowest free slot
+pub fn dup(fd: u64) -> i64 {
+    let result = with_current_process(|process| {
+        let files = process.files_mut();
+        let descriptor = files.get(fd as usize)
+            .ok_or(SyscallError::BadDescriptor)?;
+        files.insert(descriptor)
+            .ok_or(SyscallError::TooManyFiles)
+    }).unwrap_or(Err(SyscallError::NoProcess));
+    to_return_code(result.map(|fd| fd as i64))
+}
+
+/// duplicates a file descriptor into new_fd, closing new_fd first if it is open
+pub fn dup2(old_fd: u64, new_fd: u64) -> i64 {
+    let result = with_current_process(|process| {
+        let files = process.files_mut();
+        let descriptor = files.get(old_fd as usize)
+            .ok_or(SyscallError::BadDescriptor)?;
+        if old_fd == new_fd {
+            return Ok(new_fd as usize);
+        }
+        files.insert_at(new_fd as usize, descriptor)
+            .ok_or(SyscallError::BadDescriptor)
+    }).unwrap_or(Err(SyscallError::NoProcess));
+    to_return_code(result.map(|fd| fd as i64))
+}
+
+/// writes metadata for the file at path into stat
+pub unsafe fn stat(path_addr: u64, path_len: u64, stat_addr: u64) -> i64 {
+    to_return_code(try_stat(path_addr, path_len, stat_addr))
+}
+
+unsafe fn try_stat(path_addr: u64, path_len: u64, stat_addr: u64) -> Result<i64, SyscallError> {
+    let path = read_user_str(path_addr, path_len)?;
+    let metadata = vfs::lookup(path, "/")?.metadata()?;
+    write_user(stat_addr, Stat::from_metadata(&metadata))?;
+    Ok(0)
+}
+
+/// writes metadata for an open file descriptor into stat
+pub unsafe fn fstat(fd: u64, stat_addr: u64) -> i64 {
+    let stat = match get_descriptor(fd) {
+        Ok(FileDescriptor::File(file)) => match file.lock().inode.metadata() {
+            Ok(metadata) => Stat::from_metadata(&metadata),
+            Err(err) => return SyscallError::from(err).code(),
+        },
+        Ok(FileDescriptor::Console) => Stat::anonymous(S_IFCHR | 0o620),
+        Ok(FileDescriptor::PipeRead(_) | FileDescriptor::PipeWrite(_)) => {
+            Stat::anonymous(S_IFIFO | 0o600)
+        },
+        Err(err) => return err.code(),
+    };
+    to_return_code(write_user(stat_addr, stat).map(|_| 0))
+}
+
+/// fills buffer with the next entries of an open directory
+///
+/// returns the number of bytes written, 0 indicates the end of the directory
+pub unsafe fn getdents(fd: u64, buffer_addr: u64, len: u64) -> i64 {
+    to_return_code(try_getdents(fd, buffer_addr, len))
+}
+
+unsafe fn try_getdents(fd: u64, buffer_addr: u64, len: u64) -> Result<i64, SyscallError> {
+    let capacity = len / size_of::<DirEntry>() as u64;
+    let buffer = user_slice_mut::<DirEntry>(buffer_addr, capacity)?;
+    let file = match get_descriptor(fd)? {
+        FileDescriptor::File(file) => file,
+        _ => return Err(SyscallError::NotADirectory),
+    };
+    let mut file = file.lock();
+    let entries = file.inode.read_dir()?;
+
+    let mut written = 0;
+    for (slot, entry) in buffer.iter_mut().zip(entries.iter().skip(file.offset)) {
+        *slot = DirEntry::from_entry(entry);
+        written += 1;
+    }
+
+    file.offset += written;
+    Ok((written * size_of::<DirEntry>()) as i64)
+}
+
+fn get_descriptor(fd: u64) -> Result<FileDescriptor, SyscallError> {
+    with_current_process(|process| process.files_mut().get(fd as usize))
+        .flatten()
+        .ok_or(SyscallError::BadDescriptor)
+}
@@ -0,0 +1,70 @@
+use crate::process::address_space::MemoryError;
+use crate::threading::scheduler::with_current_process;
+use super::{SyscallError, to_return_code};
+
+impl From<MemoryError> for SyscallError {
+    fn from(err: MemoryError) -> Self {
+        match err {
+            MemoryError::InvalidArgument => SyscallError::InvalidArgument,
+            MemoryError::OutOfMemory | MemoryError::Overlap => SyscallError::OutOfMemory,
+        }
+    }
+}
+
+/// moves the program break to addr
+///
+/// returns the new break, or the unchanged break on failure. addr 0 queries the break
+pub fn brk(addr: u64) -> i64 {
+    to_return_code(
+        with_current_process(|process| process.brk(addr) as i64)
+            .ok_or(SyscallError::NoProcess)
+    )
+}
+
+/// moves the program break by increment bytes
+///
+/// returns the previous break
+pub fn sbrk(increment: u64) -> i64 {
+    let result = with_current_process(|process| {
+        let old_brk = process.brk(0);
+        let new_brk = old_brk.wrapping_add(increment);
+        if increment == 0 || process.brk(new_brk) == new_brk {
+            Ok(old_brk as i64)
+        } else {
+            Err(SyscallError::OutOfMemory)
+        }
+    }).unwrap_or(Err(SyscallError::NoProcess));
+    to_return_code(result)
+}
+
+/// maps len bytes of zeroed anonymous memory with the given PROT_* protection and MAP_* flags
+///
+/// returns the address of the mapping
+pub fn mmap(addr: u64, len: u64, protection: u64, flags: u64) -> i64 {
+    let result = with_current_process(|process| {
+        process.mmap(addr, len, protection, flags)
+            .map(|addr| addr as i64)
+            .map_err(SyscallError::from)
+    }).unwrap_or(Err(SyscallError::NoProcess));
+    to_return_code(result)
+}
+
+/// unmaps the pages in [addr, addr + len)
+pub fn munmap(addr: u64, len: u64) -> i64 {
+    let result = with_current_process(|process| {
+        process.munmap(addr, len)
+            .map(|_| 0)
+            .map_err(SyscallError::from)
+    }).unwrap_or(Err(SyscallError::NoProcess));
+    to_return_code(result)
+}
+
+/// changes the protection of the pages in [addr, addr + len)
+pub fn mprotect(addr: u64, len: u64, protection: u64) -> i64 {
+    let result = with_current_process(|process| {
+        process.mprotect(addr, len, protection)
+            .map(|_| 0)
+            .map_err(SyscallError::from)
+    }).unwrap_or(Err(SyscallError::NoProcess));
+    to_return_code(result)
+}
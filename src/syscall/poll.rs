@@ -0,0 +1,100 @@
+use alloc::vec::Vec;
+use x86_64::instructions::interrupts;
+use crate::interrupts::{ms_to_ticks, ticks};
+use crate::process::file_table::{FileDescriptor, POLLERR, POLLHUP, POLLNVAL};
+use crate::threading::scheduler::{current_pid, signal_pending, with_current_process, SCHEDULER};
+use super::{SyscallError, user_slice_mut};
+
+/// entry of the array passed to poll
+#[repr(C)]
+#[derive(Debug, Copy, Clone)]
+pub struct PollFd {
+    pub fd: i32,
+    pub events: u16,
+    pub revents: u16,
+}
+
+/// waits until one of the descriptors in fds is ready
+///
+/// a negative timeout waits forever and a timeout of 0 returns immediately.
+/// returns the number of descriptors with events set in revents, 0 on timeout
+pub unsafe fn poll(fds_addr: u64, nfds: u64, timeout_ms: u64) -> i64 {
+    let fds = match user_slice_mut::<PollFd>(fds_addr, nfds) {
+        Ok(fds) => fds,
+        Err(err) => return err.code(),
+    };
+    let timeout_ms = timeout_ms as i64;
+    let deadline = if timeout_ms > 0 {
+        Some(ticks() + ms_to_ticks(timeout_ms as u64))
+    } else {
+        None
+    };
+
+    // user memory may fault, so it is not touched while the scheduler is locked
+    let numbers: Vec<i32> = fds.iter().map(|poll_fd| poll_fd.fd).collect();
+    let descriptors: Option<Vec<Option<FileDescriptor>>> = with_current_process(|process| {
+        numbers.iter()
+            .map(|fd| {
+                if *fd < 0 {
+                    None
+                } else {
+                    process.files_mut().get(*fd as usize)
+                }
+            })
+            .collect()
+    });
+    let descriptors = match descriptors {
+        Some(descriptors) => descriptors,
+        None => return SyscallError::NoProcess.code(),
+    };
+    let pid = match current_pid() {
+        Some(pid) => pid,
+        None => return SyscallError::NoProcess.code(),
+    };
+
+    loop {
+        interrupts::disable();
+        let ready = check_ready(fds, &descriptors);
+        let timed_out = timeout_ms == 0 || deadline.map_or(false, |tick| ticks() >= tick);
+        if ready > 0 || timed_out {
+            SCHEDULER.lock().cancel_timers(pid);
+            interrupts::enable();
+            return ready as i64;
+        }
+        if signal_pending() {
+            SCHEDULER.lock().cancel_timers(pid);
+            interrupts::enable();
+            return SyscallError::Interrupted.code();
+        }
+
+        for descriptor in descriptors.iter().flatten() {
+            descriptor.register_poller(pid);
+        }
+        {
+            let mut scheduler = SCHEDULER.lock();
+            if let Some(tick) = deadline {
+                scheduler.cancel_timers(pid);
+                scheduler.add_timer(pid, tick);
+            }
+        }
+        // interrupts are re-enabled once this task is scheduled again
+        SCHEDULER.lock().block_current();
+    }
+}
+
+/// fills in revents and returns how many descriptors have events
+fn check_ready(fds: &mut [PollFd], descriptors: &[Option<FileDescriptor>]) -> usize {
+    let mut ready = 0;
+    for (poll_fd, descriptor) in fds.iter_mut().zip(descriptors) {
+        poll_fd.revents = match descriptor {
+            _ if poll_fd.fd < 0 => 0,
+            // errors and hangups are reported even when not requested
+            Some(descriptor) => descriptor.poll() & (poll_fd.events | POLLERR | POLLHUP),
+            None => POLLNVAL,
+        };
+        if poll_fd.revents != 0 {
+            ready += 1;
+        }
+    }
+    ready
+}
@@ -1,10 +1,38 @@
-use core::arch::asm;
-use crate::threading::scheduler::SCHEDULER;
+use x86_64::registers::model_specific::FsBase;
+use x86_64::VirtAddr;
+use crate::threading::scheduler::{with_current_process, SCHEDULER};
+use super::{SyscallError, to_return_code, write_user};
+
+const ARCH_SET_FS: u64 = 0x1002;
+const ARCH_GET_FS: u64 = 0x1003;
+/// end of the lower canonical half, the fs base must lie below it
+const USER_ADDRESS_END: u64 = 0x0000_8000_0000_0000;
 
 /// exits process
-pub unsafe fn exit(exit_code: u64, stack_addr: u64) -> ! {
-    super::delete_syscall_stack(stack_addr as *mut u8);
-    SCHEDULER.lock().end_current_task();
+pub unsafe fn exit(exit_code: u64) -> ! {
+    SCHEDULER.lock().end_current_task(exit_code as i64)
+}
 
-    unreachable!()
-}
\ No newline at end of file
+/// sets the fs base of the current process to addr, or writes it to addr for ARCH_GET_FS
+pub unsafe fn arch_prctl(code: u64, addr: u64) -> i64 {
+    match code {
+        ARCH_SET_FS => {
+            if addr >= USER_ADDRESS_END {
+                return SyscallError::InvalidArgument.code();
+            }
+            if with_current_process(|process| process.set_fs_base(addr)).is_none() {
+                return SyscallError::NoProcess.code();
+            }
+            FsBase::write(VirtAddr::new(addr));
+            0
+        },
+        ARCH_GET_FS => {
+            let fs_base = match with_current_process(|process| process.fs_base()) {
+                Some(fs_base) => fs_base,
+                None => return SyscallError::NoProcess.code(),
+            };
+            to_return_code(write_user(addr, fs_base).map(|_| 0))
+        },
+        _ => SyscallError::InvalidArgument.code(),
+    }
+}
@@ -0,0 +1,176 @@
+use x86_64::instructions::interrupts;
+use crate::console;
+use crate::elf::core_file::REGISTER_COUNT;
+use crate::process::ptrace::{self, PtraceError};
+use crate::process::signal::{self, SIGSTOP};
+use crate::threading::scheduler::{self, current_pid, with_process, PID, SCHEDULER};
+use super::{SyscallError, read_user, write_user};
+
+const PTRACE_PEEKDATA: u64 = 2;
+const PTRACE_POKEDATA: u64 = 5;
+const PTRACE_CONT: u64 = 7;
+const PTRACE_SINGLESTEP: u64 = 9;
+const PTRACE_GETREGS: u64 = 12;
+const PTRACE_SETREGS: u64 = 13;
+const PTRACE_ATTACH: u64 = 16;
+const PTRACE_DETACH: u64 = 17;
+
+/// low byte of a wait status for a stopped process, the signal sits above it
+const WAIT_STOPPED: u64 = 0x7f;
+/// a wait status holds the low byte of an exit status above a low byte of 0
+const WAIT_EXIT_SHIFT: u64 = 8;
+
+impl From<PtraceError> for SyscallError {
+    fn from(err: PtraceError) -> Self {
+        match err {
+            PtraceError::NotTraced | PtraceError::NotStopped | PtraceError::NoProcess =>
+                SyscallError::NoProcess,
+            PtraceError::AlreadyTraced => SyscallError::NotPermitted,
+            PtraceError::BadAddress => SyscallError::InvalidArgument,
+        }
+    }
+}
+
+/// debugs the process pid on behalf of the current process
+///
+/// a tracee stops whenever it receives a signal, including SIGTRAP from int3 and single steps,
+/// and stays stopped until its tracer continues it. registers and memory can only be accessed
+/// while it is stopped. PEEKDATA writes the word at addr to data
+pub unsafe fn ptrace(request: u64, pid: u64, addr: u64, data: u64) -> i64 {
+    let tracer = match current_pid() {
+        Some(tracer) => tracer,
+        None => return SyscallError::NoProcess.code(),
+    };
+    let pid = PID::new(pid);
+    if pid == tracer {
+        return SyscallError::NotPermitted.code();
+    }
+
+    // tracer memory is only touched outside the scheduler lock
+    let result = match request {
+        PTRACE_ATTACH => attach(tracer, pid),
+        PTRACE_DETACH => interrupts::without_interrupts(|| {
+            let mut scheduler = SCHEDULER.lock();
+            let process = scheduler.process_mut(pid).ok_or(SyscallError::NoProcess)?;
+            if process.tracer() != Some(tracer) {
+                return Err(SyscallError::NoProcess);
+            }
+            if process.detach() {
+                scheduler.resume(pid);
+            }
+            Ok(())
+        }),
+        PTRACE_CONT | PTRACE_SINGLESTEP => resume(tracer, pid, data, request == PTRACE_SINGLESTEP),
+        PTRACE_PEEKDATA => ptrace::peek(tracer, pid, addr)
+            .map_err(SyscallError::from)
+            .and_then(|word| write_user(data, word)),
+        PTRACE_POKEDATA => ptrace::poke(tracer, pid, addr, data).map_err(SyscallError::from),
+        PTRACE_GETREGS => with_process(pid, |process| process.traced_registers(tracer))
+            .ok_or(SyscallError::NoProcess)
+            .and_then(|registers| registers.map_err(SyscallError::from))
+            .and_then(|registers| write_user(data, registers)),
+        PTRACE_SETREGS => read_user::<[u64; REGISTER_COUNT]>(data).and_then(|registers| {
+            with_process(pid, |process| process.set_traced_registers(tracer, &registers))
+                .ok_or(SyscallError::NoProcess)
+                .and_then(|result| result.map_err(SyscallError::from))
+        }),
+        _ => Err(SyscallError::InvalidArgument),
+    };
+    match result {
+        Ok(()) => 0,
+        Err(err) => err.code(),
+    }
+}
+
+/// starts tracing pid and stops it so the tracer can look at it
+fn attach(tracer: PID, pid: PID) -> Result<(), SyscallError> {
+    interrupts::without_interrupts(|| {
+        let mut scheduler = SCHEDULER.lock();
+        let task = scheduler.task_mut(pid).ok_or(SyscallError::NoProcess)?;
+        if !may_trace(tracer, task.parent(), console::session_leader()) {
+            return Err(SyscallError::NotPermitted);
+        }
+        task.process_mut().attach(tracer)?;
+        scheduler.send_signal(pid, SIGSTOP)
+            .map_err(|_| SyscallError::NoProcess)
+    })
+}
+
+/// true if tracer may debug a process with the given parent
+///
+/// the parent of a process may trace it, and so may the session leader, as the kernel starts
+/// the processes a shell runs and they have no parent of their own
+fn may_trace(tracer: PID, parent: Option<PID>, session_leader: PID) -> bool {
+    parent == Some(tracer) || tracer == session_leader
+}
+
+/// continues a stopped tracee with the signal in data, or none if it is 0
+fn resume(tracer: PID, pid: PID, data: u64, single_step: bool) -> Result<(), SyscallError> {
+    if data != 0 && !signal::is_valid(data) {
+        return Err(SyscallError::InvalidArgument);
+    }
+    interrupts::without_interrupts(|| {
+        let mut scheduler = SCHEDULER.lock();
+        scheduler.process_mut(pid)
+            .ok_or(SyscallError::NoProcess)?
+            .resume_traced(tracer, data as u8, single_step)?;
+        scheduler.resume(pid);
+        Ok(())
+    })
+}
+
+/// waits until the tracee pid stops or exits and writes its wait status to status_addr
+///
+/// returns pid. an exit is reported once, after that the tracee is gone and NoProcess is
+/// returned. status_addr may be 0 to skip it
+pub unsafe fn waitpid(pid: u64, status_addr: u64) -> i64 {
+    let tracer = match current_pid() {
+        Some(tracer) => tracer,
+        None => return SyscallError::NoProcess.code(),
+    };
+    let pid = PID::new(pid);
+    loop {
+        interrupts::disable();
+        let status = match with_process(pid, |process| process.take_trace_stop(tracer)) {
+            Some(Ok(stop)) => Ok(stop.map(|signal| ((signal as u64) << 8) | WAIT_STOPPED)),
+            Some(Err(err)) => Err(SyscallError::from(err)),
+            // a finished tracee is only known by the exit status it left for its tracer
+            None => SCHEDULER.lock().take_traced_exit(tracer, pid)
+                .map(|exit_status| Some(((exit_status as u64) & 0xff) << WAIT_EXIT_SHIFT))
+                .ok_or(SyscallError::NoProcess),
+        };
+        let status = match status {
+            Ok(status) => status,
+            Err(err) => {
+                interrupts::enable();
+                return err.code();
+            },
+        };
+        if let Some(status) = status {
+            interrupts::enable();
+            if status_addr != 0 {
+                if let Err(err) = write_user(status_addr, status) {
+                    return err.code();
+                }
+            }
+            return pid.as_u64() as i64;
+        }
+        if scheduler::signal_pending() {
+            interrupts::enable();
+            return SyscallError::Interrupted.code();
+        }
+        // the tracee wakes this task when it stops or exits, interrupts are re-enabled once
+        // this task is scheduled again
+        SCHEDULER.lock().block_current();
+    }
+}
+
+#[test_case]
+fn test_may_trace() {
+    let (shell, child, other) = (PID::new(1), PID::new(3), PID::new(5));
+    // the session leader may attach to a process the kernel started
+    assert!(may_trace(shell, None, shell));
+    assert!(may_trace(child, Some(child), shell));
+    assert!(!may_trace(other, Some(child), shell));
+    assert!(!may_trace(other, None, shell));
+}
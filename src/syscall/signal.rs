@@ -0,0 +1,155 @@
+use x86_64::instructions::interrupts;
+use crate::console;
+use crate::interrupts::trap::TrapFrame;
+use crate::process::signal::{self, SigAction};
+use crate::threading::scheduler::{current_pid, with_current_process, with_task, PID, SCHEDULER};
+use super::{SyscallError, read_user, to_return_code, user_slice_mut};
+
+const SIG_BLOCK: u64 = 0;
+const SIG_UNBLOCK: u64 = 1;
+const SIG_SETMASK: u64 = 2;
+
+/// sends a signal to the process with the given pid
+///
+/// signal 0 only checks that the process exists
+pub fn kill(pid: u64, signal: u64) -> i64 {
+    if signal != 0 && !signal::is_valid(signal) {
+        return SyscallError::InvalidArgument.code();
+    }
+    let result = interrupts::without_interrupts(|| {
+        SCHEDULER.lock().send_signal(PID::new(pid), signal as u8)
+    });
+    match result {
+        Ok(()) => 0,
+        Err(_) => SyscallError::NoProcess.code(),
+    }
+}
+
+/// installs the action at action_addr for a signal and writes the previous one to old_addr
+///
+/// either address may be 0 to skip it
+pub unsafe fn sigaction(signal: u64, action_addr: u64, old_addr: u64) -> i64 {
+    if !signal::is_valid(signal) {
+        return SyscallError::InvalidArgument.code();
+    }
+    let signal = signal as u8;
+    // both addresses are checked before anything changes
+    let old_slot = match old_addr {
+        0 => None,
+        addr => match user_slice_mut::<SigAction>(addr, 1) {
+            Ok(slot) => Some(slot),
+            Err(err) => return err.code(),
+        },
+    };
+    let action = if action_addr != 0 {
+        match read_user::<SigAction>(action_addr) {
+            Ok(action) => Some(action),
+            Err(err) => return err.code(),
+        }
+    } else {
+        None
+    };
+
+    let result = with_current_process(|process| {
+        let signals = process.signals_mut();
+        let old_action = signals.action(signal);
+        if let Some(action) = action {
+            signals.set_action(signal, action)
+                .map_err(|_| SyscallError::InvalidArgument)?;
+        }
+        Ok(old_action)
+    }).unwrap_or(Err(SyscallError::NoProcess));
+
+    match result {
+        Ok(old_action) => {
+            if let Some(slot) = old_slot {
+                slot[0] = old_action;
+            }
+            0
+        },
+        Err(err) => err.code(),
+    }
+}
+
+/// changes the blocked signal mask according to how and writes the previous mask to old_addr
+///
+/// either address may be 0 to skip it
+pub unsafe fn sigprocmask(how: u64, set_addr: u64, old_addr: u64) -> i64 {
+    let old_slot = match old_addr {
+        0 => None,
+        addr => match user_slice_mut::<u64>(addr, 1) {
+            Ok(slot) => Some(slot),
+            Err(err) => return err.code(),
+        },
+    };
+    let set = if set_addr != 0 {
+        match read_user::<u64>(set_addr) {
+            Ok(set) => Some(set),
+            Err(err) => return err.code(),
+        }
+    } else {
+        None
+    };
+
+    let result = with_current_process(|process| {
+        let signals = process.signals_mut();
+        let old_mask = signals.blocked();
+        if let Some(set) = set {
+            let mask = match how {
+                SIG_BLOCK => old_mask | set,
+                SIG_UNBLOCK => old_mask & !set,
+                SIG_SETMASK => set,
+                _ => return Err(SyscallError::InvalidArgument),
+            };
+            signals.set_blocked(mask);
+        }
+        Ok(old_mask)
+    }).unwrap_or(Err(SyscallError::NoProcess));
+
+    match result {
+        Ok(old_mask) => {
+            if let Some(slot) = old_slot {
+                slot[0] = old_mask;
+            }
+            0
+        },
+        Err(err) => err.code(),
+    }
+}
+
+/// returns from a signal handler to the context it interrupted
+///
+/// returns the restored rax so the interrupted syscall result survives
+pub unsafe fn sigreturn(frame: &mut TrapFrame) -> i64 {
+    if signal::restore_signal_frame(frame).is_err() {
+        // the saved context is gone, there is nothing sensible to return to
+        SCHEDULER.lock().end_current_task(128 + signal::SIGSEGV as i64);
+    }
+    frame.rax as i64
+}
+
+pub fn getpid() -> i64 {
+    to_return_code(
+        current_pid()
+            .map(|pid| pid.as_u64() as i64)
+            .ok_or(SyscallError::NoProcess)
+    )
+}
+
+/// makes pid the process that receives SIGINT when Ctrl-C is pressed
+///
+/// only the session leader may choose any process, others can only pick their own children
+pub fn set_foreground(pid: u64) -> i64 {
+    to_return_code(try_set_foreground(PID::new(pid)))
+}
+
+fn try_set_foreground(pid: PID) -> Result<i64, SyscallError> {
+    let caller = current_pid().ok_or(SyscallError::NoProcess)?;
+    let parent = with_task(pid, |task| task.parent())
+        .ok_or(SyscallError::NoProcess)?;
+    if caller != console::session_leader() && parent != Some(caller) {
+        return Err(SyscallError::NotPermitted);
+    }
+    console::set_foreground(pid);
+    Ok(0)
+}
@@ -1,2 +1,3 @@
 pub mod thread;
 pub mod scheduler;
+pub mod wait_queue;
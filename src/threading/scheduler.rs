@@ -1,13 +1,27 @@
 use alloc::collections::linked_list::{CursorMut, Iter, IterMut};
 use alloc::collections::{LinkedList, VecDeque};
+use alloc::vec::Vec;
 use conquer_once::spin::OnceCell;
+use crossbeam_queue::ArrayQueue;
 use lazy_static::lazy_static;
 use spin::{Lazy, Mutex, MutexGuard};
 use spin::mutex::SpinMutexGuard;
+use x86_64::instructions::interrupts;
 use crate::{hlt_loop, println};
 use crate::process::Process;
+use crate::process::signal::{SIGCONT, SIGKILL, SIGSTOP};
 
 const QUANTUM: u32 = 20; // timer ticks or about 18.63 ms
+const WAKEUP_QUEUE_SIZE: usize = 100;
+const SIGNAL_QUEUE_SIZE: usize = 32;
+
+/// tasks woken since the last scheduling decision
+///
+/// lock free so that interrupt handlers can wake tasks while the scheduler is held
+static WAKEUPS: Lazy<ArrayQueue<PID>> = Lazy::new(|| ArrayQueue::new(WAKEUP_QUEUE_SIZE));
+
+/// signals raised by interrupt handlers, delivered to their tasks on the next scheduling decision
+static SIGNALS: Lazy<ArrayQueue<(PID, u8)>> = Lazy::new(|| ArrayQueue::new(SIGNAL_QUEUE_SIZE));
 
 pub static SCHEDULER: Mutex<Scheduler> = {
     let tasks = VecDeque::new();
@@ -16,7 +30,9 @@ pub static SCHEDULER: Mutex<Scheduler> = {
         current_task_ticks: 0,
         next_task_id: 0,
         current_task: usize::MAX-1,
-        is_enabled: false
+        is_enabled: false,
+        timers: Vec::new(),
+        traced_exits: Vec::new(),
     };
     Mutex::new(scheduler)
 };
@@ -27,11 +43,15 @@ pub struct Scheduler {
     current_task_ticks: u32,
     next_task_id: u64,
     current_task: usize,
-    is_enabled: bool
+    is_enabled: bool,
+    /// pids to wake and the tick to wake them at
+    timers: Vec<(PID, u64)>,
+    /// tracer, tracee and exit status of tracees that finished before their tracer waited
+    traced_exits: Vec<(PID, PID, i64)>,
 }
 
 #[repr(transparent)]
-#[derive(Clone, Copy, PartialEq)]
+#[derive(Debug, Clone, Copy, PartialEq)]
 pub struct PID(u64);
 
 #[derive(Debug, Clone, Copy)]
@@ -39,6 +59,8 @@ pub enum TaskState {
     READY,
     RUNNING,
     WAITING,
+    /// stopped by a signal until SIGCONT
+    STOPPED,
     DONE,
 }
 
@@ -46,6 +68,33 @@ pub struct Task {
     state: TaskState,
     process: Process,
     pid: PID,
+    /// the task that was running when this one was added, None for tasks the kernel starts
+    parent: Option<PID>,
+    exit_status: Option<i64>,
+}
+
+impl PID {
+    pub fn new(pid: u64) -> Self {
+        Self(pid)
+    }
+
+    pub fn as_u64(self) -> u64 {
+        self.0
+    }
+}
+
+impl Task {
+    pub fn state(&self) -> TaskState {
+        self.state
+    }
+
+    pub fn parent(&self) -> Option<PID> {
+        self.parent
+    }
+
+    pub fn process_mut(&mut self) -> &mut Process {
+        &mut self.process
+    }
 }
 
 pub enum TaskKillError {
@@ -63,6 +112,8 @@ impl Scheduler {
         }
 
         self.current_task_ticks += 1;
+        self.process_timers();
+        self.process_signals();
 
         if self.current_task_ticks >= QUANTUM {
             // preempt the process
@@ -74,6 +125,55 @@ impl Scheduler {
         }
     }
 
+    pub fn current_pid(&self) -> Option<PID> {
+        self.tasks.get(self.current_task)
+            .map(|task| task.pid)
+    }
+
+    /// returns the process of the currently executing task
+    pub fn current_process_mut(&mut self) -> Option<&mut Process> {
+        self.tasks.get_mut(self.current_task)
+            .map(|task| &mut task.process)
+    }
+
+    /// returns the process of a task that has not finished
+    pub fn process_mut(&mut self, pid: PID) -> Option<&mut Process> {
+        self.task_mut(pid)
+            .map(|task| &mut task.process)
+    }
+
+    /// returns a task that has not finished
+    pub fn task_mut(&mut self, pid: PID) -> Option<&mut Task> {
+        self.tasks.iter_mut()
+            .find(|task| task.pid == pid && !matches!(task.state, TaskState::DONE))
+    }
+
+    /// pids of the tasks that have not finished, in scheduling order
+    pub fn pids(&self) -> Vec<PID> {
+        self.tasks.iter()
+            .filter(|task| !matches!(task.state, TaskState::DONE))
+            .map(|task| task.pid)
+            .collect()
+    }
+
+    /// the exit status of a tracee of tracer that has finished, which is only returned once
+    pub fn take_traced_exit(&mut self, tracer: PID, pid: PID) -> Option<i64> {
+        let index = self.traced_exits.iter()
+            .position(|(exit_tracer, exit_pid, _)| *exit_tracer == tracer && *exit_pid == pid)?;
+        Some(self.traced_exits.swap_remove(index).2)
+    }
+
+    /// lets a stopped task run again
+    pub fn resume(&mut self, pid: PID) {
+        let task = self.tasks.iter_mut()
+            .find(|task| task.pid == pid);
+        if let Some(task) = task {
+            if matches!(task.state, TaskState::STOPPED) {
+                task.state = TaskState::READY;
+            }
+        }
+    }
+
     /// sets currently executing task to BLOCKED and moves to next one
     pub fn block_current(&mut self) {
         if let Some(task) = self.tasks.get_mut(self.current_task) {
@@ -82,6 +182,14 @@ impl Scheduler {
         unsafe { self.swap_tasks(); }
     }
 
+    /// sets currently executing task to STOPPED and moves to next one
+    pub fn stop_current(&mut self) {
+        if let Some(task) = self.tasks.get_mut(self.current_task) {
+            task.state = TaskState::STOPPED;
+        }
+        unsafe { self.swap_tasks(); }
+    }
+
     unsafe fn swap_tasks(&mut self) {
         if let Some(current_task) = self.tasks.get_mut(self.current_task) {
             let is_resumed = current_task.process.deactivate();
@@ -100,30 +208,139 @@ impl Scheduler {
     }
 
     fn get_next_task(&mut self) -> usize {
-        if self.tasks.is_empty() {
-            return usize::MAX;
-        }
-
         loop {
-            self.current_task += 1;
-            if self.current_task >= self.tasks.len() {
-                self.current_task = 0;
+            self.process_timers();
+            self.process_signals();
+            self.process_wakeups();
+            self.remove_finished();
+            if self.tasks.is_empty() {
+                return usize::MAX;
             }
 
-            if let Some(task) = self.tasks.get(self.current_task) {
-                match task.state {
-                    TaskState::DONE =>  {
-                        self.tasks.remove(self.current_task);
-                        self.current_task -= 1;
-                    },
-                    TaskState::WAITING => continue,
+            for _ in 0..self.tasks.len() {
+                self.current_task = self.current_task.wrapping_add(1);
+                if self.current_task >= self.tasks.len() {
+                    self.current_task = 0;
+                }
+
+                match self.tasks[self.current_task].state {
+                    TaskState::WAITING | TaskState::STOPPED => continue,
                     TaskState::RUNNING => panic!("Process falsely claims to be running"),
-                    TaskState::READY => return self.current_task
-                };
+                    TaskState::READY => return self.current_task,
+                    // only the task that just finished is left in the queue
+                    TaskState::DONE => continue,
+                }
+            }
+
+            // every task is blocked so sleep until an interrupt wakes one
+            interrupts::enable_and_hlt();
+            interrupts::disable();
+        }
+    }
+
+    /// drops finished tasks except the current one, whose kernel stack is still in use
+    fn remove_finished(&mut self) {
+        let mut index = 0;
+        while index < self.tasks.len() {
+            if index != self.current_task && matches!(self.tasks[index].state, TaskState::DONE) {
+                self.tasks.remove(index);
+                if index < self.current_task {
+                    self.current_task -= 1;
+                }
+            } else {
+                index += 1;
+            }
+        }
+    }
+
+    /// wakes pid once the timer reaches tick
+    pub fn add_timer(&mut self, pid: PID, tick: u64) {
+        self.timers.push((pid, tick));
+    }
+
+    pub fn cancel_timers(&mut self, pid: PID) {
+        self.timers.retain(|(timer_pid, _)| *timer_pid != pid);
+    }
+
+    fn process_timers(&mut self) {
+        let now = crate::interrupts::ticks();
+        self.timers.retain(|(pid, tick)| {
+            if *tick <= now {
+                wake(*pid);
+                false
+            } else {
+                true
+            }
+        });
+    }
+
+    /// moves tasks woken by wake from WAITING to READY
+    fn process_wakeups(&mut self) {
+        while let Some(pid) = WAKEUPS.pop() {
+            let task = self.tasks.iter_mut()
+                .find(|task| task.pid == pid);
+            if let Some(task) = task {
+                if matches!(task.state, TaskState::WAITING) {
+                    task.state = TaskState::READY;
+                }
             }
         }
     }
 
+    /// sends signals queued by interrupt handlers
+    fn process_signals(&mut self) {
+        while let Some((pid, signal)) = SIGNALS.pop() {
+            // the task may have exited since
+            let _ = self.send_signal(pid, signal);
+        }
+    }
+
+    /// makes a signal pending for pid
+    ///
+    /// stops, continues and kills take effect immediately, other signals wake the task so that
+    /// blocking syscalls return and the signal is delivered on the way back to user mode.
+    /// signal 0 only checks that the task exists
+    pub fn send_signal(&mut self, pid: PID, signal: u8) -> Result<(), TaskKillError> {
+        let is_current = self.current_pid() == Some(pid);
+        let task = self.tasks.iter_mut()
+            .find(|task| task.pid == pid && !matches!(task.state, TaskState::DONE))
+            .ok_or(TaskKillError::DoesNotExist)?;
+        if signal == 0 {
+            return Ok(());
+        }
+
+        if signal == SIGCONT && matches!(task.state, TaskState::STOPPED) {
+            // a stopped task was either waiting or about to return to user mode
+            task.state = TaskState::READY;
+        }
+        if is_current {
+            // delivered when the current trap returns to user mode
+            task.process.signals_mut().raise(signal);
+            return Ok(());
+        }
+
+        match signal {
+            SIGKILL => {
+                task.state = TaskState::DONE;
+                task.exit_status = Some(128 + SIGKILL as i64);
+                if let Some(tracer) = task.process.tracer() {
+                    self.traced_exits.push((tracer, pid, 128 + SIGKILL as i64));
+                    wake(tracer);
+                }
+            },
+            // a traced task stops on its way to user mode, where its tracer can inspect it
+            SIGSTOP if task.process.tracer().is_none() => task.state = TaskState::STOPPED,
+            _ => {
+                let signals = task.process.signals_mut();
+                signals.raise(signal);
+                if signals.is_handled(signal) && signals.has_deliverable() {
+                    wake(pid);
+                }
+            },
+        }
+        Ok(())
+    }
+
     /// adds task to scheduler queue
     ///
     /// returns *unique* process id
@@ -133,7 +350,9 @@ impl Scheduler {
         let task = Task {
             process,
             state: TaskState::READY,
-            pid
+            pid,
+            parent: self.current_pid(),
+            exit_status: None,
         };
         self.tasks.push_back(task);
         self.next_task_id += 1;
@@ -153,14 +372,89 @@ impl Scheduler {
 
     /// removes current task and proceeds to the next task
     ///
-    /// cleans the memory used by the process
-    pub fn end_current_task(&mut self) -> ! {
+    /// cleans the memory used by the process once another task is running
+    pub fn end_current_task(&mut self, exit_status: i64) -> ! {
         let task = self.tasks.get_mut(self.current_task)
             .expect("failed to get current task");
         task.state = TaskState::DONE;
+        task.exit_status = Some(exit_status);
+        let pid = task.pid;
+        if let Some(tracer) = task.process.tracer() {
+            self.traced_exits.push((tracer, pid, exit_status));
+            wake(tracer);
+        }
+        // exits nobody is left to wait for are forgotten
+        self.traced_exits.retain(|(tracer, _, _)| *tracer != pid);
+        // tracees of an exiting tracer carry on untraced
+        for task in self.tasks.iter_mut() {
+            if task.process.tracer() == Some(pid) && task.process.detach() {
+                task.state = TaskState::READY;
+            }
+        }
         unsafe { self.swap_tasks() }
 
         // in case no tasks are left wait for a timer interrupt to bail out
         hlt_loop();
     }
 }
+
+/// wakes a blocked task
+///
+/// safe to call from interrupt handlers and while the scheduler is locked
+pub fn wake(pid: PID) {
+    if WAKEUPS.push(pid).is_err() {
+        println!("WARNING: wakeup queue is full; wakeup dropped");
+    }
+}
+
+/// sends a signal from an interrupt handler, see Scheduler::send_signal
+pub fn queue_signal(pid: PID, signal: u8) {
+    if SIGNALS.push((pid, signal)).is_err() {
+        println!("WARNING: signal queue is full; signal dropped");
+    }
+}
+
+/// true if the current task has a signal to handle, blocking operations should give up
+pub fn signal_pending() -> bool {
+    interrupts::without_interrupts(|| {
+        SCHEDULER.lock()
+            .current_process_mut()
+            .map_or(false, |process| process.signals_mut().has_deliverable())
+    })
+}
+
+/// returns the pid of the currently executing task
+pub fn current_pid() -> Option<PID> {
+    interrupts::without_interrupts(|| SCHEDULER.lock().current_pid())
+}
+
+/// runs a closure on the currently executing process
+///
+/// interrupts are disabled for the duration so the timer cannot deadlock on the scheduler
+pub fn with_current_process<R>(f: impl FnOnce(&mut Process) -> R) -> Option<R> {
+    interrupts::without_interrupts(|| {
+        let mut scheduler = SCHEDULER.lock();
+        scheduler.current_process_mut().map(f)
+    })
+}
+
+/// pids of the tasks that have not finished
+pub fn pids() -> Vec<PID> {
+    interrupts::without_interrupts(|| SCHEDULER.lock().pids())
+}
+
+/// runs a closure on a task that has not finished
+pub fn with_task<R>(pid: PID, f: impl FnOnce(&mut Task) -> R) -> Option<R> {
+    interrupts::without_interrupts(|| {
+        let mut scheduler = SCHEDULER.lock();
+        scheduler.task_mut(pid).map(f)
+    })
+}
+
+/// runs a closure on the process of a task that has not finished
+pub fn with_process<R>(pid: PID, f: impl FnOnce(&mut Process) -> R) -> Option<R> {
+    interrupts::without_interrupts(|| {
+        let mut scheduler = SCHEDULER.lock();
+        scheduler.process_mut(pid).map(f)
+    })
+}
@@ -0,0 +1,36 @@
+use alloc::vec::Vec;
+use crate::threading::scheduler::{self, PID};
+
+/// returned by blocking operations that gave up because a signal is pending
+#[derive(Debug)]
+pub struct Interrupted;
+
+/// tasks blocked until some event occurs
+pub struct WaitQueue {
+    waiters: Vec<PID>,
+}
+
+impl WaitQueue {
+    pub const fn new() -> Self {
+        Self {
+            waiters: Vec::new(),
+        }
+    }
+
+    /// adds a task to the queue
+    ///
+    /// the task should block afterwards with interrupts still disabled so that a wakeup
+    /// cannot be lost between registering and blocking
+    pub fn register(&mut self, pid: PID) {
+        if !self.waiters.contains(&pid) {
+            self.waiters.push(pid);
+        }
+    }
+
+    /// wakes every task in the queue
+    pub fn wake_all(&mut self) {
+        for pid in self.waiters.drain(..) {
+            scheduler::wake(pid);
+        }
+    }
+}
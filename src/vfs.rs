@@ -0,0 +1,493 @@
+pub mod devfs;
+pub mod procfs;
+pub mod tmpfs;
+
+use alloc::boxed::Box;
+use alloc::string::String;
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+use core::any::Any;
+use core::fmt::{Debug, Formatter};
+use spin::Mutex;
+use crate::disk::DiskAccessError;
+
+/// file systems mounted at absolute paths, the one at "/" is mounted first
+static MOUNTS: Mutex<Vec<Mount>> = Mutex::new(Vec::new());
+
+pub type InodeRef = Arc<dyn Inode>;
+
+#[derive(Debug)]
+pub enum VfsError {
+    NotFound,
+    NotADirectory,
+    IsADirectory,
+    AlreadyExists,
+    /// a directory to be removed or replaced still has entries
+    NotEmpty,
+    NoSpace,
+    /// the name cannot be stored by the file system
+    InvalidName,
+    InvalidArgument,
+    /// mount points and the root cannot be removed or moved
+    Busy,
+    /// a rename between different file systems
+    CrossDevice,
+    /// the file system does not support the operation
+    NotSupported,
+    /// a signal arrived while waiting for a device
+    Interrupted,
+    Disk(DiskAccessError),
+}
+
+impl From<DiskAccessError> for VfsError {
+    fn from(err: DiskAccessError) -> Self {
+        VfsError::Disk(err)
+    }
+}
+
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum FileType {
+    Regular,
+    Directory,
+    CharDevice,
+    BlockDevice,
+}
+
+/// what stat reports about an inode
+#[derive(Debug, Copy, Clone)]
+pub struct Metadata {
+    /// unique within the file system
+    pub inode: u64,
+    pub file_type: FileType,
+    pub size: u64,
+    /// permission bits
+    pub mode: u32,
+    /// attributes in the format of the file system, such as FAT attribute bits
+    pub attributes: u32,
+    /// times in seconds since the unix epoch
+    pub created: i64,
+    pub modified: i64,
+    pub accessed: i64,
+}
+
+/// an entry of a directory as listed by read_dir
+#[derive(Debug, Clone)]
+pub struct DirEntry {
+    pub name: String,
+    pub inode: u64,
+    pub file_type: FileType,
+}
+
+/// a file system driver instance that can be mounted
+pub trait FileSystem: Send + Sync {
+    /// name of the driver, such as "fat32"
+    fn name(&self) -> &'static str;
+    fn root(&self) -> InodeRef;
+
+    /// true if both names find the same entry of a directory, mount points below the file
+    /// system are matched this way too
+    fn same_name(&self, a: &str, b: &str) -> bool {
+        a == b
+    }
+}
+
+/// a file, directory or device within a file system
+///
+/// directory operations fail with NotADirectory and data operations with IsADirectory unless
+/// a driver implements them. read_at also loads pages of mapped executables from the page
+/// fault handler, which runs with interrupts enabled like a syscall when user code faults
+pub trait Inode: Send + Sync {
+    fn metadata(&self) -> Result<Metadata, VfsError>;
+
+    /// lets a driver recognize its own inodes, such as the target directory of a rename
+    fn as_any(&self) -> &dyn Any;
+
+    /// finds the entry name in this directory, "." and ".." are handled by the vfs
+    fn lookup(&self, _name: &str) -> Result<InodeRef, VfsError> {
+        Err(VfsError::NotADirectory)
+    }
+
+    fn read_dir(&self) -> Result<Vec<DirEntry>, VfsError> {
+        Err(VfsError::NotADirectory)
+    }
+
+    /// creates an empty file or directory called name in this directory
+    fn create(&self, _name: &str, _file_type: FileType) -> Result<InodeRef, VfsError> {
+        Err(VfsError::NotADirectory)
+    }
+
+    /// removes the file or empty directory called name from this directory
+    fn remove(&self, _name: &str) -> Result<(), VfsError> {
+        Err(VfsError::NotADirectory)
+    }
+
+    /// moves the entry old_name of this directory to new_name in new_parent
+    ///
+    /// new_parent belongs to the same file system. an entry already called new_name is replaced,
+    /// the vfs has checked that it may be. it must still be there if the move fails
+    fn rename(
+        &self, _old_name: &str, _new_parent: &InodeRef, _new_name: &str
+    ) -> Result<(), VfsError> {
+        Err(VfsError::NotADirectory)
+    }
+
+    /// reads up to buffer.len() bytes at offset, returning the amount read
+    fn read_at(&self, _offset: u64, _buffer: &mut [u8]) -> Result<usize, VfsError> {
+        Err(VfsError::IsADirectory)
+    }
+
+    /// writes data at offset, growing the file as needed, and returns the amount written
+    fn write_at(&self, _offset: u64, _data: &[u8]) -> Result<usize, VfsError> {
+        Err(VfsError::IsADirectory)
+    }
+
+    /// cuts the file down to len bytes or extends it with zeroes
+    fn truncate(&self, _len: u64) -> Result<(), VfsError> {
+        Err(VfsError::IsADirectory)
+    }
+
+    /// opens the inode, None reads and writes through read_at and write_at
+    ///
+    /// devices and generated files return their own file to keep per open state
+    fn open(&self) -> Result<Option<Box<dyn File>>, VfsError> {
+        Ok(None)
+    }
+}
+
+/// an open file, as held by file descriptors
+pub trait File: Send + Sync {
+    fn read(&self, offset: u64, buffer: &mut [u8]) -> Result<usize, VfsError>;
+    fn write(&self, offset: u64, data: &[u8]) -> Result<usize, VfsError>;
+}
+
+impl Debug for dyn Inode {
+    fn fmt(&self, f: &mut Formatter<'_>) -> core::fmt::Result {
+        f.debug_struct("Inode")
+            .field("metadata", &self.metadata().ok())
+            .finish()
+    }
+}
+
+/// a file that reads and writes its inode directly
+struct InodeFile(InodeRef);
+
+impl File for InodeFile {
+    fn read(&self, offset: u64, buffer: &mut [u8]) -> Result<usize, VfsError> {
+        self.0.read_at(offset, buffer)
+    }
+
+    fn write(&self, offset: u64, data: &[u8]) -> Result<usize, VfsError> {
+        self.0.write_at(offset, data)
+    }
+}
+
+struct Mount {
+    /// absolute path without a trailing slash, "" for the root
+    path: String,
+    fs: Arc<dyn FileSystem>,
+    root: InodeRef,
+}
+
+/// an inode found by path along with the directories leading to it
+#[derive(Clone)]
+struct Walk {
+    inode: InodeRef,
+    /// index of the mount the inode belongs to
+    mount: usize,
+    /// the path that led here with "." and ".." removed, "" for the root
+    path: String,
+    /// the directories from the root down to the one holding inode, with their mounts
+    parents: Vec<(InodeRef, usize)>,
+}
+
+/// mounts fs at the absolute path, which does not need to exist in the file system below
+///
+/// paths crossing the mount point use the root of fs from then on
+pub fn mount(path: &str, fs: Arc<dyn FileSystem>) -> Result<(), VfsError> {
+    let path = normalize(path)?;
+    let mut mounts = MOUNTS.lock();
+    if mounts.is_empty() && !path.is_empty() {
+        // everything else is found through the root
+        return Err(VfsError::NotFound);
+    }
+    if mounts.iter().any(|mount| mount.path == path) {
+        return Err(VfsError::Busy);
+    }
+    let root = fs.root();
+    mounts.push(Mount {
+        path,
+        fs,
+        root,
+    });
+    Ok(())
+}
+
+/// the mount points with the name of the file system mounted there, in mount order
+pub fn mounts() -> Vec<(String, &'static str)> {
+    MOUNTS.lock()
+        .iter()
+        .map(|mount| {
+            let path = if mount.path.is_empty() { String::from("/") } else { mount.path.clone() };
+            (path, mount.fs.name())
+        })
+        .collect()
+}
+
+/// finds the inode at path, relative paths start at the absolute path cwd
+///
+/// "." and ".." are followed as usual and ".." of the root is the root itself
+pub fn lookup(path: &str, cwd: &str) -> Result<InodeRef, VfsError> {
+    Ok(resolve(path, cwd)?.inode)
+}
+
+/// opens the inode for reading and writing
+pub fn open(inode: &InodeRef) -> Result<Box<dyn File>, VfsError> {
+    Ok(match inode.open()? {
+        Some(file) => file,
+        None => Box::new(InodeFile(inode.clone())),
+    })
+}
+
+/// creates an empty file at path, or truncates the file already there
+pub fn create(path: &str, cwd: &str) -> Result<InodeRef, VfsError> {
+    let (parent, name) = resolve_parent(path, cwd)?;
+    match lookup_child(&parent, name) {
+        Ok(child) => {
+            if child.inode.metadata()?.file_type == FileType::Directory {
+                return Err(VfsError::IsADirectory);
+            }
+            child.inode.truncate(0)?;
+            Ok(child.inode)
+        },
+        Err(VfsError::NotFound) => parent.inode.create(name, FileType::Regular),
+        Err(err) => Err(err),
+    }
+}
+
+/// creates an empty directory at path
+pub fn create_directory(path: &str, cwd: &str) -> Result<InodeRef, VfsError> {
+    let (parent, name) = resolve_parent(path, cwd)?;
+    match lookup_child(&parent, name) {
+        Ok(_) => Err(VfsError::AlreadyExists),
+        Err(VfsError::NotFound) => parent.inode.create(name, FileType::Directory),
+        Err(err) => Err(err),
+    }
+}
+
+/// deletes the file at path
+pub fn remove(path: &str, cwd: &str) -> Result<(), VfsError> {
+    let (parent, name) = resolve_parent(path, cwd)?;
+    let child = lookup_child(&parent, name)?;
+    if child.mount != parent.mount {
+        return Err(VfsError::Busy);
+    }
+    if child.inode.metadata()?.file_type == FileType::Directory {
+        return Err(VfsError::IsADirectory);
+    }
+    parent.inode.remove(name)
+}
+
+/// deletes the empty directory at path
+pub fn remove_directory(path: &str, cwd: &str) -> Result<(), VfsError> {
+    let (parent, name) = resolve_parent(path, cwd)?;
+    let child = lookup_child(&parent, name)?;
+    if child.mount != parent.mount {
+        return Err(VfsError::Busy);
+    }
+    if child.inode.metadata()?.file_type != FileType::Directory {
+        return Err(VfsError::NotADirectory);
+    }
+    parent.inode.remove(name)
+}
+
+/// moves the file or directory at old_path to new_path, replacing what is there
+///
+/// a file replaces a file and a directory an empty directory. both paths have to be on the
+/// same file system and a directory cannot move below itself
+pub fn rename(old_path: &str, new_path: &str, cwd: &str) -> Result<(), VfsError> {
+    let (old_parent, old_name) = resolve_parent(old_path, cwd)?;
+    let old = lookup_child(&old_parent, old_name)?;
+    let (new_parent, new_name) = resolve_parent(new_path, cwd)?;
+    if old.mount != old_parent.mount {
+        return Err(VfsError::Busy);
+    }
+    if new_parent.mount != old.mount {
+        return Err(VfsError::CrossDevice);
+    }
+    let metadata = old.inode.metadata()?;
+    let is_directory = metadata.file_type == FileType::Directory;
+    let moves_below_itself = new_parent.parents.iter()
+        .chain([&(new_parent.inode.clone(), new_parent.mount)])
+        .any(|(directory, mount)| *mount == old.mount && is_same(directory, &old.inode));
+    if is_directory && moves_below_itself {
+        return Err(VfsError::InvalidArgument);
+    }
+
+    match lookup_child(&new_parent, new_name) {
+        Ok(target) if target.mount != new_parent.mount => return Err(VfsError::Busy),
+        // the same entry under another name, such as one that only differs in case
+        Ok(target) if is_same(&target.inode, &old.inode) => {},
+        // the driver replaces the target once the move has succeeded
+        Ok(target) => {
+            let target_is_directory = target.inode.metadata()?.file_type == FileType::Directory;
+            match (is_directory, target_is_directory) {
+                (true, true) if !target.inode.read_dir()?.is_empty() => {
+                    return Err(VfsError::NotEmpty);
+                },
+                (true, true) | (false, false) => {},
+                (true, false) => return Err(VfsError::NotADirectory),
+                (false, true) => return Err(VfsError::IsADirectory),
+            }
+        },
+        Err(VfsError::NotFound) => {},
+        Err(err) => return Err(err),
+    }
+    old_parent.inode.rename(old_name, &new_parent.inode, new_name)
+}
+
+/// true if both inodes are the same file, they have to be on the same file system
+fn is_same(a: &InodeRef, b: &InodeRef) -> bool {
+    match (a.metadata(), b.metadata()) {
+        (Ok(a), Ok(b)) => a.inode == b.inode,
+        _ => false,
+    }
+}
+
+/// finds the inode at path, crossing into file systems mounted on the way
+fn resolve(path: &str, cwd: &str) -> Result<Walk, VfsError> {
+    let mut walk = root_walk()?;
+    for component in path_components(path, cwd) {
+        walk = step(walk, component)?;
+    }
+    // a trailing slash asks for a directory
+    if path.ends_with('/') && walk.inode.metadata()?.file_type != FileType::Directory {
+        return Err(VfsError::NotADirectory);
+    }
+    Ok(walk)
+}
+
+/// finds the directory holding the last component of path, which is returned with it
+///
+/// the last component has to be a name, not "." or ".."
+fn resolve_parent<'a>(path: &'a str, cwd: &'a str) -> Result<(Walk, &'a str), VfsError> {
+    let mut components: Vec<&str> = path_components(path, cwd).collect();
+    let name = components.pop().ok_or(VfsError::Busy)?;
+    if name == "." || name == ".." {
+        return Err(VfsError::InvalidArgument);
+    }
+    let mut walk = root_walk()?;
+    for component in components {
+        walk = step(walk, component)?;
+    }
+    if walk.inode.metadata()?.file_type != FileType::Directory {
+        return Err(VfsError::NotADirectory);
+    }
+    Ok((walk, name))
+}
+
+/// the walk to the entry name of the directory parent
+fn lookup_child(parent: &Walk, name: &str) -> Result<Walk, VfsError> {
+    step(parent.clone(), name)
+}
+
+fn root_walk() -> Result<Walk, VfsError> {
+    let mounts = MOUNTS.lock();
+    let root = mounts.first().ok_or(VfsError::NotFound)?;
+    Ok(Walk {
+        inode: root.root.clone(),
+        mount: 0,
+        path: String::new(),
+        parents: Vec::new(),
+    })
+}
+
+/// follows one path component from the directory walk is at
+fn step(mut walk: Walk, component: &str) -> Result<Walk, VfsError> {
+    if walk.inode.metadata()?.file_type != FileType::Directory {
+        return Err(VfsError::NotADirectory);
+    }
+    match component {
+        "." => {},
+        ".." => if let Some((parent, mount)) = walk.parents.pop() {
+            walk.inode = parent;
+            walk.mount = mount;
+            let end = walk.path.rfind('/').unwrap_or(0);
+            walk.path.truncate(end);
+        },
+        name => {
+            walk.path.push('/');
+            walk.path.push_str(name);
+            let mounts = MOUNTS.lock();
+            let mounted = mounts.iter()
+                .enumerate()
+                .find(|(_, mount)| is_mount_path(&mounts, &mount.path, &walk))
+                .map(|(index, mount)| (mount.root.clone(), index));
+            drop(mounts);
+            let (child, mount) = match mounted {
+                Some(mounted) => mounted,
+                None => (walk.inode.lookup(name)?, walk.mount),
+            };
+            walk.parents.push((core::mem::replace(&mut walk.inode, child), walk.mount));
+            walk.mount = mount;
+        },
+    }
+    Ok(walk)
+}
+
+/// true if walk, which has just added its last component to its path, is at mount_path
+///
+/// each component is compared by the file system of the directory it is looked up in, so a
+/// mount point below FAT is found whatever the case of its path
+fn is_mount_path(mounts: &[Mount], mount_path: &str, walk: &Walk) -> bool {
+    let components = walk.path.split('/').skip(1);
+    let mount_components = mount_path.split('/').skip(1);
+    if components.clone().count() != mount_components.clone().count() {
+        return false;
+    }
+    // the directories holding each component, the last one is where walk is now
+    let directory_mounts = walk.parents.iter()
+        .map(|(_, mount)| *mount)
+        .chain([walk.mount]);
+    components.zip(mount_components)
+        .zip(directory_mounts)
+        .all(|((component, mount_component), mount)| {
+            mounts[mount].fs.same_name(component, mount_component)
+        })
+}
+
+/// turns an absolute path into the form mount points are compared in
+fn normalize(path: &str) -> Result<String, VfsError> {
+    if !path.starts_with('/') {
+        return Err(VfsError::InvalidArgument);
+    }
+    let mut normalized = String::new();
+    for component in path_components(path, "/") {
+        match component {
+            "." => {},
+            ".." => {
+                let end = normalized.rfind('/').unwrap_or(0);
+                normalized.truncate(end);
+            },
+            name => {
+                normalized.push('/');
+                normalized.push_str(name);
+            },
+        }
+    }
+    Ok(normalized)
+}
+
+/// the non empty components of path, preceded by those of cwd if path is relative
+fn path_components<'a>(path: &'a str, cwd: &'a str) -> impl Iterator<Item = &'a str> {
+    let base = if path.starts_with('/') { "" } else { cwd };
+    base.split('/')
+        .chain(path.split('/'))
+        .filter(|component| !component.is_empty())
+}
+
+#[test_case]
+fn test_path_components() {
+    assert!(path_components("/bin//bash", "/home").eq(["bin", "bash"]));
+    assert!(path_components("../bin/./bash", "/home/").eq(["home", "..", "bin", ".", "bash"]));
+    assert!(path_components("", "/").eq([] as [&str; 0]));
+}
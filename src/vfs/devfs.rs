@@ -0,0 +1,321 @@
+use alloc::format;
+use alloc::string::{String, ToString};
+use alloc::sync::Arc;
+use alloc::vec;
+use alloc::vec::Vec;
+use core::any::Any;
+use core::cmp::min;
+use x86_64::instructions::interrupts;
+use x86_64::instructions::port::Port;
+use crate::disk::{self, SECTOR_SIZE};
+use crate::display::serial::SERIAL1;
+use crate::display::vga_text::WRITER;
+use crate::random::random_u64;
+use crate::threading::scheduler;
+use crate::console;
+use super::{DirEntry, FileSystem, FileType, Inode, InodeRef, Metadata, VfsError};
+
+/// line status register of the first serial port
+const SERIAL_LINE_STATUS: u16 = 0x3F8 + 5;
+const SERIAL_DATA: u16 = 0x3F8;
+/// line status bit set while a received byte waits to be read
+const SERIAL_DATA_READY: u8 = 0x1;
+/// bytes sent to the serial port per turn holding its lock, which traps also take to log
+const SERIAL_CHUNK: usize = 64;
+
+/// device nodes for the hardware the kernel drives and a few pseudo devices
+///
+/// the set of devices is fixed when the file system is created
+pub struct Devfs {
+    root: Arc<DevDirectory>,
+}
+
+/// the directory holding every device
+struct DevDirectory {
+    devices: Vec<(String, InodeRef)>,
+}
+
+/// a device node, reads and writes ignore the offset unless the device is a disk
+struct DeviceNode {
+    inode: u64,
+    device: Device,
+}
+
+enum Device {
+    /// keyboard input and text output on the screen
+    Console,
+    /// the first serial port
+    Serial,
+    /// discards writes and reads as empty
+    Null,
+    /// discards writes and reads as zeroes
+    Zero,
+    /// reads as random bytes
+    Random,
+    /// an AHCI drive with its number of sectors
+    Disk { drive: usize, sectors: u64 },
+}
+
+impl Devfs {
+    /// creates the device nodes, disks are found through the AHCI driver
+    pub fn new() -> Self {
+        let mut devices = vec![
+            ("console".to_string(), Device::Console),
+            ("ttyS0".to_string(), Device::Serial),
+            ("null".to_string(), Device::Null),
+            ("zero".to_string(), Device::Zero),
+            ("random".to_string(), Device::Random),
+        ];
+        let mapper = crate::MAPPER.get()
+            .expect("mapper not initialized");
+        for drive in 0..disk::get_disk_count() {
+            // a drive that cannot be identified is left out rather than shown as empty
+            if let Ok(sectors) = disk::get_sector_count(mapper, drive) {
+                devices.push((disk_name(drive), Device::Disk { drive, sectors }));
+            }
+        }
+
+        let devices = devices.into_iter()
+            .enumerate()
+            .map(|(i, (name, device))| {
+                // the directory is inode 1
+                let node = DeviceNode {
+                    inode: i as u64 + 2,
+                    device,
+                };
+                (name, Arc::new(node) as InodeRef)
+            })
+            .collect();
+        Self {
+            root: Arc::new(DevDirectory { devices }),
+        }
+    }
+}
+
+impl FileSystem for Devfs {
+    fn name(&self) -> &'static str {
+        "devfs"
+    }
+
+    fn root(&self) -> InodeRef {
+        self.root.clone()
+    }
+}
+
+impl Inode for DevDirectory {
+    fn metadata(&self) -> Result<Metadata, VfsError> {
+        Ok(Metadata {
+            inode: 1,
+            file_type: FileType::Directory,
+            size: self.devices.len() as u64,
+            mode: 0o755,
+            attributes: 0,
+            created: 0,
+            modified: 0,
+            accessed: 0,
+        })
+    }
+
+    fn as_any(&self) -> &dyn Any {
+        self
+    }
+
+    fn lookup(&self, name: &str) -> Result<InodeRef, VfsError> {
+        self.devices.iter()
+            .find(|(device_name, _)| device_name == name)
+            .map(|(_, device)| device.clone())
+            .ok_or(VfsError::NotFound)
+    }
+
+    fn read_dir(&self) -> Result<Vec<DirEntry>, VfsError> {
+        self.devices.iter()
+            .map(|(name, device)| {
+                let metadata = device.metadata()?;
+                Ok(DirEntry {
+                    name: name.clone(),
+                    inode: metadata.inode,
+                    file_type: metadata.file_type,
+                })
+            })
+            .collect()
+    }
+
+    fn create(&self, _name: &str, _file_type: FileType) -> Result<InodeRef, VfsError> {
+        Err(VfsError::NotSupported)
+    }
+
+    fn remove(&self, _name: &str) -> Result<(), VfsError> {
+        Err(VfsError::NotSupported)
+    }
+
+    fn rename(
+        &self, _old_name: &str, _new_parent: &InodeRef, _new_name: &str
+    ) -> Result<(), VfsError> {
+        Err(VfsError::NotSupported)
+    }
+
+    fn read_at(&self, _offset: u64, _buffer: &mut [u8]) -> Result<usize, VfsError> {
+        Err(VfsError::IsADirectory)
+    }
+}
+
+impl Inode for DeviceNode {
+    fn metadata(&self) -> Result<Metadata, VfsError> {
+        let (file_type, size, mode) = match self.device {
+            Device::Console => (FileType::CharDevice, 0, 0o620),
+            Device::Disk { sectors, .. } => (FileType::BlockDevice, sectors * SECTOR_SIZE, 0o660),
+            _ => (FileType::CharDevice, 0, 0o666),
+        };
+        Ok(Metadata {
+            inode: self.inode,
+            file_type,
+            size,
+            mode,
+            attributes: 0,
+            created: 0,
+            modified: 0,
+            accessed: 0,
+        })
+    }
+
+    fn as_any(&self) -> &dyn Any {
+        self
+    }
+
+    fn read_at(&self, offset: u64, buffer: &mut [u8]) -> Result<usize, VfsError> {
+        match self.device {
+            Device::Console => console::read(buffer).map_err(|_| VfsError::Interrupted),
+            Device::Serial => read_serial(buffer),
+            Device::Null => Ok(0),
+            Device::Zero => {
+                buffer.fill(0);
+                Ok(buffer.len())
+            },
+            Device::Random => {
+                for chunk in buffer.chunks_mut(8) {
+                    chunk.copy_from_slice(&random_u64().to_le_bytes()[..chunk.len()]);
+                }
+                Ok(buffer.len())
+            },
+            Device::Disk { drive, sectors } => read_disk(drive, sectors, offset, buffer),
+        }
+    }
+
+    fn write_at(&self, offset: u64, data: &[u8]) -> Result<usize, VfsError> {
+        match self.device {
+            Device::Console => {
+                // bytes go through as they are, since a character may be split across writes
+                interrupts::without_interrupts(|| WRITER.lock().write_bytes(data));
+                Ok(data.len())
+            },
+            Device::Serial => {
+                for chunk in data.chunks(SERIAL_CHUNK) {
+                    interrupts::without_interrupts(|| {
+                        let mut serial = SERIAL1.lock();
+                        for byte in chunk {
+                            serial.send(*byte);
+                        }
+                    });
+                }
+                Ok(data.len())
+            },
+            Device::Null | Device::Zero | Device::Random => Ok(data.len()),
+            Device::Disk { drive, sectors } => write_disk(drive, sectors, offset, data),
+        }
+    }
+
+    /// devices have no length to change, so opening one with truncation leaves it alone
+    fn truncate(&self, _len: u64) -> Result<(), VfsError> {
+        Ok(())
+    }
+}
+
+/// sda for the first drive, then sdb and so on up to sdz, after which names get a number
+fn disk_name(drive: usize) -> String {
+    if drive < 26 {
+        format!("sd{}", (b'a' + drive as u8) as char)
+    } else {
+        format!("sd{}", drive)
+    }
+}
+
+/// waits for at least one byte from the serial port and reads what has arrived
+///
+/// the port raises no interrupts, so this polls and lets the timer switch to other tasks
+fn read_serial(buffer: &mut [u8]) -> Result<usize, VfsError> {
+    if buffer.is_empty() {
+        return Ok(0);
+    }
+    let mut line_status = Port::<u8>::new(SERIAL_LINE_STATUS);
+    let mut data = Port::<u8>::new(SERIAL_DATA);
+    let mut count = 0;
+    while count == 0 {
+        while count < buffer.len() && unsafe { line_status.read() } & SERIAL_DATA_READY != 0 {
+            buffer[count] = unsafe { data.read() };
+            count += 1;
+        }
+        if count == 0 {
+            if scheduler::signal_pending() {
+                return Err(VfsError::Interrupted);
+            }
+            core::hint::spin_loop();
+        }
+    }
+    Ok(count)
+}
+
+/// reads the bytes of the drive at offset, a sector at a time
+fn read_disk(
+    drive: usize, sectors: u64, offset: u64, buffer: &mut [u8]
+) -> Result<usize, VfsError> {
+    let size = sectors * SECTOR_SIZE;
+    if offset >= size {
+        return Ok(0);
+    }
+    let count = min(buffer.len() as u64, size - offset) as usize;
+    let mapper = crate::MAPPER.get()
+        .expect("mapper not initialized");
+    let mut done = 0;
+    while done < count {
+        let position = offset + done as u64;
+        let skip = (position % SECTOR_SIZE) as usize;
+        let len = min(count - done, SECTOR_SIZE as usize - skip);
+        let sector = disk::read_sectors(mapper, drive, position / SECTOR_SIZE, 1)?;
+        buffer[done..done + len].copy_from_slice(&sector[skip..skip + len]);
+        done += len;
+    }
+    Ok(count)
+}
+
+/// writes data to the drive at offset, sectors only partly covered keep the rest of their bytes
+fn write_disk(drive: usize, sectors: u64, offset: u64, data: &[u8]) -> Result<usize, VfsError> {
+    let size = sectors * SECTOR_SIZE;
+    if offset >= size && !data.is_empty() {
+        return Err(VfsError::NoSpace);
+    }
+    let count = min(data.len() as u64, size - offset) as usize;
+    let mapper = crate::MAPPER.get()
+        .expect("mapper not initialized");
+    let mut done = 0;
+    while done < count {
+        let position = offset + done as u64;
+        let lba = position / SECTOR_SIZE;
+        let skip = (position % SECTOR_SIZE) as usize;
+        let len = min(count - done, SECTOR_SIZE as usize - skip);
+        let mut sector = if len == SECTOR_SIZE as usize {
+            vec![0; SECTOR_SIZE as usize]
+        } else {
+            disk::read_sectors(mapper, drive, lba, 1)?
+        };
+        sector[skip..skip + len].copy_from_slice(&data[done..done + len]);
+        disk::write_sectors(mapper, drive, lba, 1, sector)?;
+        done += len;
+    }
+    Ok(count)
+}
+
+#[test_case]
+fn test_disk_name() {
+    assert_eq!(disk_name(0), "sda");
+    assert_eq!(disk_name(25), "sdz");
+}
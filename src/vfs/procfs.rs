@@ -0,0 +1,307 @@
+use alloc::format;
+use alloc::string::{String, ToString};
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+use core::any::Any;
+use core::cmp::min;
+use core::fmt::Write;
+use crate::interrupts::{ticks, TIMER_FREQUENCY};
+use crate::memory::with_frame_allocator;
+use crate::process::vma::{VmaBacking, VmaKind, PROT_EXEC, PROT_READ, PROT_WRITE};
+use crate::threading::scheduler::{self, TaskState, PID};
+use crate::{acpi, allocator, pci, rtc};
+use super::{DirEntry, FileSystem, FileType, Inode, InodeRef, Metadata, VfsError};
+
+/// files directly in the root, next to a directory for each process
+const KERNEL_FILES: [(&str, Node); 4] = [
+    ("meminfo", Node::Meminfo),
+    ("pci", Node::Pci),
+    ("acpi", Node::Acpi),
+    ("uptime", Node::Uptime),
+];
+const PROCESS_FILES: [(&str, fn(PID) -> Node); 2] = [
+    ("status", Node::Status),
+    ("maps", Node::Maps),
+];
+
+/// kernel and process status as files whose contents are made up when they are read
+pub struct Procfs {
+    root: Arc<ProcNode>,
+}
+
+struct ProcNode {
+    node: Node,
+}
+
+#[derive(Copy, Clone)]
+enum Node {
+    Root,
+    /// the directory of a process
+    Process(PID),
+    Status(PID),
+    Maps(PID),
+    Meminfo,
+    Pci,
+    Acpi,
+    Uptime,
+}
+
+impl Procfs {
+    pub fn new() -> Self {
+        Self {
+            root: Arc::new(ProcNode { node: Node::Root }),
+        }
+    }
+}
+
+impl FileSystem for Procfs {
+    fn name(&self) -> &'static str {
+        "proc"
+    }
+
+    fn root(&self) -> InodeRef {
+        self.root.clone()
+    }
+}
+
+impl Node {
+    /// kernel files are numbered from 2, a process gets 256 inodes from pid * 256 on
+    fn inode(self) -> u64 {
+        match self {
+            Node::Root => 1,
+            Node::Meminfo => 2,
+            Node::Pci => 3,
+            Node::Acpi => 4,
+            Node::Uptime => 5,
+            Node::Process(pid) => pid.as_u64() << 8,
+            Node::Status(pid) => (pid.as_u64() << 8) | 1,
+            Node::Maps(pid) => (pid.as_u64() << 8) | 2,
+        }
+    }
+
+    fn file_type(self) -> FileType {
+        match self {
+            Node::Root | Node::Process(_) => FileType::Directory,
+            _ => FileType::Regular,
+        }
+    }
+
+    fn to_entry(self, name: &str) -> DirEntry {
+        DirEntry {
+            name: name.to_string(),
+            inode: self.inode(),
+            file_type: self.file_type(),
+        }
+    }
+
+    /// the current contents of a file
+    fn contents(self) -> Result<String, VfsError> {
+        match self {
+            Node::Root | Node::Process(_) => Err(VfsError::IsADirectory),
+            Node::Status(pid) => process_status(pid),
+            Node::Maps(pid) => process_maps(pid),
+            Node::Meminfo => Ok(meminfo()),
+            Node::Pci => Ok(pci_devices()),
+            Node::Acpi => Ok(acpi_tables()),
+            Node::Uptime => {
+                let hundredths = ticks() * 100 / TIMER_FREQUENCY as u64;
+                Ok(format!("{}.{:02}\n", hundredths / 100, hundredths % 100))
+            },
+        }
+    }
+}
+
+impl Inode for ProcNode {
+    fn metadata(&self) -> Result<Metadata, VfsError> {
+        let file_type = self.node.file_type();
+        let now = rtc::now();
+        Ok(Metadata {
+            inode: self.node.inode(),
+            file_type,
+            // the size is not known until the file is read
+            size: 0,
+            mode: if file_type == FileType::Directory { 0o555 } else { 0o444 },
+            attributes: 0,
+            created: now,
+            modified: now,
+            accessed: now,
+        })
+    }
+
+    fn as_any(&self) -> &dyn Any {
+        self
+    }
+
+    fn lookup(&self, name: &str) -> Result<InodeRef, VfsError> {
+        let node = match self.node {
+            Node::Root => KERNEL_FILES.iter()
+                .find(|(file_name, _)| *file_name == name)
+                .map(|(_, node)| *node)
+                .or_else(|| {
+                    let pid = PID::new(name.parse().ok()?);
+                    scheduler::with_task(pid, |_| Node::Process(pid))
+                }),
+            Node::Process(pid) => PROCESS_FILES.iter()
+                .find(|(file_name, _)| *file_name == name)
+                .map(|(_, node)| node(pid)),
+            _ => return Err(VfsError::NotADirectory),
+        };
+        node.map(|node| Arc::new(ProcNode { node }) as InodeRef)
+            .ok_or(VfsError::NotFound)
+    }
+
+    fn read_dir(&self) -> Result<Vec<DirEntry>, VfsError> {
+        match self.node {
+            Node::Root => {
+                let processes = scheduler::pids()
+                    .into_iter()
+                    .map(|pid| Node::Process(pid).to_entry(&pid.as_u64().to_string()));
+                Ok(KERNEL_FILES.iter()
+                    .map(|(name, node)| node.to_entry(name))
+                    .chain(processes)
+                    .collect())
+            },
+            Node::Process(pid) => Ok(PROCESS_FILES.iter()
+                .map(|(name, node)| node(pid).to_entry(name))
+                .collect()),
+            _ => Err(VfsError::NotADirectory),
+        }
+    }
+
+    fn create(&self, _name: &str, _file_type: FileType) -> Result<InodeRef, VfsError> {
+        Err(VfsError::NotSupported)
+    }
+
+    fn remove(&self, _name: &str) -> Result<(), VfsError> {
+        Err(VfsError::NotSupported)
+    }
+
+    fn rename(
+        &self, _old_name: &str, _new_parent: &InodeRef, _new_name: &str
+    ) -> Result<(), VfsError> {
+        Err(VfsError::NotSupported)
+    }
+
+    /// makes up the whole file and returns the part at offset, so a file read in pieces
+    /// may change between them
+    fn read_at(&self, offset: u64, buffer: &mut [u8]) -> Result<usize, VfsError> {
+        let contents = self.node.contents()?;
+        let start = min(offset, contents.len() as u64) as usize;
+        let count = min(buffer.len(), contents.len() - start);
+        buffer[..count].copy_from_slice(&contents.as_bytes()[start..start + count]);
+        Ok(count)
+    }
+
+    fn write_at(&self, _offset: u64, _data: &[u8]) -> Result<usize, VfsError> {
+        Err(VfsError::NotSupported)
+    }
+
+    fn truncate(&self, _len: u64) -> Result<(), VfsError> {
+        Err(VfsError::NotSupported)
+    }
+}
+
+/// state, parent and memory use of a process
+fn process_status(pid: PID) -> Result<String, VfsError> {
+    scheduler::with_task(pid, |task| {
+        let state = state_name(task.state());
+        let parent = task.parent().map_or(0, PID::as_u64);
+        let process = task.process_mut();
+        let size: u64 = process.vmas().iter()
+            .map(|vma| vma.end - vma.start)
+            .sum();
+        let resident = process.resident_size();
+        format!(
+            "Pid:\t{}\nPPid:\t{}\nState:\t{}\nVmSize:\t{} kB\nVmRSS:\t{} kB\n",
+            pid.as_u64(), parent, state, size / 1024, resident / 1024
+        )
+    }).ok_or(VfsError::NotFound)
+}
+
+/// one line per memory area: addresses, permissions, file offset, inode and what it holds
+fn process_maps(pid: PID) -> Result<String, VfsError> {
+    scheduler::with_process(pid, |process| {
+        let mut maps = String::new();
+        for vma in process.vmas().iter() {
+            let (offset, inode) = match &vma.backing {
+                VmaBacking::File { file, offset, .. } => {
+                    (*offset, file.metadata().map_or(0, |metadata| metadata.inode))
+                },
+                VmaBacking::Anonymous => (0, 0),
+            };
+            let name = match vma.kind {
+                VmaKind::Heap => "[heap]",
+                VmaKind::Stack => "[stack]",
+                VmaKind::Image | VmaKind::Anonymous => "",
+            };
+            let permissions = permissions(vma.protection);
+            let permissions = core::str::from_utf8(&permissions).unwrap();
+            let _ = writeln!(
+                maps, "{:08x}-{:08x} {} {:08x} {} {}",
+                vma.start, vma.end, permissions, offset, inode, name
+            );
+        }
+        maps
+    }).ok_or(VfsError::NotFound)
+}
+
+/// physical memory from the frame allocator and the kernel heap
+fn meminfo() -> String {
+    let (total, available) = with_frame_allocator(|frame_allocator| {
+        (frame_allocator.total_frames(), frame_allocator.available_frames())
+    });
+    format!(
+        "MemTotal:  {:>8} kB\nMemFree:   {:>8} kB\nHeapTotal: {:>8} kB\nHeapUsed:  {:>8} kB\n",
+        total * 4, available * 4, allocator::HEAP_SIZE / 1024, allocator::heap_used() / 1024
+    )
+}
+
+/// vendor, device and class of every pci device
+fn pci_devices() -> String {
+    let mut devices = String::new();
+    for device in pci::DEVICES.get().into_iter().flatten() {
+        let id = device.get_identification();
+        let _ = writeln!(
+            devices, "{:04x}:{:04x} {:02x}{:02x}{:02x}",
+            device.get_vendor_id(), device.get_device_id(), id.class, id.subclass, id.interface
+        );
+    }
+    devices
+}
+
+/// signature and length of every acpi table the rsdt points to
+fn acpi_tables() -> String {
+    let mut tables = String::new();
+    for table in acpi::RSDT.get().into_iter().flat_map(|rsdt| rsdt.get_tables()) {
+        let _ = writeln!(tables, "{} {}", table.get_signature_str(), table.get_length());
+    }
+    tables
+}
+
+/// the state letter ps shows followed by a description
+fn state_name(state: TaskState) -> &'static str {
+    match state {
+        TaskState::READY => "R (ready)",
+        TaskState::RUNNING => "R (running)",
+        TaskState::WAITING => "S (sleeping)",
+        TaskState::STOPPED => "T (stopped)",
+        TaskState::DONE => "Z (zombie)",
+    }
+}
+
+/// PROT_* bits as in a maps file, all mappings are private
+fn permissions(protection: u64) -> [u8; 4] {
+    [
+        if protection & PROT_READ != 0 { b'r' } else { b'-' },
+        if protection & PROT_WRITE != 0 { b'w' } else { b'-' },
+        if protection & PROT_EXEC != 0 { b'x' } else { b'-' },
+        b'p',
+    ]
+}
+
+#[test_case]
+fn test_permissions() {
+    assert_eq!(&permissions(PROT_READ | PROT_EXEC), b"r-xp");
+    assert_eq!(&permissions(PROT_READ | PROT_WRITE), b"rw-p");
+    assert_eq!(&permissions(0), b"---p");
+}
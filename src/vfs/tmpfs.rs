@@ -0,0 +1,347 @@
+use alloc::collections::BTreeMap;
+use alloc::string::{String, ToString};
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+use core::any::Any;
+use core::cmp::min;
+use core::sync::atomic::{AtomicU64, Ordering};
+use spin::Mutex;
+use x86_64::instructions::interrupts;
+use crate::rtc;
+use super::{DirEntry, FileSystem, FileType, Inode, InodeRef, Metadata, VfsError};
+
+/// names are at most this many bytes
+const MAX_NAME_LEN: usize = 255;
+
+/// a file system kept in kernel memory, its contents are lost on reboot
+pub struct Tmpfs {
+    root: Arc<TmpInode>,
+}
+
+/// what all inodes of one tmpfs share
+struct Limits {
+    /// bytes of file data allowed in total
+    capacity: u64,
+    /// bytes of file data currently stored
+    used: AtomicU64,
+    next_inode: AtomicU64,
+}
+
+/// a file or directory of a tmpfs
+///
+/// the state is only locked with interrupts disabled, so page faults can load executables
+/// stored here at any time
+pub struct TmpInode {
+    inode: u64,
+    limits: Arc<Limits>,
+    state: Mutex<TmpState>,
+}
+
+struct TmpState {
+    contents: Contents,
+    created: i64,
+    modified: i64,
+    accessed: i64,
+}
+
+enum Contents {
+    File(Vec<u8>),
+    Directory(BTreeMap<String, Arc<TmpInode>>),
+}
+
+impl Tmpfs {
+    /// creates an empty tmpfs that holds at most capacity bytes of file data
+    pub fn new(capacity: u64) -> Self {
+        let limits = Arc::new(Limits {
+            capacity,
+            used: AtomicU64::new(0),
+            next_inode: AtomicU64::new(1),
+        });
+        Self {
+            root: TmpInode::new(&limits, Contents::Directory(BTreeMap::new())),
+        }
+    }
+
+    /// bytes of file data stored, including files removed while they are still open
+    pub fn used(&self) -> u64 {
+        self.root.limits.used.load(Ordering::Relaxed)
+    }
+}
+
+impl FileSystem for Tmpfs {
+    fn name(&self) -> &'static str {
+        "tmpfs"
+    }
+
+    fn root(&self) -> InodeRef {
+        self.root.clone()
+    }
+}
+
+impl Limits {
+    /// takes len more bytes from the capacity, failing if there are not enough left
+    fn reserve(&self, len: u64) -> Result<(), VfsError> {
+        self.used.fetch_update(Ordering::Relaxed, Ordering::Relaxed, |used| {
+            used.checked_add(len).filter(|used| *used <= self.capacity)
+        }).map(|_| ()).map_err(|_| VfsError::NoSpace)
+    }
+
+    fn release(&self, len: u64) {
+        self.used.fetch_sub(len, Ordering::Relaxed);
+    }
+}
+
+impl TmpInode {
+    fn new(limits: &Arc<Limits>, contents: Contents) -> Arc<Self> {
+        let now = rtc::now();
+        Arc::new(Self {
+            inode: limits.next_inode.fetch_add(1, Ordering::Relaxed),
+            limits: limits.clone(),
+            state: Mutex::new(TmpState {
+                contents,
+                created: now,
+                modified: now,
+                accessed: now,
+            }),
+        })
+    }
+
+    fn with_state<T>(&self, f: impl FnOnce(&mut TmpState) -> T) -> T {
+        interrupts::without_interrupts(|| f(&mut self.state.lock()))
+    }
+
+    /// runs f on the entries of this directory
+    fn with_entries<T>(
+        &self, f: impl FnOnce(&mut BTreeMap<String, Arc<TmpInode>>) -> Result<T, VfsError>
+    ) -> Result<T, VfsError> {
+        self.with_state(|state| match &mut state.contents {
+            Contents::Directory(entries) => {
+                let result = f(entries)?;
+                state.modified = rtc::now();
+                Ok(result)
+            },
+            Contents::File(_) => Err(VfsError::NotADirectory),
+        })
+    }
+
+    /// runs f on the data of this file, len is the size it may grow to
+    fn with_data<T>(
+        &self, len: u64, f: impl FnOnce(&mut Vec<u8>) -> T
+    ) -> Result<T, VfsError> {
+        let len = usize::try_from(len).map_err(|_| VfsError::NoSpace)?;
+        self.with_state(|state| {
+            let data = match &mut state.contents {
+                Contents::File(data) => data,
+                Contents::Directory(_) => return Err(VfsError::IsADirectory),
+            };
+            if len > data.len() {
+                self.limits.reserve((len - data.len()) as u64)?;
+            }
+            let old_len = data.len();
+            let result = f(data);
+            if data.len() < old_len {
+                self.limits.release((old_len - data.len()) as u64);
+            }
+            state.modified = rtc::now();
+            Ok(result)
+        })
+    }
+
+    fn is_empty_directory(&self) -> bool {
+        self.with_state(|state| match &state.contents {
+            Contents::Directory(entries) => entries.is_empty(),
+            Contents::File(_) => false,
+        })
+    }
+}
+
+impl Drop for TmpInode {
+    fn drop(&mut self) {
+        // removed files keep their space until the last user lets go of them
+        if let Contents::File(data) = &self.state.get_mut().contents {
+            self.limits.release(data.len() as u64);
+        }
+    }
+}
+
+impl Inode for TmpInode {
+    fn metadata(&self) -> Result<Metadata, VfsError> {
+        Ok(self.with_state(|state| {
+            let (file_type, size) = match &state.contents {
+                Contents::File(data) => (FileType::Regular, data.len() as u64),
+                Contents::Directory(entries) => (FileType::Directory, entries.len() as u64),
+            };
+            Metadata {
+                inode: self.inode,
+                file_type,
+                size,
+                mode: 0o755,
+                attributes: 0,
+                created: state.created,
+                modified: state.modified,
+                accessed: state.accessed,
+            }
+        }))
+    }
+
+    fn as_any(&self) -> &dyn Any {
+        self
+    }
+
+    fn lookup(&self, name: &str) -> Result<InodeRef, VfsError> {
+        self.with_state(|state| match &state.contents {
+            Contents::Directory(entries) => entries.get(name)
+                .map(|child| child.clone() as InodeRef)
+                .ok_or(VfsError::NotFound),
+            Contents::File(_) => Err(VfsError::NotADirectory),
+        })
+    }
+
+    fn read_dir(&self) -> Result<Vec<DirEntry>, VfsError> {
+        let children: Vec<(String, Arc<TmpInode>)> = self.with_state(|state| {
+            state.accessed = rtc::now();
+            match &state.contents {
+                Contents::Directory(entries) => Ok(entries.iter()
+                    .map(|(name, child)| (name.clone(), child.clone()))
+                    .collect()),
+                Contents::File(_) => Err(VfsError::NotADirectory),
+            }
+        })?;
+        // the types come from the children, which are locked one at a time
+        children.into_iter()
+            .map(|(name, child)| Ok(DirEntry {
+                name,
+                inode: child.inode,
+                file_type: child.metadata()?.file_type,
+            }))
+            .collect()
+    }
+
+    fn create(&self, name: &str, file_type: FileType) -> Result<InodeRef, VfsError> {
+        check_name(name)?;
+        let contents = match file_type {
+            FileType::Regular => Contents::File(Vec::new()),
+            FileType::Directory => Contents::Directory(BTreeMap::new()),
+            FileType::CharDevice | FileType::BlockDevice => return Err(VfsError::NotSupported),
+        };
+        let child = TmpInode::new(&self.limits, contents);
+        self.with_entries(|entries| {
+            if entries.contains_key(name) {
+                return Err(VfsError::AlreadyExists);
+            }
+            entries.insert(name.to_string(), child.clone());
+            Ok(())
+        })?;
+        Ok(child)
+    }
+
+    fn remove(&self, name: &str) -> Result<(), VfsError> {
+        let child = self.with_entries(|entries| {
+            let child = entries.get(name).ok_or(VfsError::NotFound)?;
+            // only this directory is locked, so the child can be looked at
+            if matches!(child.metadata()?.file_type, FileType::Directory)
+                && !child.is_empty_directory()
+            {
+                return Err(VfsError::NotEmpty);
+            }
+            Ok(entries.remove(name))
+        })?;
+        // the last reference may free the file, which is done with no lock held
+        drop(child);
+        Ok(())
+    }
+
+    fn rename(
+        &self, old_name: &str, new_parent: &InodeRef, new_name: &str
+    ) -> Result<(), VfsError> {
+        check_name(new_name)?;
+        let new_parent = new_parent.as_any()
+            .downcast_ref::<TmpInode>()
+            .filter(|new_parent| Arc::ptr_eq(&new_parent.limits, &self.limits))
+            .ok_or(VfsError::CrossDevice)?;
+        // the entry called new_name is replaced in the same step that adds child
+        if core::ptr::eq(new_parent, self) {
+            let replaced = self.with_entries(|entries| {
+                let child = entries.remove(old_name).ok_or(VfsError::NotFound)?;
+                Ok(entries.insert(new_name.to_string(), child))
+            })?;
+            // the last reference may free the file, which is done with no lock held
+            drop(replaced);
+            return Ok(());
+        }
+
+        // the directories are locked one after the other, never both at once
+        let child = self.with_entries(|entries| {
+            entries.remove(old_name).ok_or(VfsError::NotFound)
+        })?;
+        let result = new_parent.with_entries(|entries| {
+            Ok(entries.insert(new_name.to_string(), child.clone()))
+        });
+        match result {
+            Ok(replaced) => {
+                drop(replaced);
+                Ok(())
+            },
+            Err(err) => {
+                // put it back where it was, nothing else can have taken the name this fast
+                self.with_entries(|entries| {
+                    entries.insert(old_name.to_string(), child);
+                    Ok(())
+                })?;
+                Err(err)
+            },
+        }
+    }
+
+    fn read_at(&self, offset: u64, buffer: &mut [u8]) -> Result<usize, VfsError> {
+        // copied out first, buffer may be user memory that faults on this very file
+        let data = self.with_state(|state| {
+            state.accessed = rtc::now();
+            match &state.contents {
+                Contents::File(data) => {
+                    let start = min(offset, data.len() as u64) as usize;
+                    let end = start + min(buffer.len(), data.len() - start);
+                    Ok(data[start..end].to_vec())
+                },
+                Contents::Directory(_) => Err(VfsError::IsADirectory),
+            }
+        })?;
+        buffer[..data.len()].copy_from_slice(&data);
+        Ok(data.len())
+    }
+
+    fn write_at(&self, offset: u64, data: &[u8]) -> Result<usize, VfsError> {
+        let data = data.to_vec();
+        let end = offset.checked_add(data.len() as u64).ok_or(VfsError::NoSpace)?;
+        self.with_data(end, |file| {
+            let (offset, end) = (offset as usize, end as usize);
+            if end > file.len() {
+                file.resize(end, 0);
+            }
+            file[offset..end].copy_from_slice(&data);
+            data.len()
+        })
+    }
+
+    fn truncate(&self, len: u64) -> Result<(), VfsError> {
+        self.with_data(len, |file| file.resize(len as usize, 0))
+    }
+}
+
+/// rejects names that cannot be a single path component
+fn check_name(name: &str) -> Result<(), VfsError> {
+    let is_valid = !name.is_empty()
+        && name.len() <= MAX_NAME_LEN
+        && name != "."
+        && name != ".."
+        && !name.contains(|c| c == '/' || c == '\0');
+    if is_valid { Ok(()) } else { Err(VfsError::InvalidName) }
+}
+
+#[test_case]
+fn test_check_name() {
+    assert!(check_name("notes.txt").is_ok());
+    assert!(check_name("..").is_err());
+    assert!(check_name("a/b").is_err());
+    assert!(check_name("").is_err());
+}
@@ -0,0 +1,216 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(lobster::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+extern crate alloc;
+
+use alloc::sync::Arc;
+use alloc::vec;
+use alloc::vec::Vec;
+use bootloader::{entry_point, BootInfo};
+use conquer_once::spin::OnceCell;
+use core::panic::PanicInfo;
+use lobster::{allocator, gdt, interrupts, rtc};
+use lobster::memory::{self, LinearFrameAllocator};
+use lobster::vfs::{self, FileType, VfsError};
+use lobster::vfs::tmpfs::Tmpfs;
+
+/// file data the tmpfs at /tmp holds
+const CAPACITY: u64 = 0x2000;
+
+static TMP: OnceCell<Arc<Tmpfs>> = OnceCell::uninit();
+
+entry_point!(main);
+
+fn main(boot_info: &'static BootInfo) -> ! {
+    lobster::BOOT_INFO.init_once(|| boot_info);
+    // timestamps come from the real time clock, which the timer then advances
+    gdt::init();
+    interrupts::init_idt();
+    unsafe { interrupts::PICS.lock().initialize() };
+    x86_64::instructions::interrupts::enable();
+    rtc::init();
+
+    let mut mapper = unsafe { memory::init() };
+    let mut frame_allocator = unsafe { LinearFrameAllocator::init(&boot_info.memory_map) };
+    allocator::init_heap(&mut mapper, &mut frame_allocator)
+        .expect("heap initialization failed");
+
+    // a second tmpfs stands in for the disk so paths can cross a mount point
+    vfs::mount("/", Arc::new(Tmpfs::new(CAPACITY)))
+        .expect("failed to mount /");
+    let tmp = TMP.get_or_init(|| Arc::new(Tmpfs::new(CAPACITY)));
+    vfs::mount("/tmp", tmp.clone())
+        .expect("failed to mount /tmp");
+
+    test_main();
+    loop {}
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    lobster::test_panic_handler(info)
+}
+
+fn read_all(path: &str) -> Vec<u8> {
+    let inode = vfs::lookup(path, "/").expect("file not found");
+    let mut data = vec![0; inode.metadata().unwrap().size as usize];
+    let read = inode.read_at(0, &mut data).unwrap();
+    assert_eq!(read, data.len());
+    data
+}
+
+fn names(path: &str) -> Vec<alloc::string::String> {
+    vfs::lookup(path, "/").unwrap()
+        .read_dir().unwrap()
+        .into_iter()
+        .map(|entry| entry.name)
+        .collect()
+}
+
+#[test_case]
+fn write_and_read_back() {
+    let file = vfs::create("/tmp/notes.txt", "/").unwrap();
+    let open = vfs::open(&file).unwrap();
+    assert_eq!(open.write(0, b"hello").unwrap(), 5);
+    assert_eq!(open.write(5, b", world").unwrap(), 7);
+    assert_eq!(read_all("/tmp/notes.txt"), b"hello, world");
+
+    // a gap reads as zeroes
+    file.write_at(14, b"!").unwrap();
+    assert_eq!(read_all("/tmp/notes.txt"), b"hello, world\0\0!");
+
+    let mut buffer = [0; 4];
+    assert_eq!(open.read(7, &mut buffer).unwrap(), 4);
+    assert_eq!(&buffer, b"worl");
+    assert_eq!(open.read(100, &mut buffer).unwrap(), 0);
+    vfs::remove("/tmp/notes.txt", "/").unwrap();
+}
+
+#[test_case]
+fn create_truncates() {
+    let file = vfs::create("/tmp/log", "/").unwrap();
+    file.write_at(0, b"old contents").unwrap();
+    vfs::create("tmp/log", "/").unwrap();
+    assert_eq!(file.metadata().unwrap().size, 0);
+
+    file.truncate(3).unwrap();
+    assert_eq!(read_all("/tmp/log"), b"\0\0\0");
+    vfs::remove("/tmp/log", "/").unwrap();
+}
+
+#[test_case]
+fn directories() {
+    vfs::create_directory("/tmp/a", "/").unwrap();
+    vfs::create_directory("/tmp/a/b", "/").unwrap();
+    vfs::create("/tmp/a/b/c", "/").unwrap();
+    vfs::create("/tmp/a/z", "/").unwrap();
+    assert_eq!(names("/tmp/a"), ["b", "z"]);
+    assert_eq!(
+        vfs::lookup("/tmp/a/b/c", "/").unwrap().metadata().unwrap().file_type,
+        FileType::Regular
+    );
+    assert!(matches!(vfs::lookup("/tmp/a/z/", "/"), Err(VfsError::NotADirectory)));
+    assert!(matches!(vfs::create_directory("/tmp/a/b", "/"), Err(VfsError::AlreadyExists)));
+    assert!(matches!(vfs::remove_directory("/tmp/a/b", "/"), Err(VfsError::NotEmpty)));
+    assert!(matches!(vfs::remove("/tmp/a/b", "/"), Err(VfsError::IsADirectory)));
+    assert!(matches!(vfs::remove_directory("/tmp/a/z", "/"), Err(VfsError::NotADirectory)));
+
+    vfs::remove("/tmp/a/b/c", "/").unwrap();
+    vfs::remove_directory("/tmp/a/b", "/").unwrap();
+    vfs::remove("/tmp/a/z", "/").unwrap();
+    vfs::remove_directory("/tmp/a", "/").unwrap();
+    assert!(names("/tmp").is_empty());
+}
+
+#[test_case]
+fn relative_paths() {
+    vfs::create_directory("/tmp/home", "/").unwrap();
+    vfs::create("/tmp/home/file", "/").unwrap();
+    assert!(vfs::lookup("file", "/tmp/home").is_ok());
+    assert!(vfs::lookup("../tmp/./home//file", "/tmp").is_ok());
+    // ".." of the root is the root
+    assert!(vfs::lookup("/../../tmp/home/file", "/").is_ok());
+    vfs::remove("/tmp/home/file", "/").unwrap();
+    vfs::remove_directory("/tmp/home", "/").unwrap();
+}
+
+#[test_case]
+fn rename() {
+    vfs::create_directory("/tmp/src", "/").unwrap();
+    vfs::create_directory("/tmp/dst", "/").unwrap();
+    let file = vfs::create("/tmp/src/file", "/").unwrap();
+    file.write_at(0, b"moved").unwrap();
+
+    vfs::rename("/tmp/src/file", "/tmp/dst/renamed", "/").unwrap();
+    assert!(matches!(vfs::lookup("/tmp/src/file", "/"), Err(VfsError::NotFound)));
+    assert_eq!(read_all("/tmp/dst/renamed"), b"moved");
+
+    // a file replaces a file
+    vfs::create("/tmp/dst/other", "/").unwrap();
+    vfs::rename("/tmp/dst/other", "/tmp/dst/renamed", "/").unwrap();
+    assert_eq!(read_all("/tmp/dst/renamed"), b"");
+    assert_eq!(names("/tmp/dst"), ["renamed"]);
+
+    // directories move with their contents but never below themselves
+    vfs::rename("/tmp/dst", "/tmp/src/dst", "/").unwrap();
+    assert!(vfs::lookup("/tmp/src/dst/renamed", "/").is_ok());
+    assert!(matches!(
+        vfs::rename("/tmp/src", "/tmp/src/dst/src", "/"),
+        Err(VfsError::InvalidArgument)
+    ));
+
+    vfs::remove("/tmp/src/dst/renamed", "/").unwrap();
+    vfs::remove_directory("/tmp/src/dst", "/").unwrap();
+    vfs::remove_directory("/tmp/src", "/").unwrap();
+}
+
+#[test_case]
+fn mount_points() {
+    vfs::create("/outside", "/").unwrap();
+    assert!(vfs::lookup("/tmp/../outside", "/").is_ok());
+    assert!(matches!(vfs::rename("/outside", "/tmp/inside", "/"), Err(VfsError::CrossDevice)));
+    assert!(matches!(vfs::remove_directory("/tmp", "/"), Err(VfsError::Busy)));
+    assert!(matches!(vfs::mount("/tmp", Arc::new(Tmpfs::new(0))), Err(VfsError::Busy)));
+    // the directory below the mount point does not need to exist
+    assert!(matches!(vfs::lookup("/tmp", "/").unwrap().read_dir(), Ok(entries) if entries.is_empty()));
+    assert!(vfs::mounts().iter().any(|(path, name)| path == "/tmp" && *name == "tmpfs"));
+    vfs::remove("/outside", "/").unwrap();
+}
+
+#[test_case]
+fn size_limit() {
+    let tmp = TMP.get().unwrap();
+    let file = vfs::create("/tmp/big", "/").unwrap();
+    file.write_at(0, &[1; CAPACITY as usize]).unwrap();
+    assert_eq!(tmp.used(), CAPACITY);
+    assert!(matches!(file.write_at(CAPACITY, b"x"), Err(VfsError::NoSpace)));
+    // a failed write leaves the file as it was
+    assert_eq!(file.metadata().unwrap().size, CAPACITY);
+
+    // the space of a removed file is freed once nothing refers to it
+    vfs::remove("/tmp/big", "/").unwrap();
+    assert_eq!(tmp.used(), CAPACITY);
+    drop(file);
+    assert_eq!(tmp.used(), 0);
+}
+
+#[test_case]
+fn timestamps() {
+    let file = vfs::create("/tmp/stamped", "/").unwrap();
+    let created = file.metadata().unwrap().created;
+    assert_ne!(created, 0);
+
+    // wait for the clock to move on so the write is stamped later than the creation
+    while rtc::now() == created {
+        x86_64::instructions::hlt();
+    }
+    file.write_at(0, b"data").unwrap();
+    let metadata = file.metadata().unwrap();
+    assert_eq!(metadata.created, created);
+    assert!(metadata.modified > created);
+    assert!(metadata.accessed >= created);
+    vfs::remove("/tmp/stamped", "/").unwrap();
+}